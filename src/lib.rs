@@ -6,82 +6,179 @@ pub mod tests;
 mod tests;
 
 pub(crate) mod macros;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod mock;
 pub mod nts;
 pub mod nts_ke;
+pub mod pcap;
+pub mod raw;
+pub mod report;
+pub mod socks;
+pub mod stress;
 pub mod udp;
 pub mod util;
 
-use crate::nts_ke::NtsKeConnection;
-use anyhow::anyhow;
-use ntp_proto::{NtsKeys, NtsRecord};
+use crate::nts_ke::{remaining, KeOptions, KeTimings, NtsKeConnection};
+use anyhow::{anyhow, Context};
+use ntp_proto::{AeadAlgorithm, NtsKeys, NtsRecord};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
 use rustls::RootCertStore;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
 use std::fmt::{Debug, Formatter};
 use std::fs::File;
 use std::io::BufReader;
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::panic::RefUnwindSafe;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use crate::nts::NtsCookie;
-pub use tests::all_tests;
+use crate::util::result::TestContext;
+pub use tests::basic::{DEFAULT_MAX_AMPLIFICATION_FACTOR, DEFAULT_MAX_ROOT_DISTANCE_SECONDS};
+pub use tests::fuzz::FuzzOptions;
+pub use tests::{all_tests, SuiteOptions};
 pub use util::result::{TestError, TestResult};
 
+/// The number of timeouts [`NtsServer::new`] may take in total, one for each of its stages
+pub const SETUP_DEADLINE_TIMEOUTS: u32 = 4;
+
+/// The default number of remaining cookies at which [`NtsServer::take_cookie`] starts a new key exchange
+pub const DEFAULT_COOKIE_LOW_WATER_MARK: usize = 2;
+
 #[derive(Debug)]
 pub struct NtsServer {
     host: String,
     port: u16,
-    root_cert_store: Arc<RootCertStore>,
+    ke_options: KeOptions,
     udp_host: SocketAddr,
-    nts: Mutex<(Vec<NtsCookie>, Arc<NtsKeys>)>,
-    timeout: Duration,
+    nts: Mutex<VecDeque<(NtsCookie, Arc<NtsKeys>)>>,
+    cookie_low_water_mark: usize,
+    cookie_count: Option<usize>,
+    setup_timings: KeTimings,
 }
 
 impl NtsServer {
-    pub fn new(
-        host: String,
-        port: u16,
-        ca_file: Option<PathBuf>,
-        timeout: Duration,
-    ) -> TestResult<Self> {
-        let root_cert_store = root_ca(ca_file)?;
-
-        let mut ke = NtsKeConnection::new(&host, port, &root_cert_store, timeout)?;
-        let (cookies, udp_host, keys) = ke.do_request()?;
+    /// Do an initial key exchange with the NTS-KE server at `host` and `port`
+    ///
+    /// Every stage of the setup is reported with its own error context, so a failure can be attributed to name
+    /// resolution, the TCP connection, the TLS handshake, or the key exchange itself. Together the stages have to
+    /// complete within [`SETUP_DEADLINE_TIMEOUTS`] times the timeout of `ke_options`.
+    pub fn new(host: String, port: u16, ke_options: KeOptions) -> TestResult<Self> {
+        let deadline = Instant::now() + ke_options.timeout * SETUP_DEADLINE_TIMEOUTS;
+
+        let mut ke = NtsKeConnection::new(&host, port, &ke_options)
+            .test_context(|| format!("While connecting to {host}:{port}"))?;
+        ke.handshake_before(deadline)
+            .test_context(|| format!("During the TLS handshake with {host}:{port}"))?;
+        tracing::debug!(host, port, "TLS handshake with NTS-KE server completed");
+
+        let Some(remaining) = remaining(deadline) else {
+            return Err(anyhow!(
+                "Setting up {host}:{port} exceeded the deadline before the key exchange"
+            )
+            .into());
+        };
+        ke.set_timeout(remaining.min(ke_options.timeout))?;
+        let (cookies, udp_host, keys) = ke
+            .do_request(AeadAlgorithm::AeadAesSivCmac256)
+            .test_context(|| format!("During the key exchange with {host}:{port}"))?;
+        let setup_timings = ke.timings();
+        let keys = Arc::new(keys);
 
         Ok(Self {
             host,
             port,
-            root_cert_store,
+            ke_options,
             udp_host,
-            nts: Mutex::new((cookies, Arc::new(keys))),
-            timeout,
+            nts: Mutex::new(
+                cookies
+                    .into_iter()
+                    .map(|cookie| (cookie, Arc::clone(&keys)))
+                    .collect(),
+            ),
+            cookie_low_water_mark: DEFAULT_COOKIE_LOW_WATER_MARK,
+            cookie_count: None,
+            setup_timings,
         })
     }
 
+    /// Refill the cookie jar as soon as it holds `mark` cookies or fewer, instead of waiting until it is empty
+    pub fn with_cookie_low_water_mark(mut self, mark: usize) -> Self {
+        self.cookie_low_water_mark = mark;
+        self
+    }
+
+    /// Keep only `count` of the cookies handed out by every key exchange, and discard the rest
+    ///
+    /// NTS-KE has no way to ask for a number of cookies, so this simulates a server that hands out few of them. With
+    /// a count at or below the low water mark every [`NtsServer::take_cookie`] does a new key exchange.
+    pub fn with_cookie_count(mut self, count: usize) -> Self {
+        self.nts
+            .get_mut()
+            .expect("No poisoned cookies")
+            .truncate(count);
+        self.cookie_count = Some(count);
+        self
+    }
+
     pub fn udp_host(&self) -> SocketAddr {
         self.udp_host
     }
 
-    pub fn take_cookie(&self) -> TestResult<(NtsCookie, Arc<NtsKeys>)> {
-        let mut guard = self.nts.lock().expect("No poisoned cookies");
+    /// Host name of the NTS-KE server
+    pub fn host(&self) -> &str {
+        &self.host
+    }
 
-        if guard.0.is_empty() {
-            self.refill(&mut guard)?;
-        }
+    /// Port of the NTS-KE server
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// How long the phases of the initial key exchange in [`NtsServer::new`] took
+    pub fn setup_timings(&self) -> KeTimings {
+        self.setup_timings
+    }
 
-        Ok((
-            guard.0.pop().expect("Just refilled the jar"),
-            Arc::clone(&guard.1),
-        ))
+    /// The settings used to connect to the NTS-KE server
+    pub fn ke_options(&self) -> &KeOptions {
+        &self.ke_options
     }
 
-    fn refill(&self, (cookies, keys): &mut (Vec<NtsCookie>, Arc<NtsKeys>)) -> TestResult {
-        assert!(cookies.is_empty());
+    /// The timeout to use for network operations
+    pub fn timeout(&self) -> Duration {
+        self.ke_options.timeout
+    }
+
+    /// The IP family to use when connecting to the server
+    pub fn address_family(&self) -> AddressFamily {
+        self.ke_options.address_family
+    }
+
+    /// Take a cookie together with the keys of the session it belongs to
+    ///
+    /// Once the jar runs low a new key exchange is started, so tests do not have to wait for one when it runs empty.
+    pub fn take_cookie(&self) -> TestResult<(NtsCookie, Arc<NtsKeys>)> {
+        let mut cookies = self.nts.lock().expect("No poisoned cookies");
+
+        if cookies.len() <= self.cookie_low_water_mark {
+            // Failing to top up is only a problem if there is nothing left to hand out
+            if let Err(e) = self.refill(&mut cookies) {
+                if cookies.is_empty() {
+                    return Err(e);
+                }
+            }
+        }
+
+        // Hand out the oldest cookies first, so none of them go stale
+        Ok(cookies.pop_front().expect("Just refilled the jar"))
+    }
 
-        let mut ke =
-            NtsKeConnection::new(&self.host, self.port, &self.root_cert_store, self.timeout)?;
-        let (new_cookies, udp_host, new_keys) = ke.do_request()?;
+    fn refill(&self, cookies: &mut VecDeque<(NtsCookie, Arc<NtsKeys>)>) -> TestResult {
+        let mut ke = NtsKeConnection::new(&self.host, self.port, &self.ke_options)?;
+        let (new_cookies, udp_host, new_keys) = ke.do_request(AeadAlgorithm::AeadAesSivCmac256)?;
 
         if udp_host != self.udp_host {
             return Err(TestError::Error(anyhow!(
@@ -89,8 +186,13 @@ impl NtsServer {
             )));
         }
 
-        cookies.extend(new_cookies);
-        let _old_keys = std::mem::replace(keys, Arc::new(new_keys));
+        let new_keys = Arc::new(new_keys);
+        cookies.extend(
+            new_cookies
+                .into_iter()
+                .take(self.cookie_count.unwrap_or(usize::MAX))
+                .map(|cookie| (cookie, Arc::clone(&new_keys))),
+        );
 
         Ok(())
     }
@@ -99,13 +201,52 @@ impl NtsServer {
 #[derive(Debug)]
 pub enum Server {
     Ntp(SocketAddr),
-    Nts(NtsServer),
+    Nts(Box<NtsServer>),
+}
+
+/// Which IP family to connect over when a host resolves to multiple addresses
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub enum AddressFamily {
+    #[default]
+    Any,
+    V4,
+    V6,
+}
+
+impl AddressFamily {
+    pub fn matches(self, addr: &SocketAddr) -> bool {
+        match self {
+            AddressFamily::Any => true,
+            AddressFamily::V4 => addr.is_ipv4(),
+            AddressFamily::V6 => addr.is_ipv6(),
+        }
+    }
+
+    /// Pick the first of the resolved addresses that belongs to this family
+    pub fn select(self, addrs: impl IntoIterator<Item = SocketAddr>) -> Option<SocketAddr> {
+        addrs.into_iter().find(|addr| self.matches(addr))
+    }
+}
+
+impl std::fmt::Display for AddressFamily {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AddressFamily::Any => f.write_str("IP"),
+            AddressFamily::V4 => f.write_str("IPv4"),
+            AddressFamily::V6 => f.write_str("IPv6"),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct TestConfig {
     pub server: Server,
     pub timeout: Duration,
+    pub address_family: AddressFamily,
+    /// Called for every packet sent or received by the connections handed out to tests
+    pub packet_trace: Option<PacketTrace>,
+    /// Records the UDP packets of the connections handed out to tests
+    pub pcap: Option<Arc<pcap::PcapWriter>>,
 }
 
 impl TestConfig {
@@ -115,18 +256,31 @@ impl TestConfig {
             Server::Nts(server) => server.udp_host(),
         };
 
-        udp::UdpConnection::new(addr, self.timeout)
+        self.udp_to(addr)
+    }
+
+    /// A UDP connection to `addr` instead of the tested server, e.g. to the server negotiated in a key exchange
+    pub fn udp_to(&self, addr: SocketAddr) -> TestResult<udp::UdpConnection> {
+        let conn = udp::UdpConnection::new(addr, self.timeout, self.address_family)?;
+        Ok(conn
+            .with_trace(self.packet_trace.clone())
+            .with_pcap(self.pcap.clone()))
     }
 
     pub fn ke(&self) -> TestResult<NtsKeConnection> {
         match &self.server {
             Server::Ntp(_) => Err(TestError::Skipped),
-            Server::Nts(server) => NtsKeConnection::new(
-                &server.host,
-                server.port,
-                &server.root_cert_store,
-                server.timeout,
-            ),
+            Server::Nts(server) => {
+                let ke = NtsKeConnection::new(&server.host, server.port, &server.ke_options)?;
+                Ok(ke.with_trace(self.packet_trace.clone()))
+            }
+        }
+    }
+
+    pub fn nts_server(&self) -> TestResult<&NtsServer> {
+        match &self.server {
+            Server::Ntp(_) => Err(TestError::Skipped),
+            Server::Nts(server) => Ok(server),
         }
     }
 
@@ -153,7 +307,46 @@ pub fn root_ca(cafile: Option<PathBuf>) -> anyhow::Result<Arc<RootCertStore>> {
     Ok(Arc::new(root_cert_store))
 }
 
-#[derive(Clone, Eq, PartialEq)]
+/// A client certificate chain and its private key, for NTS-KE servers that require mutual TLS
+pub struct ClientCert {
+    chain: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+}
+
+impl ClientCert {
+    /// Load the PEM encoded certificate chain in `cert_file` and the private key in `key_file`
+    pub fn load(cert_file: &Path, key_file: &Path) -> anyhow::Result<Self> {
+        let mut pem = BufReader::new(File::open(cert_file).with_context(|| {
+            format!("Could not open client certificate {}", cert_file.display())
+        })?);
+        let chain = rustls_pemfile::certs(&mut pem)
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("Invalid client certificate {}", cert_file.display()))?;
+        if chain.is_empty() {
+            anyhow::bail!("No certificates found in {}", cert_file.display());
+        }
+
+        let mut pem = BufReader::new(
+            File::open(key_file)
+                .with_context(|| format!("Could not open client key {}", key_file.display()))?,
+        );
+        let key = rustls_pemfile::private_key(&mut pem)
+            .with_context(|| format!("Invalid client key {}", key_file.display()))?
+            .with_context(|| format!("No private key found in {}", key_file.display()))?;
+
+        Ok(Self { chain, key })
+    }
+}
+
+impl Debug for ClientCert {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClientCert")
+            .field("chain", &self.chain.len())
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Clone, Eq, PartialEq, Hash)]
 pub struct RawBytes(pub Box<[u8]>);
 
 impl Debug for RawBytes {
@@ -168,12 +361,59 @@ impl From<Vec<u8>> for RawBytes {
     }
 }
 
+impl From<&[u8]> for RawBytes {
+    fn from(value: &[u8]) -> Self {
+        Self(value.into())
+    }
+}
+
+/// Whether a traced packet was sent to or received from the server
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// A packet as seen by a [`PacketTrace`]
+#[derive(Debug)]
+pub struct TracedPacket<'a> {
+    /// `"NTP"` for UDP packets, `"NTS-KE"` for data on the key exchange connection
+    pub protocol: &'static str,
+    pub direction: Direction,
+    pub bytes: &'a [u8],
+}
+
+/// A callback that gets to see the raw bytes of everything sent or received by the connections of a test
+#[derive(Clone)]
+pub struct PacketTrace(Arc<dyn Fn(TracedPacket) + Send + Sync + RefUnwindSafe>);
+
+impl PacketTrace {
+    pub fn new(f: impl Fn(TracedPacket) + Send + Sync + RefUnwindSafe + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+
+    pub fn trace(&self, protocol: &'static str, direction: Direction, bytes: &[u8]) {
+        (self.0)(TracedPacket {
+            protocol,
+            direction,
+            bytes,
+        })
+    }
+}
+
+impl Debug for PacketTrace {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("PacketTrace")
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum Response {
     UdpUnparsable(RawBytes),
     UdpResponse(ntp_proto::NtpPacket<'static>),
     KeResponse(nts_ke::Response),
     KeInvalid(Vec<NtsRecord>),
+    KeRaw(Vec<nts_ke::RawRecord>),
 }
 
 impl From<udp::UdpResponse> for Response {
@@ -200,7 +440,171 @@ impl From<Vec<NtsRecord>> for Response {
     }
 }
 
+impl From<Vec<nts_ke::RawRecord>> for Response {
+    fn from(value: Vec<nts_ke::RawRecord>) -> Self {
+        Self::KeRaw(value)
+    }
+}
+
+/// The protocol a test exercises, used to group tests in reports
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Category {
+    Ntp,
+    Nts,
+    NtsKe,
+}
+
+impl std::fmt::Display for Category {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Category::Ntp => f.write_str("NTP"),
+            Category::Nts => f.write_str("NTS"),
+            Category::NtsKe => f.write_str("NTS-KE"),
+        }
+    }
+}
+
 pub trait TestCase {
     fn name(&self) -> &'static str;
     fn run(&self, conn: &TestConfig) -> TestResult;
+
+    /// The protocol this test exercises
+    fn category(&self) -> Category {
+        Category::Ntp
+    }
+
+    /// A one line summary of what this test checks, if any
+    fn description(&self) -> Option<&'static str> {
+        None
+    }
+
+    /// Link to the section of the specification this test checks, if any
+    fn rfc(&self) -> Option<&'static str> {
+        None
+    }
+
+    /// Free form labels to select groups of tests by, e.g. `security`
+    fn tags(&self) -> &'static [&'static str] {
+        &[]
+    }
+}
+
+// Named apart from the `tests` module holding the test cases for servers
+#[cfg(test)]
+mod unit_tests {
+    use super::*;
+    use crate::mock::MockServer;
+
+    fn nts_server(mock: &MockServer) -> NtsServer {
+        let ke_options = mock.ke_options(Duration::from_secs(1));
+        NtsServer::new("localhost".to_string(), mock.ke_port(), ke_options).unwrap()
+    }
+
+    /// The number of key exchanges done after the initial one, while taking `takes` cookies
+    fn refills(mock: &MockServer, server: &NtsServer, takes: usize) -> usize {
+        let before = mock.key_exchanges();
+        for _ in 0..takes {
+            server.take_cookie().unwrap();
+        }
+        mock.key_exchanges() - before
+    }
+
+    #[test]
+    fn refills_at_low_water_mark() {
+        // The mock hands out 8 cookies, the jar is at the mark of 2 after taking 6 of them and holds 9 after the refill
+        let mock = MockServer::start().unwrap();
+        let server = nts_server(&mock);
+        assert_eq!(refills(&mock, &server, 6), 0);
+        assert_eq!(refills(&mock, &server, 1), 1);
+        assert_eq!(refills(&mock, &server, 8), 1);
+    }
+
+    #[test]
+    fn refills_only_when_empty_without_mark() {
+        let mock = MockServer::start().unwrap();
+        let server = nts_server(&mock).with_cookie_low_water_mark(0);
+        assert_eq!(refills(&mock, &server, 8), 0);
+        assert_eq!(refills(&mock, &server, 1), 1);
+    }
+
+    #[test]
+    fn single_cookie_refills_on_every_take() {
+        let mock = MockServer::start().unwrap();
+        let server = nts_server(&mock).with_cookie_count(1);
+        assert_eq!(refills(&mock, &server, 5), 5);
+        // Only one cookie of every key exchange is kept
+        assert_eq!(server.nts.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn setup_timings_cover_every_phase() {
+        let mock = MockServer::start().unwrap();
+        let KeTimings {
+            dns,
+            tcp,
+            tls,
+            exchange,
+        } = nts_server(&mock).setup_timings();
+
+        for (phase, duration) in [
+            ("DNS", dns),
+            ("TCP", tcp),
+            ("TLS", tls),
+            ("exchange", exchange),
+        ] {
+            assert!(
+                duration.is_some_and(|d| !d.is_zero()),
+                "{phase}: {duration:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn stalled_tls_handshake_is_reported() {
+        // Accepts the connection, but never answers the ClientHello
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            let _stream = listener.accept().unwrap();
+            std::thread::sleep(Duration::from_secs(2));
+        });
+
+        let ke_options = KeOptions {
+            root_cert_store: Arc::new(RootCertStore::empty()),
+            timeout: Duration::from_millis(100),
+            address_family: AddressFamily::V4,
+            client_cert: None,
+            proxy: None,
+            sni: None,
+            insecure_skip_verify: false,
+        };
+        match NtsServer::new("localhost".to_string(), port, ke_options) {
+            Err(TestError::Error(e)) => assert!(
+                format!("{e:#}")
+                    .starts_with(&format!("During the TLS handshake with localhost:{port}")),
+                "{e:#}"
+            ),
+            other => panic!("Expected the TLS stage to fail, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn address_family_selects_first_matching_address() {
+        let v4: SocketAddr = "192.0.2.1:123".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:123".parse().unwrap();
+        let other_v4: SocketAddr = "192.0.2.2:123".parse().unwrap();
+
+        assert_eq!(AddressFamily::Any.select([v6, v4]), Some(v6));
+        assert_eq!(AddressFamily::V4.select([v6, v4, other_v4]), Some(v4));
+        assert_eq!(AddressFamily::V6.select([v4, v6]), Some(v6));
+        assert_eq!(AddressFamily::V6.select([v4, other_v4]), None);
+    }
+
+    #[test]
+    fn missing_address_family_is_an_error() {
+        let result =
+            udp::UdpConnection::new("127.0.0.1:123", Duration::from_secs(1), AddressFamily::V6);
+        assert!(matches!(result, Err(TestError::Error(_))));
+    }
 }
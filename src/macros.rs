@@ -38,14 +38,14 @@ macro_rules! pester_assert_ne {
         let a = $expected;
         let b = $actual;
         if a == b {
-            return crate::fail(format!("Assertion {} not equal to {} failed, value {:?}", stringify!($actual), stringify!($expected), a), $response);
+            return crate::util::result::fail(format!("Assertion {} not equal to {} failed, value {:?}", stringify!($actual), stringify!($expected), a), $response);
         }
     };
     ($response:expr, $actual:expr, $expected:expr, $($arg:tt)+) => {
         let a = $expected;
         let b = $actual;
         if a == b {
-            return crate::fail(format!("Assertion {} not equal to {} failed, value {:?}: {}", stringify!($actual), stringify!($expected), a, format!($($arg)+)), $response);
+            return crate::util::result::fail(format!("Assertion {} not equal to {} failed, value {:?}: {}", stringify!($actual), stringify!($expected), a, format!($($arg)+)), $response);
         }
     };
 }
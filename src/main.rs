@@ -1,16 +1,41 @@
 use anyhow::Context;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
 use std::net::ToSocketAddrs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use network_time_pester as pest;
-use network_time_pester::{NtsServer, Server};
-use pest::{TestConfig, TestError};
+use network_time_pester::{
+    AddressFamily, Direction, NtsServer, PacketTrace, RawBytes, Server, TracedPacket,
+};
+use pest::nts_ke::KeOptions;
+use pest::pcap::PcapWriter;
+use pest::report::{
+    compare_baseline, BaselineRecorder, ChangeReport, ConsoleReporter, JUnitReporter,
+    JsonLinesReporter, Latency, Reporter, Summary, TapReporter, TestInfo, TestReport,
+};
+use pest::socks::Socks5Proxy;
+use pest::stress::stress_ke;
+use pest::udp::take_round_trip_times;
+use pest::{
+    root_ca, ClientCert, FuzzOptions, SuiteOptions, TestCase, TestConfig, TestError, TestResult,
+    DEFAULT_MAX_AMPLIFICATION_FACTOR, DEFAULT_MAX_ROOT_DISTANCE_SECONDS,
+};
+use tracing::level_filters::LevelFilter;
 
 #[derive(Parser, Debug)]
 struct Cli {
-    #[arg(default_value = "localhost")]
-    host: String,
+    /// Servers to test, defaults to `localhost` if neither hosts nor `--hosts-file` are given
+    hosts: Vec<String>,
+
+    #[arg(long)]
+    hosts_file: Option<PathBuf>,
 
     #[arg(long, short, default_value_t = 123)]
     port: u16,
@@ -24,67 +49,845 @@ struct Cli {
     #[arg(long, short, requires = "nts")]
     ca_file: Option<PathBuf>,
 
+    #[arg(long, requires_all = ["nts", "client_key"])]
+    client_cert: Option<PathBuf>,
+
+    #[arg(long, requires = "client_cert")]
+    client_key: Option<PathBuf>,
+
+    #[arg(long, requires = "nts")]
+    proxy: Option<Socks5Proxy>,
+
+    #[arg(long, requires = "nts")]
+    sni: Option<String>,
+
+    #[arg(long, requires = "nts", conflicts_with = "ca_file")]
+    insecure_skip_verify: bool,
+
+    #[arg(long, requires = "nts")]
+    ke_timings: bool,
+
+    #[arg(long, requires = "nts", value_parser = clap::value_parser!(u16).range(1..))]
+    count_cookies: Option<u16>,
+
     #[arg(long, short, default_value = "100ms")]
     timeout: humantime::Duration,
+
+    #[arg(long, conflicts_with = "ip6")]
+    ip4: bool,
+
+    #[arg(long)]
+    ip6: bool,
+
+    #[arg(long)]
+    test_timeout: Option<humantime::Duration>,
+
+    #[arg(long, short, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+    jobs: u16,
+
+    #[arg(long)]
+    filter: Option<String>,
+
+    #[arg(long)]
+    exclude: Option<String>,
+
+    #[arg(long = "tag")]
+    tags: Vec<String>,
+
+    #[arg(long, requires = "tags")]
+    all_tags: bool,
+
+    #[arg(long, visible_alias = "format", value_enum, default_value_t = OutputFormat::Console)]
+    output: OutputFormat,
+
+    #[arg(long)]
+    output_file: Option<PathBuf>,
+
+    #[arg(long)]
+    ntpv5: bool,
+
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+    repeat: u16,
+
+    #[arg(long)]
+    fail_fast: bool,
+
+    #[arg(long)]
+    dump_packets: bool,
+
+    #[arg(long, conflicts_with = "dump_packets")]
+    only_failures: bool,
+
+    #[arg(long)]
+    pcap: Option<PathBuf>,
+
+    #[arg(long)]
+    fuzz: Option<u32>,
+
+    #[arg(long, requires = "fuzz")]
+    seed: Option<u64>,
+
+    #[arg(long)]
+    multicast: bool,
+
+    #[arg(long)]
+    baseline: Option<PathBuf>,
+
+    #[arg(long)]
+    compare: Option<PathBuf>,
+
+    #[arg(long = "source-port-123")]
+    source_port_123: bool,
+
+    #[arg(long)]
+    trickle: bool,
+
+    #[arg(long)]
+    expected_cookies: Option<usize>,
+
+    #[arg(long)]
+    expect_leap: Option<humantime::Timestamp>,
+
+    #[arg(long)]
+    check_source: bool,
+
+    #[arg(long)]
+    allow_raw: bool,
+
+    #[arg(long)]
+    offset_jitter: bool,
+
+    #[arg(long, default_value_t = DEFAULT_MAX_AMPLIFICATION_FACTOR)]
+    max_amplification: f64,
+
+    #[arg(long, default_value_t = DEFAULT_MAX_ROOT_DISTANCE_SECONDS)]
+    max_root_distance: f64,
+
+    #[cfg(feature = "test-utils")]
+    #[arg(long)]
+    self_test: bool,
+
+    #[arg(long, default_value_t = LevelFilter::WARN)]
+    log_level: LevelFilter,
+
+    #[arg(long)]
+    ignore_errors: bool,
+
+    #[arg(long)]
+    list: bool,
+
+    #[arg(long, requires = "nts", value_parser = clap::value_parser!(u16).range(1..))]
+    stress_ke: Option<u16>,
+    #[arg(long)]
+    interval: Option<humantime::Duration>,
+
+    #[arg(long, requires = "interval", value_parser = clap::value_parser!(u32).range(1..))]
+    count: Option<u32>,
+}
+
+impl Cli {
+    /// Whether `test` is selected by `--filter`, `--exclude` and `--tag`
+    ///
+    /// A test needs one of the given tags, or all of them with `--all-tags`.
+    fn selects(&self, test: &dyn TestCase) -> bool {
+        let name = test_name(test);
+        let included = match &self.filter {
+            Some(filter) => name.contains(filter.as_str()),
+            None => true,
+        };
+        let excluded = match &self.exclude {
+            Some(exclude) => name.contains(exclude.as_str()),
+            None => false,
+        };
+        let has_tag = |tag: &String| test.tags().contains(&tag.as_str());
+        let tagged = if self.tags.is_empty() {
+            true
+        } else if self.all_tags {
+            self.tags.iter().all(has_tag)
+        } else {
+            self.tags.iter().any(has_tag)
+        };
+
+        included && !excluded && tagged
+    }
+
+    /// Whether `result` should make the run fail, skipped tests never do
+    fn is_failure(&self, result: &TestResult) -> bool {
+        match result {
+            Ok(()) | Err(TestError::Skipped) => false,
+            Err(TestError::Fail(..)) => true,
+            Err(TestError::Error(_)) => !self.ignore_errors,
+        }
+    }
+
+    /// Number of results in `summary` that make the run fail
+    fn failures(&self, summary: &Summary) -> usize {
+        if self.ignore_errors {
+            summary.failed
+        } else {
+            summary.failed + summary.errored
+        }
+    }
+
+    /// All hosts given on the command line or in the hosts file
+    fn hosts(&self) -> anyhow::Result<Vec<String>> {
+        let mut hosts = self.hosts.clone();
+
+        if let Some(path) = &self.hosts_file {
+            let contents = std::fs::read_to_string(path)
+                .with_context(|| format!("Could not read hosts file {}", path.display()))?;
+            hosts.extend(
+                contents
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(String::from),
+            );
+        }
+
+        if hosts.is_empty() {
+            hosts.push("localhost".to_string());
+        }
+
+        Ok(hosts)
+    }
+
+    /// The optional tests enabled on the command line
+    ///
+    /// Without `--seed` fuzzing uses a random seed, which is then fixed for the whole run.
+    fn suite_options(&self) -> SuiteOptions {
+        SuiteOptions {
+            ntpv5: self.ntpv5,
+            fuzz: self.fuzz.map(|count| FuzzOptions {
+                count,
+                seed: self.seed.unwrap_or_else(rand::random),
+            }),
+            multicast: self.multicast,
+            source_port_123: self.source_port_123,
+            trickle: self.trickle,
+            expected_cookies: self.expected_cookies,
+            expected_leap: self.expect_leap.as_deref().copied(),
+            response_source: self.check_source,
+            allow_raw: self.allow_raw,
+            offset_jitter: self.offset_jitter,
+            max_amplification_factor: self.max_amplification,
+            max_root_distance: self.max_root_distance,
+        }
+    }
+
+    /// The IP family selected by `--ip4` or `--ip6`
+    fn address_family(&self) -> AddressFamily {
+        if self.ip4 {
+            AddressFamily::V4
+        } else if self.ip6 {
+            AddressFamily::V6
+        } else {
+            AddressFamily::Any
+        }
+    }
+
+    /// Set up the configuration to test `host`, for NTS this already performs a key exchange
+    ///
+    /// All packets are captured into `pcap`, if given.
+    fn config(
+        &self,
+        host: &str,
+        ke_options: &KeOptions,
+        pcap: Option<Arc<PcapWriter>>,
+    ) -> anyhow::Result<TestConfig> {
+        let address_family = self.address_family();
+        let server = if self.nts {
+            let mut server = NtsServer::new(host.to_string(), self.ke_port, ke_options.clone())
+                .context("Could not connect to NTS server to gather cookies and information")?;
+            if let Some(count) = self.count_cookies {
+                server = server.with_cookie_count(count.into());
+            }
+            Server::Nts(Box::new(server))
+        } else {
+            let addrs = format!("{}:{}", host, self.port)
+                .to_socket_addrs()
+                .with_context(|| format!("Failed to lookup host: {host:?}"))?;
+            let server = address_family.select(addrs).with_context(|| {
+                format!("Host {host:?} did not resolve into any {address_family} addresses")
+            })?;
+            Server::Ntp(server)
+        };
+
+        Ok(TestConfig {
+            server,
+            timeout: self.timeout.into(),
+            address_family,
+            packet_trace: self.dump_packets.then(|| PacketTrace::new(dump_packet)),
+            pcap,
+        })
+    }
+
+    /// Run all selected tests against the server in `config`, passing the results to `reporter`
+    ///
+    /// The outcome of every test is also counted in `outcomes`, to compare them across repeated runs.
+    fn run_suite(
+        &self,
+        server: &str,
+        options: &SuiteOptions,
+        config: &Arc<TestConfig>,
+        reporter: &mut dyn Reporter,
+        outcomes: &mut Outcomes,
+    ) -> anyhow::Result<Summary> {
+        let mut summary = Summary::default();
+        let tests = Mutex::new(pest::all_tests(options).filter(|test| self.selects(test.as_ref())));
+        let stop = AtomicBool::new(false);
+        let run_next = || {
+            if stop.load(Ordering::Relaxed) {
+                return None;
+            }
+
+            let test = tests.lock().expect("No poisoned tests").next()?;
+            let info = TestInfo {
+                name: test_name(test.as_ref()),
+                category: test.category(),
+                description: test.description(),
+                rfc: test.rfc(),
+            };
+
+            let span = tracing::info_span!("test", name = info.name, server);
+            let (result, latency) = span.in_scope(|| match self.test_timeout {
+                Some(deadline) => run_with_deadline(test, Arc::clone(config), deadline.into()),
+                None => run_test(test, config),
+            });
+
+            if self.fail_fast && self.is_failure(&result) {
+                stop.store(true, Ordering::Relaxed);
+            }
+
+            Some((info, result, latency))
+        };
+        let mut record = |info: &TestInfo, result: &TestResult, latency| {
+            summary.add(result);
+            outcomes.entry(info.name).or_default()[outcome_index(result)] += 1;
+            reporter.report(info, result, latency)
+        };
+
+        if self.jobs == 1 {
+            while let Some((info, result, latency)) = run_next() {
+                record(&info, &result, latency)?;
+            }
+        } else {
+            let results = Mutex::new(vec![]);
+            std::thread::scope(|s| {
+                for _ in 0..self.jobs {
+                    s.spawn(|| {
+                        while let Some(result) = run_next() {
+                            results.lock().expect("No poisoned results").push(result);
+                        }
+                    });
+                }
+            });
+
+            // Parallel runs finish in arbitrary order, sort them to keep the output stable
+            let mut results = results.into_inner().expect("No poisoned results");
+            results.sort_by_key(|(info, _, _)| info.name);
+            for (info, result, latency) in &results {
+                record(info, result, *latency)?;
+            }
+        }
+
+        Ok(summary)
+    }
+
+    /// Run the tests named in `failed` again against the server in `config`, dumping their packets to stderr
+    ///
+    /// Used by `--only-failures`. The results of these runs are printed but not reported, so they do not change the
+    /// outcome of the run.
+    fn rerun_with_dumps(&self, options: &SuiteOptions, config: TestConfig, failed: &[&str]) {
+        let config = Arc::new(TestConfig {
+            packet_trace: Some(PacketTrace::new(dump_packet)),
+            ..config
+        });
+
+        let tests = pest::all_tests(options).filter(|test| {
+            self.selects(test.as_ref()) && failed.contains(&test_name(test.as_ref()))
+        });
+        for test in tests {
+            let name = test_name(test.as_ref());
+            eprintln!("\nRe-running {name} with packet dumps:");
+            let (result, _latency) = match self.test_timeout {
+                Some(deadline) => run_with_deadline(test, Arc::clone(&config), deadline.into()),
+                None => run_test(test, &config),
+            };
+            match result {
+                Ok(()) => eprintln!("✅ {name} passed when re-run"),
+                Err(TestError::Skipped) => eprintln!("⏩ {name} was skipped when re-run"),
+                Err(TestError::Fail(msg, _)) => eprintln!("❌ {name} failed again: {msg}"),
+                Err(TestError::Error(e)) => eprintln!("❓ {name} errored again: {e:#}"),
+            }
+        }
+    }
 }
 
-fn main() -> anyhow::Result<()> {
+/// Number of passed, failed, errored, and skipped runs per test
+type Outcomes = HashMap<&'static str, [usize; 4]>;
+
+fn outcome_index(result: &TestResult) -> usize {
+    match result {
+        Ok(()) => 0,
+        Err(TestError::Fail(..)) => 1,
+        Err(TestError::Error(_)) => 2,
+        Err(TestError::Skipped) => 3,
+    }
+}
+
+/// Print how consistent the outcome of every test was over `runs` repeated runs, highlighting the flaky ones
+fn print_flakiness(host: &str, runs: u16, outcomes: &Outcomes) {
+    let mut outcomes: Vec<_> = outcomes.iter().collect();
+    outcomes.sort();
+
+    eprintln!("\nFlakiness over {runs} runs against {host}:");
+    for (name, [passed, failed, errored, skipped]) in outcomes {
+        let consistent = [passed, failed, errored, skipped]
+            .into_iter()
+            .filter(|&&count| count > 0)
+            .count()
+            == 1;
+        let marker = if consistent { "  " } else { "⚠️" };
+        eprintln!(
+            "{marker} {name}: {passed} passed, {failed} failed, {errored} errored, {skipped} skipped"
+        );
+    }
+}
+
+type BoxedTest = Box<dyn TestCase + std::panic::UnwindSafe + Send>;
+
+/// Run a single test, measuring the latency of its requests
+fn run_test(test: BoxedTest, config: &TestConfig) -> (TestResult, Option<Latency>) {
+    // Discard measurements a previous test on this thread left behind
+    take_round_trip_times();
+    tracing::debug!("starting test");
+    let result = pest::util::catch_unwind(move || test.run(config));
+    let latency = Latency::from_samples(&take_round_trip_times());
+    tracing::debug!(?result, "finished test");
+
+    (result, latency)
+}
+
+/// Run a single test on a separate thread, giving up on it once `deadline` passed
+///
+/// A test that exceeds the deadline keeps running in the background until its sockets time out, but its result is
+/// ignored.
+fn run_with_deadline(
+    test: BoxedTest,
+    config: Arc<TestConfig>,
+    deadline: Duration,
+) -> (TestResult, Option<Latency>) {
+    let (tx, rx) = mpsc::channel();
+    let span = tracing::Span::current();
+    std::thread::spawn(move || {
+        // The receiver is gone if the test exceeded the deadline
+        let _ = tx.send(span.in_scope(|| run_test(test, &config)));
+    });
+
+    match rx.recv_timeout(deadline) {
+        Ok(outcome) => outcome,
+        Err(_) => {
+            tracing::warn!("test exceeded deadline, abandoning it");
+            (
+                Err(TestError::Error(anyhow::anyhow!(
+                    "test exceeded deadline of {}",
+                    humantime::format_duration(deadline)
+                ))),
+                None,
+            )
+        }
+    }
+}
+
+/// Print a packet to stderr for `--dump-packets`
+fn dump_packet(packet: TracedPacket) {
+    let arrow = match packet.direction {
+        Direction::Sent => "→",
+        Direction::Received => "←",
+    };
+    eprintln!(
+        "{arrow} {} {:?}",
+        packet.protocol,
+        RawBytes::from(packet.bytes)
+    );
+}
+
+fn test_name(test: &dyn TestCase) -> &'static str {
+    test.name().trim_start_matches("network_time_pester::")
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum OutputFormat {
+    /// Human-readable output
+    Console,
+    /// JUnit XML
+    Junit,
+    /// One JSON object per test
+    Jsonl,
+    /// Test Anything Protocol version 13
+    Tap,
+    /// A CBOR array of all test results
+    #[cfg(feature = "cbor")]
+    Cbor,
+}
+
+/// Exits with a non-zero code if any test failed, or errored without `--ignore-errors`
+fn main() -> anyhow::Result<ExitCode> {
     let cli = Cli::parse();
+    tracing_subscriber::fmt()
+        .with_max_level(cli.log_level)
+        .with_writer(io::stderr)
+        .init();
+    let options = cli.suite_options();
+    if !pest::all_tests(&options).any(|test| cli.selects(test.as_ref())) {
+        anyhow::bail!("No tests match the given --filter, --exclude and --tag options");
+    }
+
+    if cli.list {
+        for test in pest::all_tests(&options) {
+            if cli.selects(test.as_ref()) {
+                println!("{} [{}]", test_name(test.as_ref()), test.category());
+                if let Some(description) = test.description() {
+                    println!("    {description}");
+                }
+                if !test.tags().is_empty() {
+                    println!("    Tags: {}", test.tags().join(", "));
+                }
+            }
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    #[cfg(feature = "test-utils")]
+    if cli.self_test {
+        let mut reporter = ConsoleReporter::new(io::stdout());
+        let summary = pest::mock::self_test(&mut reporter, cli.timeout.into())?;
+        if cli.failures(&summary) > 0 {
+            return Ok(ExitCode::FAILURE);
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    let hosts = cli.hosts()?;
+    let baseline = match &cli.compare {
+        Some(path) => Some(load_baseline(path)?),
+        None => None,
+    };
 
-    let config = if cli.nts {
-        let server = NtsServer::new(cli.host, cli.ke_port, cli.ca_file, cli.timeout.into())
-            .context("Could not connect to NTS server to gather cookies and information")?;
-        TestConfig {
-            server: Server::Nts(server),
-            timeout: cli.timeout.into(),
-        }
-    } else {
-        let server = format!("{}:{}", cli.host, cli.port)
-            .to_socket_addrs()
-            .with_context(|| format!("Failed to lookup host: {:?}", cli.host))?
-            .next()
-            .with_context(|| format!("Host {:?} did not resolve into an IPs", cli.host))?;
-        TestConfig {
-            server: Server::Ntp(server),
-            timeout: cli.timeout.into(),
+    let out: Box<dyn Write> = match &cli.output_file {
+        Some(path) => {
+            Box::new(BufWriter::new(File::create(path).with_context(|| {
+                format!("Could not create output file {}", path.display())
+            })?))
         }
+        None => Box::new(io::stdout()),
+    };
+    let reporter: Box<dyn Reporter> = match cli.output {
+        OutputFormat::Console => Box::new(ConsoleReporter::new(out)),
+        OutputFormat::Junit => Box::new(JUnitReporter::new(out)),
+        OutputFormat::Jsonl => Box::new(JsonLinesReporter::new(out)),
+        OutputFormat::Tap => Box::new(TapReporter::new(out)),
+        #[cfg(feature = "cbor")]
+        OutputFormat::Cbor => Box::new(pest::report::CborReporter::new(out)),
     };
+    let mut reporter = BaselineRecorder::new(reporter);
 
-    let mut passed = 0;
-    let mut failed = 0;
-    let mut errored = 0;
-    let mut skipped = 0;
-    for test in pest::all_tests() {
-        let name = test.name().trim_start_matches("network_time_pester::");
-        let config_ref = &config;
-
-        match pest::util::catch_unwind(move || test.run(config_ref)) {
-            Ok(()) => {
-                passed += 1;
-                println!("✅ {name}");
+    let pcap = match &cli.pcap {
+        Some(path) => Some(Arc::new(PcapWriter::create(path).with_context(|| {
+            format!("Could not create pcap file {}", path.display())
+        })?)),
+        None => None,
+    };
+
+    let client_cert = match (&cli.client_cert, &cli.client_key) {
+        (Some(cert), Some(key)) => Some(Arc::new(ClientCert::load(cert, key)?)),
+        _ => None,
+    };
+    if cli.insecure_skip_verify {
+        eprintln!(
+            "⚠️ WARNING: --insecure-skip-verify accepts any NTS-KE server certificate, the server is NOT authenticated"
+        );
+    }
+    let ke_options = KeOptions {
+        root_cert_store: root_ca(cli.ca_file.clone())?,
+        timeout: cli.timeout.into(),
+        address_family: cli.address_family(),
+        client_cert,
+        proxy: cli.proxy.clone(),
+        sni: cli.sni.clone(),
+        insecure_skip_verify: cli.insecure_skip_verify,
+    };
+
+    let soak: Option<Duration> = cli.interval.map(Into::into);
+    let cycles = match (soak, cli.count) {
+        (Some(_), Some(count)) => count.into(),
+        (Some(_), None) => u64::MAX,
+        (None, _) => 1,
+    };
+    let interrupted = match soak {
+        Some(_) => Some(interrupt_channel()?),
+        None => None,
+    };
+
+    let mut failures = 0;
+    let mut unreachable = vec![];
+    let mut total = Summary::default();
+    let mut cycles_run = 0;
+    'cycles: for cycle in 1..=cycles {
+        cycles_run = cycle;
+        let mut cycle_summary = Summary::default();
+        let mut cycle_unreachable = vec![];
+
+        for host in &hosts {
+            // A single broken server should not prevent testing the others. Setting up the configuration every cycle
+            // also refreshes the NTS cookies during a soak.
+            let config = match cli.config(host, &ke_options, pcap.clone()) {
+                Ok(config) => config,
+                Err(e) => {
+                    eprintln!("❓ Could not set up tests for {host}: {e:#}");
+                    cycle_unreachable.push(host.as_str());
+                    continue;
+                }
+            };
+
+            if let (true, Server::Nts(server)) = (cli.ke_timings, &config.server) {
+                eprintln!("NTS-KE setup of {host}: {}", server.setup_timings());
             }
-            Err(TestError::Fail(msg, None)) => {
-                failed += 1;
-                println!("❌ {name}\n ↳ {msg}")
+
+            if let Some(count) = cli.stress_ke {
+                let Server::Nts(server) = &config.server else {
+                    unreachable!("--stress-ke requires --nts");
+                };
+
+                // Like the other side reports this goes to stderr, stdout is reserved for the --format output
+                eprintln!(
+                    "Stress testing the NTS-KE server of {host} with {count} concurrent connections"
+                );
+                let report = stress_ke(server, count.into());
+                eprintln!("{report}\n");
+                if !report.is_success() {
+                    failures += 1;
+                }
+                continue;
+            }
+
+            let config = Arc::new(config);
+            let mut outcomes = Outcomes::new();
+            let mut runs = 0;
+            let mut failed_fast = false;
+            for run in 1..=cli.repeat {
+                runs = run;
+                let mut suite = host.clone();
+                if soak.is_some() {
+                    suite.push_str(&format!(" (cycle {cycle})"));
+                }
+                if cli.repeat > 1 {
+                    suite.push_str(&format!(" (run {run}/{})", cli.repeat));
+                }
+
+                reporter.set_server(host);
+                reporter.start_suite(&suite)?;
+                let summary =
+                    cli.run_suite(host, &options, &config, &mut reporter, &mut outcomes)?;
+                reporter.finish_suite()?;
+
+                cycle_summary.merge(&summary);
+                failures += cli.failures(&summary);
+                failed_fast = cli.fail_fast && failures > 0;
+                if failed_fast {
+                    break;
+                }
             }
-            Err(TestError::Fail(msg, Some(r))) => {
-                failed += 1;
-                println!("❌ {name}\n ↳ {msg}\n ↳ {r:#?}")
+
+            if cli.repeat > 1 {
+                print_flakiness(host, runs, &outcomes);
             }
-            Err(TestError::Skipped) => {
-                skipped += 1;
-                println!("⏩ {name}")
+            if cli.only_failures {
+                let mut failed: Vec<_> = outcomes
+                    .iter()
+                    .filter(|(_, &[_, failed, errored, _])| {
+                        failed > 0 || (errored > 0 && !cli.ignore_errors)
+                    })
+                    .map(|(&name, _)| name)
+                    .collect();
+                failed.sort();
+
+                if !failed.is_empty() {
+                    // A fresh configuration, so the re-runs get new NTS cookies too
+                    match cli.config(host, &ke_options, pcap.clone()) {
+                        Ok(config) => cli.rerun_with_dumps(&options, config, &failed),
+                        Err(e) => eprintln!("❓ Could not set up re-runs for {host}: {e:#}"),
+                    }
+                }
             }
-            Err(TestError::Error(e)) => {
-                errored += 1;
-                println!("❓ {name}:\n ↳ {e:#}")
+            if failed_fast {
+                break 'cycles;
+            }
+        }
+
+        let Some(interval) = soak else {
+            unreachable = cycle_unreachable;
+            break;
+        };
+
+        // During a soak an unreachable server is just a bad cycle, the next one might reach it again
+        failures += cycle_unreachable.len();
+        total.merge(&cycle_summary);
+        let Summary {
+            passed,
+            failed,
+            errored,
+            skipped,
+        } = cycle_summary;
+        let mut line = format!(
+            "{} cycle {cycle}: ✅ {passed} ❌ {failed} ❓ {errored} ⏩ {skipped}",
+            humantime::format_rfc3339_seconds(SystemTime::now())
+        );
+        if !cycle_unreachable.is_empty() {
+            line.push_str(&format!(", unreachable: {}", cycle_unreachable.join(", ")));
+        }
+        eprintln!("{line}");
+
+        if cycle == cycles {
+            break;
+        }
+        if let Some(interrupted) = &interrupted {
+            match interrupted.recv_timeout(interval) {
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Ok(()) | Err(mpsc::RecvTimeoutError::Disconnected) => break,
             }
         }
     }
 
-    println!(
-        "\n✅ Passed: {passed}\n❌ Failed: {failed}\n❓ Errored: {errored}\n⏩ Skipped: {skipped}"
-    );
+    reporter.finish()?;
+
+    if soak.is_some() {
+        eprintln!("\nTotal over {cycles_run} cycles:\n{total}");
+    }
+
+    if let Some(path) = &cli.baseline {
+        let file = File::create(path)
+            .with_context(|| format!("Could not create baseline file {}", path.display()))?;
+        let mut out = BufWriter::new(file);
+        serde_json::to_writer_pretty(&mut out, reporter.reports())
+            .map_err(io::Error::from)
+            .and_then(|()| out.flush())
+            .with_context(|| format!("Could not write baseline file {}", path.display()))?;
+    }
+
+    if let Some(baseline) = &baseline {
+        let changes = compare_baseline(baseline, reporter.reports());
+        eprintln!("\n{}", ChangeReport(&changes));
+        failures += changes
+            .iter()
+            .filter(|change| change.is_regression())
+            .count();
+    }
+
+    if !unreachable.is_empty() {
+        anyhow::bail!("Could not test servers: {}", unreachable.join(", "));
+    }
 
-    Ok(())
+    if failures > 0 {
+        return Ok(ExitCode::FAILURE);
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Read the reports saved with `--baseline`
+fn load_baseline(path: &Path) -> anyhow::Result<Vec<TestReport>> {
+    let file = File::open(path)
+        .with_context(|| format!("Could not open baseline file {}", path.display()))?;
+    serde_json::from_reader(io::BufReader::new(file))
+        .with_context(|| format!("Could not parse baseline file {}", path.display()))
+}
+
+/// A channel that receives a message when the process is interrupted with Ctrl-C
+///
+/// A second interrupt exits right away, in case the current cycle hangs.
+fn interrupt_channel() -> anyhow::Result<mpsc::Receiver<()>> {
+    let (sender, receiver) = mpsc::channel();
+    let interrupted = AtomicBool::new(false);
+    ctrlc::set_handler(move || {
+        if interrupted.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+        eprintln!("Interrupted, finishing the current cycle");
+        let _ = sender.send(());
+    })
+    .context("Could not install Ctrl-C handler")?;
+
+    Ok(receiver)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The names of the tests selected by the given command line
+    fn selected(args: &[&str]) -> Vec<&'static str> {
+        let cli = Cli::parse_from(["network-time-pester"].iter().chain(args));
+        pest::all_tests(&cli.suite_options())
+            .filter(|test| cli.selects(test.as_ref()))
+            .map(|test| test_name(test.as_ref()))
+            .collect()
+    }
+
+    #[test]
+    fn filter_and_exclude_select_by_name() {
+        let all = selected(&[]);
+        let filtered = selected(&["--filter", "nts_ke::"]);
+        let excluded = selected(&["--filter", "nts_ke::", "--exclude", "aead"]);
+
+        assert!(!filtered.is_empty());
+        assert!(filtered.len() < all.len());
+        assert!(filtered.iter().all(|name| name.contains("nts_ke::")));
+        assert!(excluded.len() < filtered.len());
+        assert!(excluded
+            .iter()
+            .all(|name| name.contains("nts_ke::") && !name.contains("aead")));
+    }
+
+    #[test]
+    fn filter_matches_trimmed_name() {
+        assert_eq!(
+            selected(&["--filter", "tests::basic::test_responds_to_version_4"]),
+            ["tests::basic::test_responds_to_version_4"]
+        );
+        assert!(selected(&["--filter", "network_time_pester::"]).is_empty());
+    }
+
+    #[test]
+    fn tag_selects_exactly_the_tagged_tests() {
+        let amplification = [
+            "tests::basic::test_amplification_factor",
+            "tests::modes::test_ignores_control_and_private_modes",
+        ];
+        assert_eq!(selected(&["--tag", "amplification"]), amplification);
+        assert_eq!(
+            selected(&["--tag", "amplification", "--multicast"]),
+            [
+                amplification.as_slice(),
+                &["tests::modes::test_no_unicast_response_to_multicast"]
+            ]
+            .concat()
+        );
+        // Every amplification test is a security test as well
+        assert_eq!(
+            selected(&["--tag", "amplification", "--tag", "security", "--all-tags"]),
+            amplification
+        );
+    }
+
+    #[test]
+    fn thresholds_default_unless_given() {
+        let options = Cli::parse_from(["network-time-pester"]).suite_options();
+        assert_eq!(options.max_amplification_factor, 1.5);
+
+        let options =
+            Cli::parse_from(["network-time-pester", "--max-amplification", "2"]).suite_options();
+        assert_eq!(options.max_amplification_factor, 2.0);
+    }
 }
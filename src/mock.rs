@@ -0,0 +1,414 @@
+//! A minimal in-process NTP and NTS-KE server, to check the test harness itself without a real server
+//!
+//! Only available with the `test-utils` feature. The NTP responder answers every NTPv4 client request with a plausible
+//! stratum 1 response, the NTS-KE responder answers every request with the same canned records and random cookies.
+//! Neither implements NTS, so the NTP side does not accept the cookies.
+
+use crate::nts_ke::KeOptions;
+use crate::report::{Reporter, Summary, TestInfo};
+use crate::tests::extensions::UNIQUE_IDENTIFIER_TYPE;
+use crate::tests::{basic, nts_ke};
+use crate::udp::{ntp_timestamp, udp_test, NtpTimestampExt};
+use crate::{AddressFamily, NtsServer, Server, TestCase, TestConfig};
+use anyhow::Context;
+use ntp_proto::NtsRecord;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::{RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::panic::UnwindSafe;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// How often the responder threads check whether the server was dropped
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Number of cookies handed out by every key exchange, like ntpd-rs does
+const COOKIE_COUNT: usize = 8;
+
+/// Length of the random cookies
+const COOKIE_LEN: usize = 64;
+
+/// A NTP and a NTS-KE server on localhost, running until dropped
+pub struct MockServer {
+    ntp_addr: SocketAddr,
+    ke_port: u16,
+    root_cert_store: Arc<RootCertStore>,
+    key_exchanges: Arc<AtomicUsize>,
+    stop: Arc<AtomicBool>,
+}
+
+impl MockServer {
+    /// Bind both servers to ephemeral ports on 127.0.0.1 and start answering requests on background threads
+    ///
+    /// The NTS-KE server presents a freshly generated self-signed certificate for `localhost`, see
+    /// [`root_cert_store`](Self::root_cert_store).
+    pub fn start() -> anyhow::Result<Self> {
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let ntp = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).context("Could not bind NTP socket")?;
+        ntp.set_read_timeout(Some(POLL_INTERVAL))?;
+        let ntp_addr = ntp.local_addr()?;
+
+        let (tls, root_cert_store) = tls_config(&["localhost"])?;
+
+        let ke =
+            TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).context("Could not bind NTS-KE socket")?;
+        ke.set_nonblocking(true)?;
+        let ke_port = ke.local_addr()?.port();
+
+        let ntp_stop = Arc::clone(&stop);
+        std::thread::spawn(move || serve_ntp(ntp, &ntp_stop));
+        let ke_stop = Arc::clone(&stop);
+        let key_exchanges = Arc::new(AtomicUsize::new(0));
+        let ke_count = Arc::clone(&key_exchanges);
+        let tls = Arc::new(tls);
+        std::thread::spawn(move || serve_ke(ke, tls, ntp_addr, &ke_count, &ke_stop));
+
+        Ok(Self {
+            ntp_addr,
+            ke_port,
+            root_cert_store: Arc::new(root_cert_store),
+            key_exchanges,
+            stop,
+        })
+    }
+
+    /// The address of the NTP server
+    pub fn ntp_addr(&self) -> SocketAddr {
+        self.ntp_addr
+    }
+
+    /// The port of the NTS-KE server on `localhost`
+    pub fn ke_port(&self) -> u16 {
+        self.ke_port
+    }
+
+    /// Root certificates trusting only the certificate of the NTS-KE server
+    pub fn root_cert_store(&self) -> Arc<RootCertStore> {
+        Arc::clone(&self.root_cert_store)
+    }
+
+    /// Settings to connect to the NTS-KE server with
+    pub fn ke_options(&self, timeout: Duration) -> KeOptions {
+        ke_options(self.root_cert_store(), timeout)
+    }
+
+    /// The number of key exchanges the NTS-KE server answered so far
+    pub fn key_exchanges(&self) -> usize {
+        self.key_exchanges.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// A TLS server for `names` with a freshly generated self-signed certificate, and root certificates trusting it
+fn tls_config(names: &[&str]) -> anyhow::Result<(ServerConfig, RootCertStore)> {
+    let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+    let cert =
+        rcgen::generate_simple_self_signed(names).context("Could not generate certificate")?;
+    let cert_der = CertificateDer::from(cert.serialize_der()?);
+    let key_der = PrivateKeyDer::from(PrivatePkcs8KeyDer::from(cert.serialize_private_key_der()));
+    let mut root_cert_store = RootCertStore::empty();
+    root_cert_store.add(cert_der.clone())?;
+
+    let mut tls = ServerConfig::builder_with_protocol_versions(&[&rustls::version::TLS13])
+        .with_no_client_auth()
+        .with_single_cert(vec![cert_der], key_der)
+        .context("Invalid certificate")?;
+    tls.alpn_protocols = vec![b"ntske/1".to_vec()];
+
+    Ok((tls, root_cert_store))
+}
+
+/// The server side of a connection to a [`MockTlsServer`]
+pub type MockTlsStream = StreamOwned<ServerConnection, TcpStream>;
+
+/// A TLS server on localhost for a single connection, which is answered however the test likes
+///
+/// Unlike [`MockServer`] this allows sending malformed or oddly split responses. It presents a certificate like the
+/// NTS-KE server of [`MockServer`].
+pub struct MockTlsServer {
+    port: u16,
+    root_cert_store: Arc<RootCertStore>,
+}
+
+impl MockTlsServer {
+    /// Bind to an ephemeral port on 127.0.0.1, and pass the first connection to `respond` on a background thread
+    pub fn start<F>(respond: F) -> anyhow::Result<Self>
+    where
+        F: FnOnce(&mut MockTlsStream) -> io::Result<()> + Send + 'static,
+    {
+        Self::start_for(&["localhost"], respond)
+    }
+
+    /// Start the server like [`start`](Self::start), but with a certificate for the server `names`
+    pub fn start_for<F>(names: &[&str], respond: F) -> anyhow::Result<Self>
+    where
+        F: FnOnce(&mut MockTlsStream) -> io::Result<()> + Send + 'static,
+    {
+        let (tls, root_cert_store) = tls_config(names)?;
+        let listener =
+            TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).context("Could not bind TLS socket")?;
+        let port = listener.local_addr()?.port();
+
+        std::thread::spawn(move || {
+            let result = listener.accept().and_then(|(stream, _)| {
+                stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+                let connection = ServerConnection::new(Arc::new(tls)).map_err(io::Error::other)?;
+                respond(&mut StreamOwned::new(connection, stream))
+            });
+            if let Err(e) = result {
+                tracing::debug!(error = ?e, "mock TLS server failed");
+            }
+        });
+
+        Ok(Self {
+            port,
+            root_cert_store: Arc::new(root_cert_store),
+        })
+    }
+
+    /// The port of the server on `localhost`
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Settings to connect to the server with
+    pub fn ke_options(&self, timeout: Duration) -> KeOptions {
+        ke_options(Arc::clone(&self.root_cert_store), timeout)
+    }
+}
+
+/// Settings to connect to a mock server trusting `root_cert_store`
+///
+/// The servers only listen on 127.0.0.1, so `localhost` must not resolve to `::1`.
+fn ke_options(root_cert_store: Arc<RootCertStore>, timeout: Duration) -> KeOptions {
+    KeOptions {
+        root_cert_store,
+        timeout,
+        address_family: AddressFamily::V4,
+        client_cert: None,
+        proxy: None,
+        sni: None,
+        insecure_skip_verify: false,
+    }
+}
+
+/// Read records from `stream` up to and including EndOfMessage
+pub fn read_request(stream: &mut impl Read) -> io::Result<Vec<NtsRecord>> {
+    let mut records = vec![];
+    loop {
+        let record = NtsRecord::read(stream)?;
+        let end = record == NtsRecord::EndOfMessage;
+        records.push(record);
+        if end {
+            return Ok(records);
+        }
+    }
+}
+
+/// Write all `records` to `stream`, and close it
+pub fn write_response(
+    stream: &mut MockTlsStream,
+    records: impl IntoIterator<Item = NtsRecord>,
+) -> io::Result<()> {
+    let mut buf = vec![];
+    for record in records {
+        record.write(&mut buf)?;
+    }
+    stream.write_all(&buf)?;
+    stream.conn.send_close_notify();
+    stream.flush()
+}
+
+/// Answer NTP requests on `socket` until `stop` is set
+fn serve_ntp(socket: UdpSocket, stop: &AtomicBool) {
+    let mut buf = [0u8; 1024];
+    while !stop.load(Ordering::Relaxed) {
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+            Err(e) => {
+                tracing::debug!(error = ?e, "mock NTP server could not receive");
+                continue;
+            }
+        };
+
+        if let Some(response) = ntp_response(&buf[..len], SystemTime::now()) {
+            if let Err(e) = socket.send_to(&response, from) {
+                tracing::debug!(error = ?e, "mock NTP server could not send");
+            }
+        }
+    }
+}
+
+/// The response to `request` received at `received`, or `None` if it is not a valid NTPv4 client request
+///
+/// Extension fields are checked for the length rules of RFC7822, only a unique identifier is returned.
+fn ntp_response(request: &[u8], received: SystemTime) -> Option<Vec<u8>> {
+    let header = request.get(..48)?;
+    let version = (header[0] >> 3) & 0b111;
+    let mode = header[0] & 0b111;
+    if version != 4 || mode != 3 {
+        return None;
+    }
+
+    let mut extensions = vec![];
+    let mut rest = &request[48..];
+    while !rest.is_empty() {
+        let field_header = rest.get(..4)?;
+        let len = u16::from_be_bytes([field_header[2], field_header[3]]) as usize;
+        if len < 16 || !len.is_multiple_of(4) {
+            return None;
+        }
+        let field = rest.get(..len)?;
+        // The unique identifier is returned as is
+        if u16::from_be_bytes([field[0], field[1]]) == UNIQUE_IDENTIFIER_TYPE {
+            extensions.extend_from_slice(field);
+        }
+        rest = &rest[len..];
+    }
+
+    let mut response = Vec::with_capacity(48 + extensions.len());
+    response.push((4 << 3) | 4); // no leap second, version 4, server mode
+    response.push(1); // stratum
+    response.push(header[2]); // poll
+    response.push(-20i8 as u8); // precision of about a microsecond
+    response.extend_from_slice(&0u32.to_be_bytes()); // root delay
+    response.extend_from_slice(&0x0000_0010u32.to_be_bytes()); // root dispersion of about 250µs
+    response.extend_from_slice(b"MOCK");
+    response.extend_from_slice(&ntp_bytes(received - Duration::from_secs(16)));
+    response.extend_from_slice(&header[40..48]);
+    response.extend_from_slice(&ntp_bytes(received));
+    response.extend_from_slice(&ntp_bytes(SystemTime::now()));
+    response.extend_from_slice(&extensions);
+
+    Some(response)
+}
+
+/// `time` as a NTP timestamp on the wire
+fn ntp_bytes(time: SystemTime) -> [u8; 8] {
+    ntp_timestamp(time).as_u64().to_be_bytes()
+}
+
+/// Accept NTS-KE connections on `listener` until `stop` is set, answering them one at a time
+fn serve_ke(
+    listener: TcpListener,
+    tls: Arc<ServerConfig>,
+    ntp_addr: SocketAddr,
+    key_exchanges: &AtomicUsize,
+    stop: &AtomicBool,
+) {
+    while !stop.load(Ordering::Relaxed) {
+        let stream = match listener.accept() {
+            Ok((stream, _)) => stream,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                std::thread::sleep(POLL_INTERVAL);
+                continue;
+            }
+            Err(e) => {
+                tracing::debug!(error = ?e, "mock NTS-KE server could not accept");
+                continue;
+            }
+        };
+
+        if let Err(e) = answer_ke(stream, Arc::clone(&tls), ntp_addr, key_exchanges) {
+            tracing::debug!(error = ?e, "mock NTS-KE exchange failed");
+        }
+    }
+}
+
+/// Read a request until EndOfMessage, and send the canned response pointing to the NTP server at `ntp_addr`
+///
+/// Complete requests are counted in `key_exchanges` before the response is sent, so a client sees its own exchange.
+fn answer_ke(
+    stream: TcpStream,
+    tls: Arc<ServerConfig>,
+    ntp_addr: SocketAddr,
+    key_exchanges: &AtomicUsize,
+) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+    let connection = ServerConnection::new(tls).map_err(io::Error::other)?;
+    let mut stream = StreamOwned::new(connection, stream);
+
+    let request = read_request(&mut stream)?;
+    tracing::trace!(?request, "mock NTS-KE server received request");
+    key_exchanges.fetch_add(1, Ordering::Relaxed);
+
+    let mut records = vec![
+        NtsRecord::NextProtocol {
+            protocol_ids: vec![0],
+        },
+        NtsRecord::AeadAlgorithm {
+            critical: false,
+            algorithm_ids: vec![15],
+        },
+        NtsRecord::Server {
+            critical: false,
+            name: ntp_addr.ip().to_string(),
+        },
+        NtsRecord::Port {
+            critical: false,
+            port: ntp_addr.port(),
+        },
+    ];
+    records.extend((0..COOKIE_COUNT).map(|_| NtsRecord::NewCookie {
+        cookie_data: (0..COOKIE_LEN).map(|_| rand::random()).collect(),
+    }));
+    records.push(NtsRecord::EndOfMessage);
+
+    write_response(&mut stream, records)
+}
+
+/// Run the tests for a normal exchange against a [`MockServer`], passing the results to `reporter`
+///
+/// The harness is working if they all pass.
+pub fn self_test(reporter: &mut dyn Reporter, timeout: Duration) -> anyhow::Result<Summary> {
+    let mock = MockServer::start()?;
+    let nts = NtsServer::new(
+        "localhost".to_string(),
+        mock.ke_port(),
+        mock.ke_options(timeout),
+    )
+    .context("Could not do a key exchange with the mock NTS-KE server")?;
+
+    let tests: [(Server, Box<dyn TestCase + UnwindSafe + Send>); 2] = [
+        (
+            Server::Ntp(mock.ntp_addr()),
+            udp_test(basic::test_responds_to_version_4),
+        ),
+        (Server::Nts(Box::new(nts)), nts_ke::happy(None)),
+    ];
+
+    let mut summary = Summary::default();
+    reporter.start_suite("mock server")?;
+    for (server, test) in tests {
+        let config = TestConfig {
+            server,
+            timeout,
+            address_family: AddressFamily::V4,
+            packet_trace: None,
+            pcap: None,
+        };
+        let result = test.run(&config);
+        let info = TestInfo {
+            name: test.name().trim_start_matches("network_time_pester::"),
+            category: test.category(),
+            description: test.description(),
+            rfc: test.rfc(),
+        };
+        summary.add(&result);
+        reporter.report(&info, &result, None)?;
+    }
+    reporter.finish_suite()?;
+    reporter.finish()?;
+
+    Ok(summary)
+}
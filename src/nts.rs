@@ -1,15 +1,15 @@
 //! Functions and types for implementing NTS tests
 
 use crate::udp::{udp_server_still_alive, UdpConnection};
-use crate::{RawBytes, TestCase, TestConfig, TestResult};
-use ntp_proto::NtsKeys;
+use crate::{Category, RawBytes, TestCase, TestConfig, TestResult};
+use ntp_proto::{NtpPacket, NtsKeys};
 use std::ops::Deref;
 use std::panic::UnwindSafe;
 
 /// A wrapper for a NTS cookie
 ///
 /// Using this wrapper ensures we can not mix up byte slices and improves debug printing.
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub struct NtsCookie(pub RawBytes);
 
 impl Deref for NtsCookie {
@@ -20,15 +20,36 @@ impl Deref for NtsCookie {
     }
 }
 
+impl NtsCookie {
+    /// A copy of this cookie with one byte flipped, which the server should no longer accept
+    pub fn corrupted(&self) -> Self {
+        let mut bytes = self.0 .0.clone();
+        let middle = bytes.len() / 2;
+        if let Some(byte) = bytes.get_mut(middle) {
+            *byte ^= 0xff;
+        }
+
+        Self(RawBytes(bytes))
+    }
+}
+
+/// Collect the new cookies the server sent in an NTS protected response
+pub fn new_cookies(packet: &NtpPacket) -> Vec<NtsCookie> {
+    packet
+        .new_cookies()
+        .map(|cookie| NtsCookie(cookie.into()))
+        .collect()
+}
+
 /// Wrap a test function that requires a server connection, as well as NTS data
 ///
 /// The function passed will be called during test execution. It gets passed a [`UdpConnection`] to the target server,
 /// as well as a valid NTS server cookie, and matching key set.
 ///
 /// If the test completes successfully this wrapper checks if the server still replies to normal requests.
-pub fn nts_test<F>(f: F) -> Box<dyn TestCase + UnwindSafe>
+pub fn nts_test<F>(f: F) -> Box<dyn TestCase + UnwindSafe + Send>
 where
-    F: Fn(&mut UdpConnection, NtsCookie, &NtsKeys) -> TestResult + UnwindSafe + 'static,
+    F: Fn(&mut UdpConnection, NtsCookie, &NtsKeys) -> TestResult + UnwindSafe + Send + 'static,
 {
     struct KeTest<F> {
         f: F,
@@ -42,6 +63,10 @@ where
             std::any::type_name::<F>()
         }
 
+        fn category(&self) -> Category {
+            Category::Nts
+        }
+
         fn run(&self, conf: &TestConfig) -> TestResult {
             let mut conn = conf.udp()?;
             let (test_cookie, keys) = conf.take_cookie()?;
@@ -1,18 +1,89 @@
 //! Functionality to connect to a NTS-KE server and run tests against it
 
 use crate::nts::NtsCookie;
-use crate::util::result::{fail, TestError, TestResult};
-use crate::{TestCase, TestConfig};
-use anyhow::{anyhow, Context};
-use ntp_proto::{AeadAlgorithm, AesSivCmac256, NtsKeys, NtsRecord, NtsRecordDecoder, ProtocolId};
-use rustls::pki_types::ServerName;
-use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
-use std::fmt::Debug;
-use std::io::{Read, Write};
+use crate::socks::Socks5Proxy;
+use crate::udp::UdpConnection;
+use crate::util::result::{fail, fail_no_response, TestError, TestResult};
+use crate::{
+    AddressFamily, Category, ClientCert, Direction, NtsServer, PacketTrace, TestCase, TestConfig,
+};
+use anyhow::Context;
+use ntp_proto::{
+    AeadAlgorithm, AesSivCmac256, AesSivCmac512, NtsKeys, NtsRecord, NtsRecordDecoder, ProtocolId,
+};
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{
+    ClientConfig, ClientConnection, DigitallySignedStruct, ProtocolVersion, RootCertStore,
+    SignatureScheme, StreamOwned, SupportedProtocolVersion,
+};
+use std::fmt::{Debug, Display, Formatter};
+use std::io::{self, ErrorKind, Read, Write};
 use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
 use std::panic::UnwindSafe;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+/// Settings shared by all connections to a NTS-KE server
+#[derive(Debug, Clone)]
+pub struct KeOptions {
+    /// Used to verify the server signature
+    pub root_cert_store: Arc<RootCertStore>,
+    /// Timeout for every read and write on the connection
+    pub timeout: Duration,
+    /// Only addresses of this family are used, for the key exchange itself as well as for the NTP server it points to
+    pub address_family: AddressFamily,
+    /// Presented to servers that require mutual TLS
+    pub client_cert: Option<Arc<ClientCert>>,
+    /// Connect through this proxy instead of directly, the NTP traffic is not proxied
+    pub proxy: Option<Socks5Proxy>,
+    /// Present this name to the server instead of the host connected to, e.g. to reach a virtual host by IP
+    pub sni: Option<String>,
+    /// Accept any server certificate, e.g. a self-signed one on a test server. The handshake signatures are still
+    /// checked, but anyone can impersonate the server.
+    pub insecure_skip_verify: bool,
+}
+
+/// How long each phase of a key exchange took, `None` for phases that did not happen (yet)
+///
+/// Connections through a proxy let it resolve the host, so their DNS time is part of the TCP phase. Without an explicit
+/// [`handshake`](NtsKeConnection::handshake) the TLS handshake is part of the exchange phase.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct KeTimings {
+    pub dns: Option<Duration>,
+    pub tcp: Option<Duration>,
+    pub tls: Option<Duration>,
+    pub exchange: Option<Duration>,
+}
+
+impl Display for KeTimings {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let phases = [
+            ("DNS", self.dns),
+            ("TCP", self.tcp),
+            ("TLS", self.tls),
+            ("exchange", self.exchange),
+        ];
+
+        let mut first = true;
+        for (name, duration) in phases {
+            let Some(duration) = duration else {
+                continue;
+            };
+            if !first {
+                f.write_str(", ")?;
+            }
+            first = false;
+            write!(f, "{name} {:.2}ms", duration.as_secs_f64() * 1000.0)?;
+        }
+
+        Ok(())
+    }
+}
 
 /// An active connection to a NTS-KE server
 ///
@@ -22,55 +93,200 @@ use std::time::Duration;
 pub struct NtsKeConnection {
     stream: StreamOwned<ClientConnection, TcpStream>,
     host: String,
+    address_family: AddressFamily,
     record_decoder: NtsRecordDecoder,
+    /// Received bytes that do not form a complete record yet, framed by the lengths in the record headers
+    partial: Vec<u8>,
+    /// Total bytes of NTS-KE records received on this connection
+    received: usize,
+    read_buf: Vec<u8>,
+    trace: Option<PacketTrace>,
+    timings: KeTimings,
 }
 
 impl NtsKeConnection {
-    /// Connect to the server given by `host` and `port`
-    ///
-    /// The `root_cert_store` is used to verify the server signature
-    pub fn new(
+    const INITIAL_READ_LEN: usize = 4096;
+    const MAX_READ_LEN: usize = 64 * 1024;
+
+    /// Connect to the server given by `host` and `port`, using the given `options`
+    pub fn new(host: &str, port: u16, options: &KeOptions) -> TestResult<Self> {
+        Self::new_with_versions(host, port, options, rustls::DEFAULT_VERSIONS)
+    }
+
+    /// Connect to the server like [`new`](NtsKeConnection::new), but only offer the given TLS versions
+    pub fn new_with_versions(
         host: &str,
         port: u16,
-        root_cert_store: &Arc<RootCertStore>,
-        timeout: Duration,
+        options: &KeOptions,
+        versions: &[&'static SupportedProtocolVersion],
     ) -> TestResult<Self> {
-        let addr = (host, port)
-            .to_socket_addrs()
-            .context(format!("Could not resolve host: {host:?}"))?
-            .next()
-            .context(format!("Host has no IP entries: {host:?}"))?;
-
-        let mut config = ClientConfig::builder()
-            .with_root_certificates(Arc::clone(root_cert_store))
-            .with_no_client_auth();
-
-        // Ensure we send only ntske/1 as alpn
-        config.alpn_protocols.clear();
-        config.alpn_protocols.push(b"ntske/1".to_vec());
-
-        let domain = ServerName::try_from(host)
-            .context("invalid dnsname")?
+        let config = client_config(options, versions, &[b"ntske/1".to_vec()])?;
+
+        Self::connect(host, port, config, options)
+    }
+
+    /// Connect to the server like [`new`](NtsKeConnection::new), but offer the given ALPN protocols instead of
+    /// `ntske/1`
+    pub fn new_with_alpn(
+        host: &str,
+        port: u16,
+        options: &KeOptions,
+        alpn: &[Vec<u8>],
+    ) -> TestResult<Self> {
+        let config = client_config(options, rustls::DEFAULT_VERSIONS, alpn)?;
+
+        Self::connect(host, port, config, options)
+    }
+
+    fn connect(
+        host: &str,
+        port: u16,
+        config: ClientConfig,
+        options: &KeOptions,
+    ) -> TestResult<Self> {
+        let KeOptions {
+            timeout,
+            address_family,
+            ..
+        } = *options;
+
+        let server_name = options.sni.as_deref().unwrap_or(host);
+        let domain = ServerName::try_from(server_name)
+            .with_context(|| format!("Invalid server name {server_name:?}"))?
             .to_owned();
+        tracing::debug!(host, server_name, "connecting to NTS-KE server");
         let connection = ClientConnection::new(Arc::new(config), domain)
             .context("Could not open TLS connection")?;
-        let stream = TcpStream::connect(addr).context("Could not open TCP connection")?;
-        stream
-            .set_read_timeout(Some(timeout))
-            .context("Could not set read timeout")?;
-        stream
-            .set_write_timeout(Some(timeout))
-            .context("Could not set write timeout")?;
+
+        let mut timings = KeTimings::default();
+        let stream = match &options.proxy {
+            // The proxy resolves the host, so the address family can not be chosen
+            Some(proxy) => {
+                let start = Instant::now();
+                let stream = proxy
+                    .connect(host, port, timeout)
+                    .with_context(|| format!("Could not connect through proxy {proxy}"))?;
+                timings.tcp = Some(start.elapsed());
+                stream
+            }
+            None => {
+                let start = Instant::now();
+                let addrs = (host, port)
+                    .to_socket_addrs()
+                    .context(format!("Could not resolve host: {host:?}"))?;
+                let addr = address_family
+                    .select(addrs)
+                    .context(format!("Host has no {address_family} entries: {host:?}"))?;
+                tracing::debug!(%addr, "resolved NTS-KE server");
+                timings.dns = Some(start.elapsed());
+
+                let start = Instant::now();
+                let stream = TcpStream::connect_timeout(&addr, timeout)
+                    .with_context(|| format!("Could not open TCP connection to {addr}"))?;
+                timings.tcp = Some(start.elapsed());
+                tracing::debug!(%addr, "connected to NTS-KE server");
+                stream
+                    .set_read_timeout(Some(timeout))
+                    .context("Could not set read timeout")?;
+                stream
+                    .set_write_timeout(Some(timeout))
+                    .context("Could not set write timeout")?;
+                stream
+            }
+        };
 
         let stream = StreamOwned::new(connection, stream);
 
         Ok(Self {
             stream,
             host: host.to_string(),
+            address_family,
             record_decoder: Default::default(),
+            partial: vec![],
+            received: 0,
+            read_buf: vec![0; Self::INITIAL_READ_LEN],
+            trace: None,
+            timings,
         })
     }
 
+    /// Pass all data sent and received on this connection to `trace`
+    pub fn with_trace(mut self, trace: Option<PacketTrace>) -> Self {
+        self.trace = trace;
+        self
+    }
+
+    fn trace(&self, direction: Direction, bytes: &[u8]) {
+        if let Some(trace) = &self.trace {
+            trace.trace("NTS-KE", direction, bytes);
+        }
+    }
+
+    /// Complete the TLS handshake, returning the negotiated TLS version
+    ///
+    /// The handshake is normally performed implicitly when sending the first record.
+    pub fn handshake(&mut self) -> TestResult<Option<ProtocolVersion>> {
+        let start = Instant::now();
+        while self.stream.conn.is_handshaking() {
+            self.stream
+                .conn
+                .complete_io(&mut self.stream.sock)
+                .context("TLS handshake failed")?;
+        }
+        self.timings.tls.get_or_insert(start.elapsed());
+
+        Ok(self.stream.conn.protocol_version())
+    }
+
+    /// Complete the TLS handshake like [`handshake`](NtsKeConnection::handshake), but give up at `deadline`
+    ///
+    /// A server that accepts the connection but trickles the handshake can otherwise stall it for a timeout per
+    /// message.
+    pub fn handshake_before(&mut self, deadline: Instant) -> TestResult<Option<ProtocolVersion>> {
+        let start = Instant::now();
+        while self.stream.conn.is_handshaking() {
+            self.set_timeout(
+                remaining(deadline).context("TLS handshake did not complete in time")?,
+            )?;
+            self.stream
+                .conn
+                .complete_io(&mut self.stream.sock)
+                .context("TLS handshake failed")?;
+        }
+        self.timings.tls.get_or_insert(start.elapsed());
+
+        Ok(self.stream.conn.protocol_version())
+    }
+
+    /// How long the phases of this connection took so far
+    pub fn timings(&self) -> KeTimings {
+        self.timings
+    }
+
+    /// Set the timeout of every following read and write on the connection
+    pub fn set_timeout(&mut self, timeout: Duration) -> TestResult {
+        self.stream
+            .sock
+            .set_read_timeout(Some(timeout))
+            .context("Could not set read timeout")?;
+        self.stream
+            .sock
+            .set_write_timeout(Some(timeout))
+            .context("Could not set write timeout")?;
+
+        Ok(())
+    }
+
+    /// The host name this connection was opened to
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// The IP family used to connect to the server and the NTP server it negotiates
+    pub fn address_family(&self) -> AddressFamily {
+        self.address_family
+    }
+
     /// Serialize and send a single record to the server
     pub fn send_record(&mut self, record: NtsRecord) -> TestResult {
         let mut buf = vec![];
@@ -78,6 +294,8 @@ impl NtsKeConnection {
             .write(&mut buf)
             .expect("Writing into a vec can not fail");
 
+        tracing::trace!(?record, "sending NTS-KE record");
+        self.trace(Direction::Sent, &buf);
         self.stream
             .write_all(&buf[..])
             .context("Failed to write to TLS connection")?;
@@ -85,6 +303,24 @@ impl NtsKeConnection {
         Ok(())
     }
 
+    /// Send `bytes` as is, without interpreting them as records
+    ///
+    /// Every call is flushed into its own TLS record, which allows tests to control how the request is split up.
+    pub fn send_raw(&mut self, bytes: &[u8]) -> TestResult {
+        self.trace(Direction::Sent, bytes);
+        self.stream
+            .write_all(bytes)
+            .and_then(|()| self.stream.flush())
+            .context("Failed to write to TLS connection")?;
+
+        Ok(())
+    }
+
+    /// The number of bytes of NTS-KE records received so far, not counting the TLS overhead
+    pub fn bytes_received(&self) -> usize {
+        self.received
+    }
+
     /// Try to receive the next record from the server
     ///
     /// Behaves similar to an iterator. Returns `Ok(Some(record))` until all records have been received when it returns
@@ -96,20 +332,47 @@ impl NtsKeConnection {
                 .step()
                 .context("Could not read from NTS records")?
             {
+                tracing::trace!(?record, "received NTS-KE record");
                 return Ok(Some(record));
             }
 
-            let mut buf = vec![0; 1024];
-            let read_bytes = self
-                .stream
-                .read(&mut buf)
-                .context("Could not read from TLS connection")?;
-            buf.truncate(read_bytes);
-            if buf.is_empty() {
+            let read_bytes = match self.stream.read(&mut self.read_buf) {
+                Ok(read_bytes) => read_bytes,
+                Err(e) if !self.partial.is_empty() && is_closed(&e) => {
+                    return fail_no_response(format!(
+                        "Connection closed mid-record after {} bytes buffered: {e}",
+                        self.partial.len()
+                    ))
+                }
+                Err(e) => {
+                    return Err(anyhow::Error::new(e)
+                        .context("Could not read from TLS connection")
+                        .into())
+                }
+            };
+            if read_bytes == 0 {
+                if !self.partial.is_empty() {
+                    return fail_no_response(format!(
+                        "Connection closed mid-record after {} bytes buffered",
+                        self.partial.len()
+                    ));
+                }
+
+                tracing::trace!("NTS-KE connection closed");
                 return Ok(None);
             }
 
-            self.record_decoder.extend(buf);
+            let data = &self.read_buf[..read_bytes];
+            self.trace(Direction::Received, data);
+            self.record_decoder.extend(data.iter().copied());
+            self.partial.extend_from_slice(data);
+            self.partial.drain(..complete_records_len(&self.partial));
+            self.received += read_bytes;
+
+            // The server sends more than fits, read bigger chunks from now on
+            if read_bytes == self.read_buf.len() && self.read_buf.len() < Self::MAX_READ_LEN {
+                self.read_buf.resize(self.read_buf.len() * 2, 0);
+            }
         }
     }
 
@@ -120,50 +383,132 @@ impl NtsKeConnection {
         &mut self,
         request: impl IntoIterator<Item = NtsRecord>,
     ) -> TestResult<Response> {
+        let start = Instant::now();
         let mut buf = vec![];
         for rec in request {
+            tracing::trace!(record = ?rec, "sending NTS-KE record");
             rec.write(&mut buf).expect("Vec never runs out of space");
         }
+        self.trace(Direction::Sent, &buf);
         self.stream.write_all(&buf).context("Failed to write TLS")?;
 
+        let response = self.recv_response()?;
+        self.timings.exchange = Some(start.elapsed());
+
+        Ok(response)
+    }
+
+    /// Receive records until the server closes the connection, and parse them into a response
+    ///
+    /// Fails if the server sent no EndOfMessage before closing the connection, or sent more records after it.
+    pub fn recv_response(&mut self) -> TestResult<Response> {
         let mut records = vec![];
+        let mut end_of_message = None;
         loop {
-            let last = records.last();
             match self.recv_record() {
-                Ok(Some(rec)) => records.push(rec),
-                Ok(None) if last == Some(&NtsRecord::EndOfMessage) => break,
-                Ok(None) => {
-                    return fail(
-                        "NTS-KE closed connection without sending EndOfMessage",
-                        records,
-                    )
+                Ok(Some(rec)) => {
+                    if end_of_message.is_none() && rec == NtsRecord::EndOfMessage {
+                        end_of_message = Some(records.len());
+                    }
+                    records.push(rec);
                 }
-                Err(e) => Err(anyhow!(e).context("Could not read next record"))?,
+                Ok(None) => break,
+                Err(TestError::Error(e)) => {
+                    return Err(e.context("Could not read next record").into())
+                }
+                Err(e) => return Err(e),
             }
         }
 
-        let response = Response::try_from(records)?;
-        Ok(response)
+        let Some(end_of_message) = end_of_message else {
+            return fail(
+                "NTS-KE closed connection without sending EndOfMessage",
+                records,
+            );
+        };
+        let trailing = &records[end_of_message + 1..];
+        if !trailing.is_empty() {
+            return fail(
+                format!("Server sent records after EndOfMessage: {trailing:?}"),
+                records,
+            );
+        }
+
+        Response::try_from(records)
+    }
+
+    /// Perform a complete exchange with the server, returning the records exactly as they were received
+    ///
+    /// Unlike [`exchange`](Self::exchange) the response is not interpreted, and the critical bit of every record is
+    /// kept. Records are received until EndOfMessage, so this does not wait for the server to close the connection.
+    pub fn exchange_raw(
+        &mut self,
+        request: impl IntoIterator<Item = NtsRecord>,
+    ) -> TestResult<Vec<RawRecord>> {
+        let mut buf = vec![];
+        for rec in request {
+            tracing::trace!(record = ?rec, "sending NTS-KE record");
+            rec.write(&mut buf).expect("Vec never runs out of space");
+        }
+        self.trace(Direction::Sent, &buf);
+        self.stream.write_all(&buf).context("Failed to write TLS")?;
+
+        let mut pending = vec![];
+        let mut records = vec![];
+        loop {
+            while let Some((record, len)) = RawRecord::parse(&pending) {
+                pending.drain(..len);
+                let end = record.record_type == RawRecord::END_OF_MESSAGE;
+                records.push(record);
+                if end {
+                    return Ok(records);
+                }
+            }
+
+            let read_bytes = self
+                .stream
+                .read(&mut self.read_buf)
+                .context("Could not read from TLS connection")?;
+            if read_bytes == 0 {
+                return fail(
+                    format!(
+                        "NTS-KE closed connection without sending EndOfMessage, {} bytes of an incomplete record left",
+                        pending.len()
+                    ),
+                    records,
+                );
+            }
+
+            let data = &self.read_buf[..read_bytes];
+            self.trace(Direction::Received, data);
+            pending.extend_from_slice(data);
+            self.received += read_bytes;
+        }
     }
 
     /// Perform a complete request/response cycle with default data, extracting all data needed to contact the UDP side.
-    pub fn do_request(&mut self) -> TestResult<(Vec<NtsCookie>, SocketAddr, NtsKeys)> {
+    ///
+    /// Only `aead` is offered to the server, so the returned keys are always for that algorithm.
+    pub fn do_request(
+        &mut self,
+        aead: AeadAlgorithm,
+    ) -> TestResult<(Vec<NtsCookie>, SocketAddr, NtsKeys)> {
         let response = self.exchange([
             NtsRecord::NextProtocol {
                 protocol_ids: vec![ProtocolId::NtpV4 as u16],
             },
             NtsRecord::AeadAlgorithm {
                 critical: false,
-                algorithm_ids: vec![AeadAlgorithm::AeadAesSivCmac256 as u16],
+                algorithm_ids: vec![aead as u16],
             },
             NtsRecord::EndOfMessage,
         ])?;
 
-        let Some(&[aead]) = response.aead.as_deref() else {
+        let Some(&[negotiated]) = response.aead.as_deref() else {
             return fail("KE did not reply with exactly one AEAD", response);
         };
-        let aead = AeadAlgorithm::try_deserialize(aead).context("invalid AEAD")?;
-        if aead != AeadAlgorithm::AeadAesSivCmac256 {
+        let negotiated = AeadAlgorithm::try_deserialize(negotiated).context("invalid AEAD")?;
+        if negotiated != aead {
             return fail("KE replied with an aead we did not ask for", response);
         }
 
@@ -177,26 +522,141 @@ impl NtsKeConnection {
         }
 
         // TODO: Once ntp-proto updated rustls: Use AeadAlgorithm::extract_nts_keys directly
-        let c2s = extract_nts_key(&self.stream.conn, aead.c2s_context(ProtocolId::NtpV4))
-            .context("Could not extract session keys")?;
-        let s2c = extract_nts_key(&self.stream.conn, aead.s2c_context(ProtocolId::NtpV4))
-            .context("Could not extract session keys")?;
+        let c2s_context = aead.c2s_context(ProtocolId::NtpV4);
+        let s2c_context = aead.s2c_context(ProtocolId::NtpV4);
+        let keys = match aead {
+            AeadAlgorithm::AeadAesSivCmac256 => NtsKeys {
+                c2s: Box::new(AesSivCmac256::new(
+                    extract_nts_key(&self.stream.conn, c2s_context)
+                        .context("Could not extract session keys")?,
+                )),
+                s2c: Box::new(AesSivCmac256::new(
+                    extract_nts_key(&self.stream.conn, s2c_context)
+                        .context("Could not extract session keys")?,
+                )),
+            },
+            AeadAlgorithm::AeadAesSivCmac512 => NtsKeys {
+                c2s: Box::new(AesSivCmac512::new(
+                    extract_nts_key(&self.stream.conn, c2s_context)
+                        .context("Could not extract session keys")?,
+                )),
+                s2c: Box::new(AesSivCmac512::new(
+                    extract_nts_key(&self.stream.conn, s2c_context)
+                        .context("Could not extract session keys")?,
+                )),
+            },
+        };
 
-        let c2s = Box::new(AesSivCmac256::new(c2s));
-        let s2c = Box::new(AesSivCmac256::new(s2c));
+        let udp_host = resolve_udp_host(&response, &self.host, self.address_family)?;
 
-        let keys = NtsKeys { c2s, s2c };
+        Ok((response.cookies, udp_host, keys))
+    }
+}
 
-        let host = response.server.as_deref().unwrap_or(&self.host);
-        let port = response.port.unwrap_or(123);
+/// The time left until `deadline`, or `None` if it passed
+pub fn remaining(deadline: Instant) -> Option<Duration> {
+    deadline
+        .checked_duration_since(Instant::now())
+        .filter(|remaining| !remaining.is_zero())
+}
 
-        let udp_host = format!("{host}:{port}")
-            .to_socket_addrs()
-            .with_context(|| format!("Could not resolve {host}:{port}"))?
-            .next()
-            .with_context(|| format!("{host:?} did not resolve into any IPs"))?;
+/// The port NTP is served on if the key exchange did not negotiate another one
+pub const DEFAULT_NTP_PORT: u16 = 123;
 
-        Ok((response.cookies, udp_host, keys))
+/// The NTP server negotiated in `response`, see [RFC8915 section 4.1.7](https://datatracker.ietf.org/doc/html/rfc8915#section-4.1.7)
+///
+/// The Server and Port Negotiation records each override their part of the address independently. Without a Server
+/// record `default_host`, the host of the key exchange, is used, and without a Port record [`DEFAULT_NTP_PORT`].
+pub fn resolve_udp_host(
+    response: &Response,
+    default_host: &str,
+    address_family: AddressFamily,
+) -> TestResult<SocketAddr> {
+    let host = response.server.as_deref().unwrap_or(default_host);
+    let port = response.port.unwrap_or(DEFAULT_NTP_PORT);
+
+    // Resolving the tuple instead of a formatted string also handles IPv6 literals in the Server record
+    let udp_hosts = (host, port)
+        .to_socket_addrs()
+        .with_context(|| format!("Could not resolve {host}:{port}"))?;
+    let udp_host = address_family
+        .select(udp_hosts)
+        .with_context(|| format!("{host:?} did not resolve into any {address_family} addresses"))?;
+
+    Ok(udp_host)
+}
+
+fn client_config(
+    options: &KeOptions,
+    versions: &[&'static SupportedProtocolVersion],
+    alpn: &[Vec<u8>],
+) -> TestResult<ClientConfig> {
+    let builder = ClientConfig::builder_with_protocol_versions(versions)
+        .with_root_certificates(Arc::clone(&options.root_cert_store));
+    let mut config = match &options.client_cert {
+        Some(cert) => builder
+            .with_client_auth_cert(cert.chain.clone(), cert.key.clone_key())
+            .context("Invalid client certificate or key")?,
+        None => builder.with_no_client_auth(),
+    };
+
+    // Ensure we send exactly the requested alpn protocols
+    config.alpn_protocols = alpn.to_vec();
+
+    if options.insecure_skip_verify {
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(AcceptAnyCertificate));
+    }
+
+    Ok(config)
+}
+
+/// A certificate verifier for `--insecure-skip-verify` that accepts any certificate
+///
+/// Only the certificate chain is not verified, the server still has to prove it has the key of the certificate it
+/// presents.
+#[derive(Debug)]
+struct AcceptAnyCertificate;
+
+impl AcceptAnyCertificate {
+    fn algorithms() -> WebPkiSupportedAlgorithms {
+        rustls::crypto::ring::default_provider().signature_verification_algorithms
+    }
+}
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &Self::algorithms())
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &Self::algorithms())
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        Self::algorithms().supported_schemes()
     }
 }
 
@@ -214,10 +674,68 @@ fn extract_nts_key<T: Default + AsMut<[u8]>, ConnectionData>(
     Ok(key)
 }
 
+/// Wrap a function that opens its own connections to a NTS-KE server, turning it into a [`TestCase`].
+///
+/// Use this for tests that need control over how the connection is set up, otherwise prefer [`ke_test`].
+pub fn ke_server_test<F>(f: F) -> Box<dyn TestCase + UnwindSafe + Send>
+where
+    F: Fn(&NtsServer) -> TestResult + UnwindSafe + Send + 'static,
+{
+    struct KeServerTest<F> {
+        f: F,
+    }
+
+    impl<F> TestCase for KeServerTest<F>
+    where
+        F: Fn(&NtsServer) -> TestResult,
+    {
+        fn name(&self) -> &'static str {
+            std::any::type_name::<F>()
+        }
+
+        fn category(&self) -> Category {
+            Category::NtsKe
+        }
+
+        fn run(&self, conf: &TestConfig) -> TestResult {
+            (self.f)(conf.nts_server()?)
+        }
+    }
+
+    Box::new(KeServerTest { f })
+}
+
+/// Whether `error` means the server closed or reset the connection, as opposed to e.g. a timeout
+fn is_closed(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        ErrorKind::UnexpectedEof
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe
+    )
+}
+
+/// The length of the complete records at the start of `bytes`, according to the body lengths in their headers
+///
+/// The decoder does not report how much of its buffer it consumed, so the framing is followed separately.
+fn complete_records_len(bytes: &[u8]) -> usize {
+    let mut len = 0;
+    while let Some(header) = bytes.get(len..len + 4) {
+        let record_len = 4 + u16::from_be_bytes([header[2], header[3]]) as usize;
+        if bytes.len() < len + record_len {
+            break;
+        }
+        len += record_len;
+    }
+
+    len
+}
+
 /// Wrap a function taking a fresh connection to a NTS-KE server, turning it into a [`TestCase`].
-pub fn ke_test<F>(f: F) -> Box<dyn TestCase + UnwindSafe>
+pub fn ke_test<F>(f: F) -> Box<dyn TestCase + UnwindSafe + Send>
 where
-    F: Fn(&mut NtsKeConnection) -> TestResult + UnwindSafe + 'static,
+    F: Fn(&mut NtsKeConnection) -> TestResult + UnwindSafe + Send + 'static,
 {
     struct KeTest<F> {
         f: F,
@@ -231,6 +749,10 @@ where
             std::any::type_name::<F>()
         }
 
+        fn category(&self) -> Category {
+            Category::NtsKe
+        }
+
         fn run(&self, conf: &TestConfig) -> TestResult {
             let mut conn = conf.ke()?;
             (self.f)(&mut conn)
@@ -240,6 +762,45 @@ where
     Box::new(KeTest { f })
 }
 
+/// Wrap a function taking a fresh NTS-KE session, turning it into a [`TestCase`].
+///
+/// The wrapper runs [`do_request`](NtsKeConnection::do_request) on a new connection, then hands the function the
+/// connection, a UDP connection to the negotiated NTP server, and the cookies and keys received. This allows tests to
+/// check the key exchange and the NTS protected NTP traffic of a single session.
+pub fn ke_udp_test<F>(f: F) -> Box<dyn TestCase + UnwindSafe + Send>
+where
+    F: Fn(&mut NtsKeConnection, &mut UdpConnection, Vec<NtsCookie>, &NtsKeys) -> TestResult
+        + UnwindSafe
+        + Send
+        + 'static,
+{
+    struct KeUdpTest<F> {
+        f: F,
+    }
+
+    impl<F> TestCase for KeUdpTest<F>
+    where
+        F: Fn(&mut NtsKeConnection, &mut UdpConnection, Vec<NtsCookie>, &NtsKeys) -> TestResult,
+    {
+        fn name(&self) -> &'static str {
+            std::any::type_name::<F>()
+        }
+
+        fn category(&self) -> Category {
+            Category::NtsKe
+        }
+
+        fn run(&self, conf: &TestConfig) -> TestResult {
+            let mut ke = conf.ke()?;
+            let (cookies, udp_host, keys) = ke.do_request(AeadAlgorithm::AeadAesSivCmac256)?;
+            let mut udp = conf.udp_to(udp_host)?;
+            (self.f)(&mut ke, &mut udp, cookies, &keys)
+        }
+    }
+
+    Box::new(KeUdpTest { f })
+}
+
 /// Convenience wrapper around all fields needed for a NTS-KE request
 #[derive(Clone, Eq, PartialEq)]
 pub struct Request {
@@ -248,6 +809,8 @@ pub struct Request {
     pub critical_aead: bool,
     pub server: Option<String>,
     pub port: Option<u16>,
+    /// Shuffle the records (except for the final EndOfMessage) using this seed
+    pub shuffle_seed: Option<u64>,
 }
 
 impl Default for Request {
@@ -258,6 +821,7 @@ impl Default for Request {
             critical_aead: false,
             server: None,
             port: None,
+            shuffle_seed: None,
         }
     }
 }
@@ -289,7 +853,11 @@ impl IntoIterator for Request {
             });
         }
 
-        // TODO shuffle records here
+        // The order of records is not significant, a seeded shuffle keeps failures reproducible. Unlike `StdRng`,
+        // ChaCha8 produces the same sequence in every version of rand.
+        if let Some(seed) = self.shuffle_seed {
+            recs.shuffle(&mut ChaCha8Rng::seed_from_u64(seed));
+        }
 
         recs.push(NtsRecord::EndOfMessage);
 
@@ -297,12 +865,131 @@ impl IntoIterator for Request {
     }
 }
 
+/// The code of a NTS-KE Error record
+///
+/// The registered codes are listed in [RFC8915 section 7.6](https://datatracker.ietf.org/doc/html/rfc8915#section-7.6),
+/// the debug output shows the numeric code along with its name.
+/// Codes that are not registered are kept as [`Unassigned`](NtsKeError::Unassigned).
+#[derive(Clone, Copy, Eq, PartialEq, Hash)]
+pub enum NtsKeError {
+    UnrecognizedCriticalRecord,
+    BadRequest,
+    InternalServerError,
+    Unassigned(u16),
+}
+
+impl From<u16> for NtsKeError {
+    fn from(code: u16) -> Self {
+        match code {
+            0 => Self::UnrecognizedCriticalRecord,
+            1 => Self::BadRequest,
+            2 => Self::InternalServerError,
+            other => Self::Unassigned(other),
+        }
+    }
+}
+
+impl From<NtsKeError> for u16 {
+    fn from(code: NtsKeError) -> Self {
+        match code {
+            NtsKeError::UnrecognizedCriticalRecord => 0,
+            NtsKeError::BadRequest => 1,
+            NtsKeError::InternalServerError => 2,
+            NtsKeError::Unassigned(other) => other,
+        }
+    }
+}
+
+impl Display for NtsKeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnrecognizedCriticalRecord => f.write_str("Unrecognized Critical Record"),
+            Self::BadRequest => f.write_str("Bad Request"),
+            Self::InternalServerError => f.write_str("Internal Server Error"),
+            Self::Unassigned(code) => write!(f, "unassigned error {code}"),
+        }
+    }
+}
+
+impl Debug for NtsKeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({self})", u16::from(*self))
+    }
+}
+
+/// The code of a NTS-KE Warning record
+///
+/// [RFC8915 section 7.7](https://datatracker.ietf.org/doc/html/rfc8915#section-7.7) does not register any warning
+/// codes yet, so every code is shown as unassigned in the debug output. Clients should ignore warnings.
+#[derive(Clone, Copy, Eq, PartialEq, Hash)]
+pub struct NtsKeWarning(pub u16);
+
+impl Debug for NtsKeWarning {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (unassigned)", self.0)
+    }
+}
+
+/// A NTS-KE record as it was sent on the wire, see [RFC8915 section 4](https://datatracker.ietf.org/doc/html/rfc8915#section-4)
+///
+/// [`NtsRecord`] only keeps the critical bit of the record types for which it is negotiable, this keeps it for all.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RawRecord {
+    pub critical: bool,
+    /// The record type without the critical bit
+    pub record_type: u16,
+    pub body: Vec<u8>,
+}
+
+impl RawRecord {
+    pub const END_OF_MESSAGE: u16 = 0;
+    pub const NEXT_PROTOCOL: u16 = 1;
+    pub const ERROR: u16 = 2;
+    pub const WARNING: u16 = 3;
+    pub const AEAD_ALGORITHM: u16 = 4;
+    pub const NEW_COOKIE: u16 = 5;
+    pub const SERVER: u16 = 6;
+    pub const PORT: u16 = 7;
+
+    const CRITICAL_BIT: u16 = 0x8000;
+
+    /// Split the first record off `buf`, returning it and its length, or `None` if `buf` holds no complete record
+    fn parse(buf: &[u8]) -> Option<(Self, usize)> {
+        let header = buf.get(..4)?;
+        let record_type = u16::from_be_bytes([header[0], header[1]]);
+        let len = 4 + u16::from_be_bytes([header[2], header[3]]) as usize;
+        let body = buf.get(4..len)?;
+
+        let record = Self {
+            critical: record_type & Self::CRITICAL_BIT != 0,
+            record_type: record_type & !Self::CRITICAL_BIT,
+            body: body.to_vec(),
+        };
+        Some((record, len))
+    }
+
+    /// The name of the record type as used in RFC8915
+    pub fn type_name(&self) -> &'static str {
+        match self.record_type {
+            Self::END_OF_MESSAGE => "End of Message",
+            Self::NEXT_PROTOCOL => "NTS Next Protocol Negotiation",
+            Self::ERROR => "Error",
+            Self::WARNING => "Warning",
+            Self::AEAD_ALGORITHM => "AEAD Algorithm Negotiation",
+            Self::NEW_COOKIE => "New Cookie for NTPv4",
+            Self::SERVER => "NTPv4 Server Negotiation",
+            Self::PORT => "NTPv4 Port Negotiation",
+            _ => "unknown",
+        }
+    }
+}
+
 /// Convenience wrapper around all fields that can be contained in a NTS-KE response.
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Response {
     pub next_protocol: Option<Vec<u16>>,
-    pub errors: Vec<u16>,
-    pub warnings: Vec<u16>,
+    pub errors: Vec<NtsKeError>,
+    pub warnings: Vec<NtsKeWarning>,
     pub aead: Option<Vec<u16>>,
     pub cookies: Vec<NtsCookie>,
     pub server: Option<String>,
@@ -340,8 +1027,8 @@ impl TryFrom<Vec<NtsRecord>> for Response {
                         );
                     }
                 }
-                NtsRecord::Error { errorcode } => errors.push(errorcode),
-                NtsRecord::Warning { warningcode } => warnings.push(warningcode),
+                NtsRecord::Error { errorcode } => errors.push(NtsKeError::from(errorcode)),
+                NtsRecord::Warning { warningcode } => warnings.push(NtsKeWarning(warningcode)),
                 NtsRecord::AeadAlgorithm {
                     critical: _,
                     algorithm_ids,
@@ -393,3 +1080,338 @@ impl TryFrom<Vec<NtsRecord>> for Response {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{MockTlsServer, MockTlsStream};
+    use crate::ClientCert;
+    use rustls::DEFAULT_VERSIONS;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use std::path::PathBuf;
+
+    fn response(records: impl IntoIterator<Item = NtsRecord>) -> Response {
+        let mut records: Vec<_> = records.into_iter().collect();
+        records.push(NtsRecord::EndOfMessage);
+        Response::try_from(records).unwrap()
+    }
+
+    fn server(name: &str) -> NtsRecord {
+        NtsRecord::Server {
+            critical: false,
+            name: name.into(),
+        }
+    }
+
+    fn port(port: u16) -> NtsRecord {
+        NtsRecord::Port {
+            critical: false,
+            port,
+        }
+    }
+
+    #[test]
+    fn port_without_server_is_parsed() {
+        let res = response([port(4123)]);
+        assert_eq!(res.server, None);
+        assert_eq!(res.port, Some(4123));
+    }
+
+    #[test]
+    fn server_without_port_is_parsed() {
+        let res = response([server("127.0.0.2")]);
+        assert_eq!(res.server.as_deref(), Some("127.0.0.2"));
+        assert_eq!(res.port, None);
+    }
+
+    #[test]
+    fn duplicate_server_or_port_fails() {
+        for records in [
+            vec![server("a"), server("b"), NtsRecord::EndOfMessage],
+            vec![port(1), port(2), NtsRecord::EndOfMessage],
+        ] {
+            assert!(matches!(
+                Response::try_from(records),
+                Err(TestError::Fail(..))
+            ));
+        }
+    }
+
+    #[test]
+    fn udp_host_defaults_to_ke_host_and_port_123() {
+        let res = response([]);
+        let udp_host = resolve_udp_host(&res, "127.0.0.1", AddressFamily::Any).unwrap();
+        assert_eq!(udp_host, (Ipv4Addr::LOCALHOST, DEFAULT_NTP_PORT).into());
+    }
+
+    #[test]
+    fn udp_host_overrides_server_and_port_independently() {
+        let res = response([port(4123)]);
+        let udp_host = resolve_udp_host(&res, "127.0.0.1", AddressFamily::Any).unwrap();
+        assert_eq!(udp_host, (Ipv4Addr::LOCALHOST, 4123).into());
+
+        let res = response([server("127.0.0.2")]);
+        let udp_host = resolve_udp_host(&res, "127.0.0.1", AddressFamily::Any).unwrap();
+        assert_eq!(
+            udp_host,
+            (Ipv4Addr::new(127, 0, 0, 2), DEFAULT_NTP_PORT).into()
+        );
+    }
+
+    #[test]
+    fn udp_host_accepts_ipv6_literal_server() {
+        let res = response([server("::1"), port(4123)]);
+        let udp_host = resolve_udp_host(&res, "127.0.0.1", AddressFamily::V6).unwrap();
+        assert_eq!(udp_host, (Ipv6Addr::LOCALHOST, 4123).into());
+    }
+
+    #[test]
+    fn udp_host_of_wrong_family_is_an_error() {
+        let res = response([server("::1")]);
+        let result = resolve_udp_host(&res, "127.0.0.1", AddressFamily::V4);
+        assert!(matches!(result, Err(TestError::Error(_))));
+    }
+
+    /// A connection to a [`MockTlsServer`] sending `response` with `write`, which also closes the connection
+    fn connect_to(
+        write: impl FnOnce(&mut MockTlsStream, &[u8]) -> io::Result<()> + Send + 'static,
+        response: Vec<NtsRecord>,
+    ) -> NtsKeConnection {
+        let server = MockTlsServer::start(move |stream| {
+            let mut buf = vec![];
+            for record in response {
+                record.write(&mut buf)?;
+            }
+            write(stream, &buf)
+        })
+        .unwrap();
+        let options = server.ke_options(Duration::from_secs(1));
+        NtsKeConnection::new("localhost", server.port(), &options).unwrap()
+    }
+
+    fn close(stream: &mut MockTlsStream) -> io::Result<()> {
+        stream.conn.send_close_notify();
+        stream.flush()
+    }
+
+    fn cookie(len: usize) -> NtsRecord {
+        NtsRecord::NewCookie {
+            cookie_data: (0..len).map(|i| i as u8).collect(),
+        }
+    }
+
+    #[test]
+    fn record_split_into_tiny_reads() {
+        let response = vec![cookie(64), cookie(64), NtsRecord::EndOfMessage];
+        let mut conn = connect_to(
+            |stream, bytes| {
+                // Every flush ends up in its own TLS record
+                for byte in bytes {
+                    stream.write_all(&[*byte])?;
+                    stream.flush()?;
+                }
+                close(stream)
+            },
+            response.clone(),
+        );
+
+        for expected in response {
+            assert_eq!(conn.recv_record().unwrap(), Some(expected));
+        }
+        assert_eq!(conn.recv_record().unwrap(), None);
+    }
+
+    #[test]
+    fn record_larger_than_read_buffer() {
+        let len = 3 * NtsKeConnection::INITIAL_READ_LEN;
+        let mut conn = connect_to(
+            |stream, bytes| {
+                stream.write_all(bytes)?;
+                close(stream)
+            },
+            vec![cookie(len), NtsRecord::EndOfMessage],
+        );
+
+        let res = conn.recv_response().unwrap();
+        assert_eq!(res.cookies.len(), 1);
+        assert_eq!(res.cookies[0].len(), len);
+    }
+
+    /// Send `records`, but close the connection `missing` bytes before their end
+    fn truncated(records: Vec<NtsRecord>, missing: usize, close_notify: bool) -> NtsKeConnection {
+        connect_to(
+            move |stream, bytes| {
+                stream.write_all(&bytes[..bytes.len() - missing])?;
+                if close_notify {
+                    close(stream)
+                } else {
+                    stream.flush()
+                }
+            },
+            records,
+        )
+    }
+
+    fn assert_closed_mid_record(result: TestResult<Response>, buffered: usize) {
+        match result {
+            Err(TestError::Fail(msg, None)) => assert!(
+                msg.starts_with(&format!(
+                    "Connection closed mid-record after {buffered} bytes buffered"
+                )),
+                "{msg}"
+            ),
+            other => panic!("Expected a failure, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn close_mid_record_fails() {
+        // The first cookie is complete, of the second one 4 header and 60 body bytes arrive
+        let records = vec![cookie(64), cookie(64), NtsRecord::EndOfMessage];
+        let mut conn = truncated(records.clone(), 8, true);
+        assert_closed_mid_record(conn.recv_response(), 64);
+
+        let mut conn = truncated(records, 8, false);
+        assert_closed_mid_record(conn.recv_response(), 64);
+    }
+
+    #[test]
+    fn close_mid_header_fails() {
+        let mut conn = truncated(vec![cookie(64), NtsRecord::EndOfMessage], 2, true);
+        assert_closed_mid_record(conn.recv_response(), 2);
+    }
+
+    #[test]
+    fn record_after_end_of_message_fails() {
+        let records = vec![cookie(64), NtsRecord::EndOfMessage, cookie(32)];
+        let mut conn = connect_to(
+            |stream, bytes| {
+                stream.write_all(bytes)?;
+                close(stream)
+            },
+            records,
+        );
+
+        match conn.recv_response() {
+            Err(TestError::Fail(msg, Some(_))) => assert!(
+                msg.starts_with("Server sent records after EndOfMessage: [NewCookie"),
+                "{msg}"
+            ),
+            other => panic!("Expected a failure, got {other:?}"),
+        }
+    }
+
+    /// Complete a handshake with a self-signed server, which is not in the root store of the client
+    fn handshake_self_signed(insecure_skip_verify: bool) -> TestResult<Option<ProtocolVersion>> {
+        let server = MockTlsServer::start(|stream| {
+            while stream.conn.is_handshaking() {
+                stream.conn.complete_io(&mut stream.sock)?;
+            }
+            close(stream)
+        })
+        .unwrap();
+        let options = KeOptions {
+            root_cert_store: Arc::new(RootCertStore::empty()),
+            insecure_skip_verify,
+            ..server.ke_options(Duration::from_secs(1))
+        };
+
+        NtsKeConnection::new("localhost", server.port(), &options)?.handshake()
+    }
+
+    #[test]
+    fn self_signed_server_requires_insecure_skip_verify() {
+        match handshake_self_signed(false) {
+            Err(TestError::Error(e)) => {
+                let message = format!("{e:#}");
+                assert!(message.contains("UnknownIssuer"), "{message}");
+            }
+            other => panic!("Expected the handshake to fail, got {other:?}"),
+        }
+        assert!(handshake_self_signed(true).unwrap().is_some());
+    }
+
+    /// The server name a client connecting to 127.0.0.1 with `sni` presents
+    fn presented_server_name(sni: Option<&str>) -> Option<String> {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let server = MockTlsServer::start_for(&["127.0.0.1", "ntp.example"], move |stream| {
+            while stream.conn.is_handshaking() {
+                stream.conn.complete_io(&mut stream.sock)?;
+            }
+            sender
+                .send(stream.conn.server_name().map(str::to_string))
+                .unwrap();
+            close(stream)
+        })
+        .unwrap();
+
+        let mut options = server.ke_options(Duration::from_secs(1));
+        options.sni = sni.map(str::to_string);
+        let mut conn = NtsKeConnection::new("127.0.0.1", server.port(), &options).unwrap();
+        conn.handshake().unwrap();
+        receiver.recv_timeout(Duration::from_secs(1)).unwrap()
+    }
+
+    #[test]
+    fn sni_override_is_presented() {
+        assert_eq!(
+            presented_server_name(Some("ntp.example")).as_deref(),
+            Some("ntp.example")
+        );
+    }
+
+    #[test]
+    fn no_sni_for_ip_without_override() {
+        assert_eq!(presented_server_name(None), None);
+    }
+
+    /// Write a self-signed client certificate and its key as PEM files into the temporary directory
+    fn client_cert_files(name: &str) -> (PathBuf, PathBuf) {
+        let cert = rcgen::generate_simple_self_signed(vec!["client".to_string()]).unwrap();
+        let prefix = format!("network-time-pester-{}-{name}", std::process::id());
+        let cert_file = std::env::temp_dir().join(format!("{prefix}.crt"));
+        let key_file = std::env::temp_dir().join(format!("{prefix}.key"));
+        std::fs::write(&cert_file, cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(&key_file, cert.serialize_private_key_pem()).unwrap();
+        (cert_file, key_file)
+    }
+
+    fn options(client_cert: Option<ClientCert>) -> KeOptions {
+        KeOptions {
+            root_cert_store: Arc::new(RootCertStore::empty()),
+            timeout: Duration::from_secs(1),
+            address_family: AddressFamily::Any,
+            client_cert: client_cert.map(Arc::new),
+            proxy: None,
+            sni: None,
+            insecure_skip_verify: false,
+        }
+    }
+
+    #[test]
+    fn client_config_presents_client_cert() {
+        let (cert_file, key_file) = client_cert_files("present");
+        let client_cert = ClientCert::load(&cert_file, &key_file).unwrap();
+        std::fs::remove_file(cert_file).unwrap();
+        std::fs::remove_file(key_file).unwrap();
+
+        let with_cert = client_config(&options(Some(client_cert)), DEFAULT_VERSIONS, &[]);
+        assert!(with_cert.unwrap().client_auth_cert_resolver.has_certs());
+        let without_cert = client_config(&options(None), DEFAULT_VERSIONS, &[]);
+        assert!(!without_cert.unwrap().client_auth_cert_resolver.has_certs());
+    }
+
+    #[test]
+    fn missing_client_key_is_reported() {
+        let (cert_file, key_file) = client_cert_files("missing-key");
+        std::fs::remove_file(&key_file).unwrap();
+
+        let result = ClientCert::load(&cert_file, &key_file);
+        std::fs::remove_file(cert_file).unwrap();
+        let message = format!("{:#}", result.err().unwrap());
+        assert!(
+            message.starts_with("Could not open client key"),
+            "{message}"
+        );
+    }
+}
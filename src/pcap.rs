@@ -0,0 +1,155 @@
+//! Capture UDP exchanges to a pcap file, for inspection with tools like Wireshark
+//!
+//! The capture uses the raw IP link type, so the IP and UDP headers of every packet are synthesized from the socket
+//! addresses of the connection.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Packets start directly with an IPv4 or IPv6 header
+const LINKTYPE_RAW: u32 = 101;
+const IPPROTO_UDP: u8 = 17;
+const SNAPLEN: u32 = 65535;
+
+/// Writes UDP packets into a pcap file
+///
+/// The writer can be shared between connections, records are written atomically.
+#[derive(Debug)]
+pub struct PcapWriter {
+    out: Mutex<BufWriter<File>>,
+}
+
+impl PcapWriter {
+    /// Create the file at `path` and write the pcap header
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+
+        out.write_all(&0xa1b2c3d4u32.to_le_bytes())?;
+        out.write_all(&2u16.to_le_bytes())?;
+        out.write_all(&4u16.to_le_bytes())?;
+        out.write_all(&0i32.to_le_bytes())?; // timezone offset
+        out.write_all(&0u32.to_le_bytes())?; // timestamp accuracy
+        out.write_all(&SNAPLEN.to_le_bytes())?;
+        out.write_all(&LINKTYPE_RAW.to_le_bytes())?;
+
+        Ok(Self {
+            out: Mutex::new(out),
+        })
+    }
+
+    /// Record a UDP packet with `payload` sent from `src` to `dst` at `time`
+    pub fn write_udp(
+        &self,
+        src: SocketAddr,
+        dst: SocketAddr,
+        payload: &[u8],
+        time: SystemTime,
+    ) -> io::Result<()> {
+        let packet = ip_packet(src, dst, payload)?;
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+
+        let mut out = self.out.lock().expect("No poisoned pcap writer");
+        out.write_all(&(since_epoch.as_secs() as u32).to_le_bytes())?;
+        out.write_all(&since_epoch.subsec_micros().to_le_bytes())?;
+        out.write_all(&(packet.len() as u32).to_le_bytes())?;
+        out.write_all(&(packet.len() as u32).to_le_bytes())?;
+        out.write_all(&packet)
+    }
+}
+
+impl Drop for PcapWriter {
+    fn drop(&mut self) {
+        if let Ok(out) = self.out.get_mut() {
+            let _ = out.flush();
+        }
+    }
+}
+
+/// Wrap `payload` in UDP and IP headers
+pub(crate) fn ip_packet(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> io::Result<Vec<u8>> {
+    let udp_len = u16::try_from(8 + payload.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "UDP payload too large"))?;
+
+    let mut udp = Vec::with_capacity(udp_len as usize);
+    udp.extend_from_slice(&src.port().to_be_bytes());
+    udp.extend_from_slice(&dst.port().to_be_bytes());
+    udp.extend_from_slice(&udp_len.to_be_bytes());
+    udp.extend_from_slice(&[0, 0]); // checksum, filled in below
+    udp.extend_from_slice(payload);
+
+    let mut packet = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            let mut header = Vec::with_capacity(20);
+            header.push(0x45); // version 4, header length 20 bytes
+            header.push(0);
+            header.extend_from_slice(&(20 + udp_len).to_be_bytes());
+            header.extend_from_slice(&[0, 0, 0x40, 0]); // identification, don't fragment
+            header.push(64); // TTL
+            header.push(IPPROTO_UDP);
+            header.extend_from_slice(&[0, 0]); // checksum, filled in below
+            header.extend_from_slice(&src_ip.octets());
+            header.extend_from_slice(&dst_ip.octets());
+            let header_checksum = checksum(&[&header]);
+            header[10..12].copy_from_slice(&header_checksum.to_be_bytes());
+
+            let pseudo_header = [&[0, IPPROTO_UDP][..], &udp_len.to_be_bytes()];
+            let udp_checksum = checksum(&[&header[12..20], &pseudo_header.concat(), &udp]);
+            udp[6..8].copy_from_slice(&udp_checksum.to_be_bytes());
+
+            header
+        }
+        (IpAddr::V6(src_ip), IpAddr::V6(dst_ip)) => {
+            let mut header = Vec::with_capacity(40);
+            header.extend_from_slice(&[0x60, 0, 0, 0]); // version 6, no traffic class or flow label
+            header.extend_from_slice(&udp_len.to_be_bytes());
+            header.push(IPPROTO_UDP);
+            header.push(64); // hop limit
+            header.extend_from_slice(&src_ip.octets());
+            header.extend_from_slice(&dst_ip.octets());
+
+            let pseudo_header = [&(udp_len as u32).to_be_bytes()[..], &[0, 0, 0, IPPROTO_UDP]];
+            let udp_checksum = checksum(&[&header[8..40], &pseudo_header.concat(), &udp]);
+            udp[6..8].copy_from_slice(&udp_checksum.to_be_bytes());
+
+            header
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Can not capture packet from {src} to {dst} with mixed address families"),
+            ))
+        }
+    };
+
+    packet.extend_from_slice(&udp);
+    Ok(packet)
+}
+
+/// The internet checksum over the concatenation of `parts`, each part has to be of even length except for the last
+fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    for part in parts {
+        for word in part.chunks(2) {
+            let word = match word {
+                [high, low] => u16::from_be_bytes([*high, *low]),
+                [high] => u16::from_be_bytes([*high, 0]),
+                _ => unreachable!("chunks are never empty"),
+            };
+            sum += word as u32;
+        }
+    }
+
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    match !(sum as u16) {
+        // A zero UDP checksum means no checksum, the equivalent all ones is sent instead
+        0 => 0xffff,
+        checksum => checksum,
+    }
+}
@@ -0,0 +1,38 @@
+//! Sending UDP datagrams with a forged source address over a raw IPv4 socket
+//!
+//! Opening a raw socket needs `CAP_NET_RAW` or root, so this is only used by the tests enabled with `--allow-raw`. The
+//! headers are built as expected by Linux, other systems may want some fields in host byte order.
+
+use crate::pcap;
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::io;
+use std::net::SocketAddrV4;
+
+/// The `IPPROTO_RAW` protocol, the packets sent include their own IP header
+const IPPROTO_RAW: i32 = 255;
+
+/// A raw IPv4 socket to send UDP datagrams from any source address
+pub struct RawSender {
+    socket: Socket,
+}
+
+impl RawSender {
+    /// Open the raw socket, failing with [`io::ErrorKind::PermissionDenied`] without the privileges to do so
+    pub fn new() -> io::Result<Self> {
+        let socket = Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::from(IPPROTO_RAW)))?;
+        Ok(Self { socket })
+    }
+
+    /// Send `payload` as a UDP datagram from `from` to `to`
+    pub fn send_spoofed(
+        &self,
+        from: SocketAddrV4,
+        to: SocketAddrV4,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let packet = pcap::ip_packet(from.into(), to.into(), payload)?;
+        self.socket.send_to(&packet, &SockAddr::from(to))?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,948 @@
+//! Reporting of test results in different output formats
+//!
+//! Every format implements the [`Reporter`] trait, which gets passed the outcome of each test as it finishes.
+
+use crate::{Category, TestError, TestResult};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::io::{self, Write};
+use std::time::Duration;
+
+/// A sink for test results
+///
+/// For every tested server the reporter sees a call to [`start_suite`](Reporter::start_suite), followed by one
+/// [`report`](Reporter::report) per test and a [`finish_suite`](Reporter::finish_suite).
+pub trait Reporter {
+    /// Called before the tests against `server` start
+    fn start_suite(&mut self, server: &str) -> io::Result<()>;
+
+    /// Record the result of a single test, with the latency measured while it ran (if any)
+    fn report(
+        &mut self,
+        test: &TestInfo,
+        result: &TestResult,
+        latency: Option<Latency>,
+    ) -> io::Result<()>;
+
+    /// Called after all tests against the current server were reported
+    fn finish_suite(&mut self) -> io::Result<()>;
+
+    /// Called once after all servers were tested
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+/// What reporters get to know about a test, besides its result
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct TestInfo {
+    pub name: &'static str,
+    pub category: Category,
+    /// One line summary of what the test checks
+    pub description: Option<&'static str>,
+    /// Link to the section of the specification the test checks
+    pub rfc: Option<&'static str>,
+}
+
+/// Count of the test outcomes
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct Summary {
+    pub passed: usize,
+    pub failed: usize,
+    pub errored: usize,
+    pub skipped: usize,
+}
+
+impl Summary {
+    /// Count one more result
+    pub fn add(&mut self, result: &TestResult) {
+        match result {
+            Ok(()) => self.passed += 1,
+            Err(TestError::Fail(_, _)) => self.failed += 1,
+            Err(TestError::Error(_)) => self.errored += 1,
+            Err(TestError::Skipped) => self.skipped += 1,
+        }
+    }
+
+    /// Total number of tests counted
+    pub fn total(&self) -> usize {
+        self.passed + self.failed + self.errored + self.skipped
+    }
+
+    /// Combine the counts of two summaries
+    pub fn merge(&mut self, other: &Summary) {
+        self.passed += other.passed;
+        self.failed += other.failed;
+        self.errored += other.errored;
+        self.skipped += other.skipped;
+    }
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let Summary {
+            passed,
+            failed,
+            errored,
+            skipped,
+        } = self;
+
+        write!(
+            f,
+            "✅ Passed: {passed}\n❌ Failed: {failed}\n❓ Errored: {errored}\n⏩ Skipped: {skipped}"
+        )
+    }
+}
+
+/// Round trip time statistics of the requests made during a test
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Latency {
+    pub min_ms: f64,
+    pub median_ms: f64,
+}
+
+impl Latency {
+    /// Compute the statistics over the given samples, `None` if there are none
+    pub fn from_samples(samples: &[Duration]) -> Option<Self> {
+        let mut samples = samples.to_vec();
+        samples.sort();
+
+        let min = samples.first()?;
+        let median = samples[samples.len() / 2];
+
+        Some(Self {
+            min_ms: min.as_secs_f64() * 1000.0,
+            median_ms: median.as_secs_f64() * 1000.0,
+        })
+    }
+}
+
+impl Display for Latency {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "RTT min {:.2}ms, median {:.2}ms",
+            self.min_ms, self.median_ms
+        )
+    }
+}
+
+/// The outcome of a test as reported to the outside world
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Pass,
+    Fail,
+    Error,
+    Skipped,
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Status::Pass => f.write_str("pass"),
+            Status::Fail => f.write_str("fail"),
+            Status::Error => f.write_str("error"),
+            Status::Skipped => f.write_str("skipped"),
+        }
+    }
+}
+
+/// A serializable report of a single test result
+///
+/// This decouples the machine-readable output formats from the internal [`TestError`] and [`Response`](crate::Response)
+/// types.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TestReport {
+    pub server: String,
+    pub name: String,
+    pub category: Category,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rfc: Option<String>,
+    pub status: Status,
+    pub message: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency: Option<Latency>,
+}
+
+impl TestReport {
+    pub fn new(
+        server: &str,
+        test: &TestInfo,
+        result: &TestResult,
+        latency: Option<Latency>,
+    ) -> Self {
+        let (status, message, response) = match result {
+            Ok(()) => (Status::Pass, None, None),
+            Err(TestError::Fail(msg, r)) => (
+                Status::Fail,
+                Some(msg.clone()),
+                r.as_ref().map(|r| format!("{r:?}")),
+            ),
+            Err(TestError::Error(e)) => (Status::Error, Some(format!("{e:#}")), None),
+            Err(TestError::Skipped) => (Status::Skipped, None, None),
+        };
+
+        Self {
+            server: server.to_string(),
+            name: test.name.to_string(),
+            category: test.category,
+            description: test.description.map(str::to_string),
+            rfc: test.rfc.map(str::to_string),
+            status,
+            message,
+            response,
+            latency,
+        }
+    }
+}
+
+/// Human-readable output with one line per test, followed by a summary per server
+///
+/// Consecutive tests of the same category are grouped under a common heading.
+pub struct ConsoleReporter<W> {
+    out: W,
+    category: Option<Category>,
+    summary: Summary,
+    total: Summary,
+    suites: usize,
+}
+
+impl<W: Write> ConsoleReporter<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            category: None,
+            summary: Summary::default(),
+            total: Summary::default(),
+            suites: 0,
+        }
+    }
+}
+
+impl<W: Write> Reporter for ConsoleReporter<W> {
+    fn start_suite(&mut self, server: &str) -> io::Result<()> {
+        if self.suites > 0 {
+            writeln!(self.out)?;
+        }
+        self.suites += 1;
+        self.category = None;
+        self.summary = Summary::default();
+
+        writeln!(self.out, "Testing {server}")
+    }
+
+    fn report(
+        &mut self,
+        test: &TestInfo,
+        result: &TestResult,
+        latency: Option<Latency>,
+    ) -> io::Result<()> {
+        self.summary.add(result);
+
+        if self.category.replace(test.category) != Some(test.category) {
+            writeln!(self.out, "[{}]", test.category)?;
+        }
+
+        let name = test.name;
+        let name = match latency {
+            Some(latency) => format!("{name} ({latency})"),
+            None => name.to_string(),
+        };
+
+        match result {
+            Ok(()) => writeln!(self.out, "✅ {name}"),
+            Err(TestError::Fail(msg, None)) => writeln!(self.out, "❌ {name}\n ↳ {msg}"),
+            Err(TestError::Fail(msg, Some(r))) => {
+                writeln!(self.out, "❌ {name}\n ↳ {msg}\n ↳ {r:#?}")
+            }
+            Err(TestError::Skipped) => writeln!(self.out, "⏩ {name}"),
+            Err(TestError::Error(e)) => writeln!(self.out, "❓ {name}:\n ↳ {e:#}"),
+        }
+    }
+
+    fn finish_suite(&mut self) -> io::Result<()> {
+        self.total.merge(&self.summary);
+
+        writeln!(self.out, "\n{}", self.summary)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        if self.suites > 1 {
+            writeln!(
+                self.out,
+                "\nTotal over {} runs:\n{}",
+                self.suites, self.total
+            )?;
+        }
+
+        self.out.flush()
+    }
+}
+
+/// JUnit XML output, as understood by most CI systems
+///
+/// Every server is reported as a separate test suite. The XML is written when [`finish`](Reporter::finish) is called,
+/// since the suite headers contain the totals.
+pub struct JUnitReporter<W> {
+    out: W,
+    suites: Vec<JUnitSuite>,
+}
+
+struct JUnitSuite {
+    server: String,
+    summary: Summary,
+    cases: Vec<String>,
+}
+
+impl<W: Write> JUnitReporter<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            suites: vec![],
+        }
+    }
+}
+
+impl<W: Write> Reporter for JUnitReporter<W> {
+    fn start_suite(&mut self, server: &str) -> io::Result<()> {
+        self.suites.push(JUnitSuite {
+            server: server.to_string(),
+            summary: Summary::default(),
+            cases: vec![],
+        });
+
+        Ok(())
+    }
+
+    fn report(
+        &mut self,
+        test: &TestInfo,
+        result: &TestResult,
+        latency: Option<Latency>,
+    ) -> io::Result<()> {
+        let suite = self
+            .suites
+            .last_mut()
+            .expect("start_suite is called before report");
+        suite.summary.add(result);
+
+        let name = test.name;
+        let classname = name.rsplit_once("::").map_or(name, |(module, _)| module);
+        let mut body = match result {
+            Ok(()) => String::new(),
+            Err(TestError::Fail(msg, None)) => {
+                format!("<failure message=\"{}\"/>", xml_escape(msg))
+            }
+            Err(TestError::Fail(msg, Some(r))) => format!(
+                "<failure message=\"{}\">{}</failure>",
+                xml_escape(msg),
+                xml_escape(&format!("{r:#?}"))
+            ),
+            Err(TestError::Skipped) => "<skipped/>".to_string(),
+            Err(TestError::Error(e)) => {
+                format!("<error message=\"{}\"/>", xml_escape(&format!("{e:#}")))
+            }
+        };
+
+        let mut properties = format!("<property name=\"category\" value=\"{}\"/>", test.category);
+        if let Some(rfc) = test.rfc {
+            properties.push_str(&format!(
+                "<property name=\"rfc\" value=\"{}\"/>",
+                xml_escape(rfc)
+            ));
+        }
+        body.insert_str(0, &format!("<properties>{properties}</properties>"));
+
+        if let Some(latency) = latency {
+            body.push_str(&format!("<system-out>{latency}</system-out>"));
+        }
+
+        suite.cases.push(format!(
+            "    <testcase name=\"{}\" classname=\"{}\">{body}</testcase>",
+            xml_escape(name),
+            xml_escape(classname),
+        ));
+
+        Ok(())
+    }
+
+    fn finish_suite(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        writeln!(self.out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(self.out, "<testsuites>")?;
+        for suite in &self.suites {
+            let Summary {
+                failed,
+                errored,
+                skipped,
+                ..
+            } = suite.summary;
+
+            writeln!(
+                self.out,
+                r#"  <testsuite name="{}" tests="{}" failures="{failed}" errors="{errored}" skipped="{skipped}">"#,
+                xml_escape(&suite.server),
+                suite.summary.total()
+            )?;
+            for case in &suite.cases {
+                writeln!(self.out, "{case}")?;
+            }
+            writeln!(self.out, "  </testsuite>")?;
+        }
+        writeln!(self.out, "</testsuites>")?;
+        self.out.flush()
+    }
+}
+
+/// JSON Lines output, one [`TestReport`] object per line
+pub struct JsonLinesReporter<W> {
+    out: W,
+    server: String,
+}
+
+impl<W: Write> JsonLinesReporter<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            server: String::new(),
+        }
+    }
+}
+
+impl<W: Write> Reporter for JsonLinesReporter<W> {
+    fn start_suite(&mut self, server: &str) -> io::Result<()> {
+        self.server = server.to_string();
+        Ok(())
+    }
+
+    fn report(
+        &mut self,
+        test: &TestInfo,
+        result: &TestResult,
+        latency: Option<Latency>,
+    ) -> io::Result<()> {
+        let report = TestReport::new(&self.server, test, result, latency);
+        serde_json::to_writer(&mut self.out, &report)?;
+        writeln!(self.out)
+    }
+
+    fn finish_suite(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// CBOR output, a single array with a [`TestReport`] of every test
+///
+/// The array is written when [`finish`](Reporter::finish) is called. Only available with the `cbor` feature.
+#[cfg(feature = "cbor")]
+pub struct CborReporter<W> {
+    out: W,
+    server: String,
+    reports: Vec<TestReport>,
+}
+
+#[cfg(feature = "cbor")]
+impl<W: Write> CborReporter<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            server: String::new(),
+            reports: vec![],
+        }
+    }
+}
+
+#[cfg(feature = "cbor")]
+impl<W: Write> Reporter for CborReporter<W> {
+    fn start_suite(&mut self, server: &str) -> io::Result<()> {
+        self.server = server.to_string();
+        Ok(())
+    }
+
+    fn report(
+        &mut self,
+        test: &TestInfo,
+        result: &TestResult,
+        latency: Option<Latency>,
+    ) -> io::Result<()> {
+        self.reports
+            .push(TestReport::new(&self.server, test, result, latency));
+        Ok(())
+    }
+
+    fn finish_suite(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        ciborium::into_writer(&self.reports, &mut self.out).map_err(|e| match e {
+            ciborium::ser::Error::Io(e) => e,
+            other => io::Error::other(other.to_string()),
+        })?;
+        self.out.flush()
+    }
+}
+
+/// Wraps another reporter, keeping a [`TestReport`] of every result to save or compare as a baseline
+///
+/// Results are recorded for the server given to [`set_server`](Self::set_server), not the label of the suite, so
+/// repeated runs against the same server share their keys. See [`compare_baseline`] for how two runs are compared.
+pub struct BaselineRecorder<R> {
+    inner: R,
+    server: String,
+    reports: Vec<TestReport>,
+}
+
+impl<R: Reporter> BaselineRecorder<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            server: String::new(),
+            reports: vec![],
+        }
+    }
+
+    /// Record the following results for `server`
+    pub fn set_server(&mut self, server: &str) {
+        self.server = server.to_string();
+    }
+
+    /// The reports of all results seen so far
+    pub fn reports(&self) -> &[TestReport] {
+        &self.reports
+    }
+}
+
+impl<R: Reporter> Reporter for BaselineRecorder<R> {
+    fn start_suite(&mut self, server: &str) -> io::Result<()> {
+        self.inner.start_suite(server)
+    }
+
+    fn report(
+        &mut self,
+        test: &TestInfo,
+        result: &TestResult,
+        latency: Option<Latency>,
+    ) -> io::Result<()> {
+        self.reports
+            .push(TestReport::new(&self.server, test, result, latency));
+        self.inner.report(test, result, latency)
+    }
+
+    fn finish_suite(&mut self) -> io::Result<()> {
+        self.inner.finish_suite()
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.inner.finish()
+    }
+}
+
+impl Reporter for Box<dyn Reporter> {
+    fn start_suite(&mut self, server: &str) -> io::Result<()> {
+        self.as_mut().start_suite(server)
+    }
+
+    fn report(
+        &mut self,
+        test: &TestInfo,
+        result: &TestResult,
+        latency: Option<Latency>,
+    ) -> io::Result<()> {
+        self.as_mut().report(test, result, latency)
+    }
+
+    fn finish_suite(&mut self) -> io::Result<()> {
+        self.as_mut().finish_suite()
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.as_mut().finish()
+    }
+}
+
+/// A test whose status differs between a baseline and the current run
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct StatusChange {
+    pub server: String,
+    pub name: String,
+    /// The status in the baseline, `None` for tests that are new
+    pub before: Option<Status>,
+    /// The status in the current run, `None` for tests that did not run anymore
+    pub after: Option<Status>,
+}
+
+impl StatusChange {
+    /// Whether a test that passed in the baseline now fails or errors
+    pub fn is_regression(&self) -> bool {
+        self.before == Some(Status::Pass)
+            && matches!(self.after, Some(Status::Fail | Status::Error))
+    }
+}
+
+impl Display for StatusChange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let status = |status: Option<Status>| match status {
+            Some(status) => status.to_string(),
+            None => "missing".to_string(),
+        };
+        let marker = if self.is_regression() {
+            "❌"
+        } else {
+            "ℹ️"
+        };
+
+        write!(
+            f,
+            "{marker} {} {}: {} → {}",
+            self.server,
+            self.name,
+            status(self.before),
+            status(self.after)
+        )
+    }
+}
+
+/// The tests whose status differs between `baseline` and `current`
+///
+/// Changes are listed in the order of `current`, followed by the tests that only appear in `baseline`.
+/// Tests are matched by server and name. If a test was reported multiple times, e.g. with `--repeat` or `--soak`, the
+/// last report counts.
+pub fn compare_baseline(baseline: &[TestReport], current: &[TestReport]) -> Vec<StatusChange> {
+    let key = |report: &TestReport| (report.server.clone(), report.name.clone());
+    let before: HashMap<_, _> = baseline.iter().map(|r| (key(r), r.status)).collect();
+    let after: HashMap<_, _> = current.iter().map(|r| (key(r), r.status)).collect();
+
+    let mut seen = HashSet::new();
+    let mut changes = vec![];
+    for report in current.iter().chain(baseline) {
+        let key = key(report);
+        if !seen.insert(key.clone()) {
+            continue;
+        }
+
+        let (before, after) = (before.get(&key).copied(), after.get(&key).copied());
+        if before != after {
+            let (server, name) = key;
+            changes.push(StatusChange {
+                server,
+                name,
+                before,
+                after,
+            });
+        }
+    }
+
+    changes
+}
+
+/// The changes found by [`compare_baseline`], displayed as a count followed by one line per change
+pub struct ChangeReport<'a>(pub &'a [StatusChange]);
+
+impl Display for ChangeReport<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Changed status compared to the baseline: {}",
+            self.0.len()
+        )?;
+        for change in self.0 {
+            write!(f, "\n{change}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// [TAP version 13](https://testanything.org/tap-version-13-specification.html) output
+///
+/// All servers are reported in a single stream, with a comment line before the tests of each server. The output is
+/// written when [`finish`](Reporter::finish) is called, since the plan line at the start contains the number of tests.
+pub struct TapReporter<W> {
+    out: W,
+    lines: Vec<String>,
+    tests: usize,
+}
+
+impl<W: Write> TapReporter<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            lines: vec![],
+            tests: 0,
+        }
+    }
+
+    /// Add a YAML diagnostic block with the given multi-line values
+    fn diagnostics(&mut self, entries: &[(&str, &str)]) {
+        self.lines.push("  ---".to_string());
+        for (key, value) in entries {
+            self.lines.push(format!("  {key}: |"));
+            self.lines
+                .extend(value.lines().map(|line| format!("    {line}")));
+        }
+        self.lines.push("  ...".to_string());
+    }
+}
+
+impl<W: Write> Reporter for TapReporter<W> {
+    fn start_suite(&mut self, server: &str) -> io::Result<()> {
+        self.lines.push(format!("# Testing {server}"));
+        Ok(())
+    }
+
+    fn report(
+        &mut self,
+        test: &TestInfo,
+        result: &TestResult,
+        latency: Option<Latency>,
+    ) -> io::Result<()> {
+        self.tests += 1;
+        let number = self.tests;
+        let name = test.name;
+
+        match result {
+            Ok(()) => self.lines.push(format!("ok {number} - {name}")),
+            Err(TestError::Skipped) => self.lines.push(format!("ok {number} - {name} # SKIP")),
+            Err(TestError::Fail(msg, response)) => {
+                self.lines.push(format!("not ok {number} - {name}"));
+                let response = response.as_ref().map(|r| format!("{r:#?}"));
+                let mut entries = vec![("severity", "fail"), ("message", msg.as_str())];
+                if let Some(response) = &response {
+                    entries.push(("response", response));
+                }
+                self.diagnostics(&entries);
+            }
+            Err(TestError::Error(e)) => {
+                self.lines.push(format!("not ok {number} - {name}"));
+                let msg = format!("{e:#}");
+                self.diagnostics(&[("severity", "error"), ("message", &msg)]);
+            }
+        }
+
+        if let Some(latency) = latency {
+            self.lines.push(format!("# {latency}"));
+        }
+
+        Ok(())
+    }
+
+    fn finish_suite(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        writeln!(self.out, "TAP version 13")?;
+        writeln!(self.out, "1..{}", self.tests)?;
+        for line in &self.lines {
+            writeln!(self.out, "{line}")?;
+        }
+        self.out.flush()
+    }
+}
+
+fn xml_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::result::PASS;
+    use crate::Response;
+
+    const INFO: TestInfo = TestInfo {
+        name: "tests::basic::example",
+        category: Category::Ntp,
+        description: None,
+        rfc: None,
+    };
+
+    fn failure() -> TestResult {
+        Err(TestError::Fail(
+            "Server did something wrong".to_string(),
+            Some(Box::new(Response::UdpUnparsable(vec![1, 2, 3].into()))),
+        ))
+    }
+
+    #[test]
+    fn jsonl_reports_failure_with_message() {
+        let mut out = vec![];
+        let mut reporter = JsonLinesReporter::new(&mut out);
+        reporter.start_suite("localhost").unwrap();
+        reporter.report(&INFO, &failure(), None).unwrap();
+        reporter.finish_suite().unwrap();
+        reporter.finish().unwrap();
+
+        let report: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(report["server"], "localhost");
+        assert_eq!(report["name"], "tests::basic::example");
+        assert_eq!(report["status"], "fail");
+        assert_eq!(report["message"], "Server did something wrong");
+        assert!(report["response"].as_str().unwrap().contains("010203"));
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor_round_trips_reports() {
+        let described = TestInfo {
+            description: Some("An example"),
+            rfc: Some("RFC5905"),
+            ..INFO
+        };
+        let latency = Latency {
+            min_ms: 1.0,
+            median_ms: 2.5,
+        };
+        let results = [
+            ("localhost", &described, PASS, Some(latency)),
+            ("localhost", &INFO, failure(), None),
+            ("ntp.example", &INFO, Err(TestError::Skipped), None),
+            (
+                "ntp.example",
+                &described,
+                Err(TestError::Error(anyhow::anyhow!("No route"))),
+                None,
+            ),
+        ];
+
+        let mut out = vec![];
+        let mut reporter = CborReporter::new(&mut out);
+        for (server, test, result, latency) in &results {
+            reporter.start_suite(server).unwrap();
+            reporter.report(test, result, *latency).unwrap();
+            reporter.finish_suite().unwrap();
+        }
+        reporter.finish().unwrap();
+
+        let expected: Vec<_> = results
+            .iter()
+            .map(|(server, test, result, latency)| TestReport::new(server, test, result, *latency))
+            .collect();
+        let decoded: Vec<TestReport> = ciborium::from_reader(out.as_slice()).unwrap();
+        assert_eq!(decoded, expected);
+    }
+
+    #[test]
+    fn tap_golden_output() {
+        let mut out = vec![];
+        let mut reporter = TapReporter::new(&mut out);
+        let latency = Latency {
+            min_ms: 1.0,
+            median_ms: 2.5,
+        };
+        reporter.start_suite("localhost").unwrap();
+        reporter.report(&INFO, &PASS, Some(latency)).unwrap();
+        reporter
+            .report(&INFO, &Err(TestError::Skipped), None)
+            .unwrap();
+        reporter.report(&INFO, &failure(), None).unwrap();
+        let error = Err(TestError::Error(
+            anyhow::anyhow!("No route").context("Sending"),
+        ));
+        reporter.report(&INFO, &error, None).unwrap();
+        reporter.finish_suite().unwrap();
+        reporter.finish().unwrap();
+
+        let expected = "\
+TAP version 13
+1..4
+# Testing localhost
+ok 1 - tests::basic::example
+# RTT min 1.00ms, median 2.50ms
+ok 2 - tests::basic::example # SKIP
+not ok 3 - tests::basic::example
+  ---
+  severity: |
+    fail
+  message: |
+    Server did something wrong
+  response: |
+    UdpUnparsable(
+        \"010203\",
+    )
+  ...
+not ok 4 - tests::basic::example
+  ---
+  severity: |
+    error
+  message: |
+    Sending: No route
+  ...
+";
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+
+    fn info(name: &'static str) -> TestInfo {
+        TestInfo { name, ..INFO }
+    }
+
+    #[test]
+    fn baseline_compares_last_run_of_each_server() {
+        let baseline = [
+            TestReport::new("localhost", &info("a"), &PASS, None),
+            TestReport::new("localhost", &info("b"), &failure(), None),
+            TestReport::new("localhost", &info("unchanged"), &PASS, None),
+        ];
+
+        let mut recorder = BaselineRecorder::new(ConsoleReporter::new(io::sink()));
+        for (run, a, b) in [(1, PASS, PASS), (2, failure(), PASS)] {
+            recorder.set_server("localhost");
+            recorder
+                .start_suite(&format!("localhost (run {run}/2)"))
+                .unwrap();
+            recorder.report(&info("a"), &a, None).unwrap();
+            recorder.report(&info("b"), &b, None).unwrap();
+            recorder.report(&info("unchanged"), &PASS, None).unwrap();
+            recorder.report(&info("new"), &PASS, None).unwrap();
+            recorder.finish_suite().unwrap();
+        }
+
+        let changes = compare_baseline(&baseline, recorder.reports());
+        let summary: Vec<_> = changes
+            .iter()
+            .map(|change| {
+                assert_eq!(change.server, "localhost");
+                (change.name.as_str(), change.before, change.after)
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("a", Some(Status::Pass), Some(Status::Fail)),
+                ("b", Some(Status::Fail), Some(Status::Pass)),
+                ("new", None, Some(Status::Pass)),
+            ]
+        );
+        assert!(changes[0].is_regression());
+        assert!(!changes[1].is_regression());
+
+        let report = ChangeReport(&changes).to_string();
+        assert!(report.starts_with(
+            "Changed status compared to the baseline: 3\n❌ localhost a: pass → fail"
+        ));
+    }
+}
@@ -0,0 +1,236 @@
+//! A minimal SOCKS5 client, to reach NTS-KE servers that are only reachable through a proxy
+//!
+//! Only the parts of [RFC1928](https://datatracker.ietf.org/doc/html/rfc1928) needed to open a TCP connection without
+//! authentication are implemented. UDP is not proxied, so NTP traffic still goes directly to the server.
+
+use std::fmt::{Display, Formatter};
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::time::Duration;
+
+const VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+const CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// The address of a SOCKS5 proxy, given as `socks5://host:port`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Socks5Proxy {
+    host: String,
+    port: u16,
+}
+
+impl FromStr for Socks5Proxy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let address = s
+            .strip_prefix("socks5://")
+            .or_else(|| s.strip_prefix("socks5h://"))
+            .ok_or_else(|| format!("Proxy {s:?} does not start with socks5://"))?;
+        let (host, port) = address
+            .rsplit_once(':')
+            .ok_or_else(|| format!("Proxy {s:?} has no port"))?;
+        let port = port
+            .parse()
+            .map_err(|e| format!("Proxy {s:?} has an invalid port: {e}"))?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(format!("Proxy {s:?} has no host"));
+        }
+
+        Ok(Self {
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl Display for Socks5Proxy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "socks5://[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "socks5://{}:{}", self.host, self.port)
+        }
+    }
+}
+
+impl Socks5Proxy {
+    /// Open a TCP connection to `host` and `port` through the proxy
+    ///
+    /// The host name is resolved by the proxy, so hosts only known on the other side of it can be reached. The
+    /// `timeout` applies to every read and write on the returned stream, including the proxy handshake.
+    pub fn connect(&self, host: &str, port: u16, timeout: Duration) -> io::Result<TcpStream> {
+        let proxy = (self.host.as_str(), self.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::other(format!("Proxy {self} did not resolve")))?;
+        let mut stream = TcpStream::connect_timeout(&proxy, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+
+        stream.write_all(&[VERSION, 1, NO_AUTHENTICATION])?;
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply)?;
+        match reply {
+            [VERSION, NO_AUTHENTICATION] => {}
+            [VERSION, NO_ACCEPTABLE_METHODS] => {
+                return Err(io::Error::other("Proxy requires authentication"))
+            }
+            other => {
+                return Err(io::Error::other(format!(
+                    "Unexpected proxy method selection {other:?}"
+                )))
+            }
+        }
+
+        let host_len = u8::try_from(host.len())
+            .map_err(|_| io::Error::other(format!("Host name {host:?} is too long for SOCKS5")))?;
+        let mut request = vec![VERSION, CONNECT, 0, ATYP_DOMAIN, host_len];
+        request.extend_from_slice(host.as_bytes());
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request)?;
+
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply)?;
+        if reply[0] != VERSION {
+            return Err(io::Error::other(format!(
+                "Proxy replied with version {}",
+                reply[0]
+            )));
+        }
+        if reply[1] != 0 {
+            return Err(io::Error::other(format!(
+                "Proxy could not connect to {host}:{port}: {}",
+                reply_message(reply[1])
+            )));
+        }
+
+        // The address the proxy bound for us is of no use, but has to be read before the stream is ours
+        let bound_len = match reply[3] {
+            ATYP_IPV4 => 4,
+            ATYP_IPV6 => 16,
+            ATYP_DOMAIN => {
+                let mut len = [0u8; 1];
+                stream.read_exact(&mut len)?;
+                len[0] as usize
+            }
+            other => {
+                return Err(io::Error::other(format!(
+                    "Proxy replied with unknown address type {other}"
+                )))
+            }
+        };
+        let mut bound = vec![0u8; bound_len + 2];
+        stream.read_exact(&mut bound)?;
+
+        Ok(stream)
+    }
+}
+
+/// Description of a SOCKS5 reply code, see [RFC1928 section 6](https://datatracker.ietf.org/doc/html/rfc1928#section-6)
+fn reply_message(code: u8) -> &'static str {
+    match code {
+        1 => "general SOCKS server failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unassigned error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, TcpListener};
+    use std::thread::JoinHandle;
+
+    /// A proxy for a single connection that answers the request with `reply_code`, and then echoes one message
+    ///
+    /// The thread returns the host and port the client asked for.
+    fn proxy(reply_code: u8) -> (Socks5Proxy, JoinHandle<(String, u16)>) {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let proxy = Socks5Proxy {
+            host: "127.0.0.1".to_string(),
+            port: listener.local_addr().unwrap().port(),
+        };
+
+        let handle = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).unwrap();
+            assert_eq!(greeting, [VERSION, 1, NO_AUTHENTICATION]);
+            stream.write_all(&[VERSION, NO_AUTHENTICATION]).unwrap();
+
+            let mut request = [0u8; 5];
+            stream.read_exact(&mut request).unwrap();
+            assert_eq!(request[..4], [VERSION, CONNECT, 0, ATYP_DOMAIN]);
+            let mut host = vec![0u8; request[4] as usize];
+            stream.read_exact(&mut host).unwrap();
+            let mut port = [0u8; 2];
+            stream.read_exact(&mut port).unwrap();
+            stream
+                .write_all(&[VERSION, reply_code, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+                .unwrap();
+
+            if reply_code == 0 {
+                let mut message = [0u8; 5];
+                stream.read_exact(&mut message).unwrap();
+                stream.write_all(&message).unwrap();
+            }
+
+            (String::from_utf8(host).unwrap(), u16::from_be_bytes(port))
+        });
+
+        (proxy, handle)
+    }
+
+    #[test]
+    fn connects_through_proxy() {
+        let (proxy, handle) = proxy(0);
+        let mut stream = proxy
+            .connect("nts.example.com", 4460, Duration::from_secs(1))
+            .unwrap();
+
+        stream.write_all(b"hello").unwrap();
+        let mut echo = [0u8; 5];
+        stream.read_exact(&mut echo).unwrap();
+        assert_eq!(&echo, b"hello");
+        assert_eq!(
+            handle.join().unwrap(),
+            ("nts.example.com".to_string(), 4460)
+        );
+    }
+
+    #[test]
+    fn reports_proxy_reply_code() {
+        let (proxy, handle) = proxy(5);
+        let error = proxy
+            .connect("nts.example.com", 4460, Duration::from_secs(1))
+            .unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "Proxy could not connect to nts.example.com:4460: connection refused"
+        );
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn parses_proxy_address() {
+        let proxy: Socks5Proxy = "socks5://[::1]:1080".parse().unwrap();
+        assert_eq!(proxy.host, "::1");
+        assert_eq!(proxy.port, 1080);
+        assert_eq!(proxy.to_string(), "socks5://[::1]:1080");
+        assert!("http://proxy:1080".parse::<Socks5Proxy>().is_err());
+    }
+}
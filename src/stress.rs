@@ -0,0 +1,141 @@
+//! Load tests that measure how a server copes with many concurrent clients
+//!
+//! Unlike the tests in [`all_tests`](crate::all_tests) these do not check for correct behavior of a single exchange,
+//! but report statistics over many of them.
+
+use crate::nts::NtsCookie;
+use crate::nts_ke::NtsKeConnection;
+use crate::NtsServer;
+use ntp_proto::AeadAlgorithm;
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant};
+
+/// The outcome of a single key exchange during [`stress_ke`]
+enum KeOutcome {
+    Success(Vec<NtsCookie>),
+    TlsFailure,
+    ProtocolFailure,
+}
+
+/// Statistics over the concurrent key exchanges of [`stress_ke`]
+#[derive(Debug, Default, Clone)]
+pub struct KeStressReport {
+    /// Number of key exchanges started
+    pub connections: usize,
+    /// Key exchanges that returned cookies and keys
+    pub succeeded: usize,
+    /// Connections that could not be established, or failed during the TLS handshake
+    pub tls_failures: usize,
+    /// Connections that completed the handshake, but not the NTS-KE protocol
+    pub protocol_failures: usize,
+    /// Cookies handed out more than once, over all connections
+    pub duplicate_cookies: usize,
+    /// Duration of the successful key exchanges, sorted ascending
+    pub latencies: Vec<Duration>,
+}
+
+impl KeStressReport {
+    /// Whether every key exchange succeeded with unique cookies
+    pub fn is_success(&self) -> bool {
+        self.succeeded == self.connections && self.duplicate_cookies == 0
+    }
+
+    /// The latency below which `fraction` of the successful exchanges completed
+    fn percentile(&self, fraction: f64) -> Option<Duration> {
+        let last = self.latencies.len().checked_sub(1)?;
+        Some(self.latencies[(last as f64 * fraction).round() as usize])
+    }
+}
+
+impl Display for KeStressReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "✅ Succeeded: {}/{}", self.succeeded, self.connections)?;
+        writeln!(f, "❓ TLS failures: {}", self.tls_failures)?;
+        writeln!(f, "❌ Protocol failures: {}", self.protocol_failures)?;
+        writeln!(f, "❌ Duplicate cookies: {}", self.duplicate_cookies)?;
+
+        let (Some(min), Some(median), Some(p90), Some(max)) = (
+            self.percentile(0.0),
+            self.percentile(0.5),
+            self.percentile(0.9),
+            self.percentile(1.0),
+        ) else {
+            return write!(f, "No successful key exchanges to measure latency");
+        };
+
+        write!(
+            f,
+            "Latency min {:.2}ms, median {:.2}ms, p90 {:.2}ms, max {:.2}ms",
+            min.as_secs_f64() * 1000.0,
+            median.as_secs_f64() * 1000.0,
+            p90.as_secs_f64() * 1000.0,
+            max.as_secs_f64() * 1000.0,
+        )
+    }
+}
+
+/// Run `count` key exchanges against `server` at the same time, each on its own thread
+///
+/// The connections use the same [`KeOptions`](crate::nts_ke::KeOptions) as `server`.
+pub fn stress_ke(server: &NtsServer, count: usize) -> KeStressReport {
+    let outcomes: Vec<_> = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..count)
+            .map(|_| scope.spawn(|| timed_key_exchange(server)))
+            .collect();
+
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap_or((KeOutcome::ProtocolFailure, None)))
+            .collect()
+    });
+
+    let mut report = KeStressReport {
+        connections: count,
+        ..KeStressReport::default()
+    };
+    let mut seen = HashSet::new();
+    for (outcome, latency) in outcomes {
+        match outcome {
+            KeOutcome::Success(cookies) => {
+                report.succeeded += 1;
+                report.duplicate_cookies += cookies
+                    .into_iter()
+                    .filter(|cookie| !seen.insert(cookie.clone()))
+                    .count();
+            }
+            KeOutcome::TlsFailure => report.tls_failures += 1,
+            KeOutcome::ProtocolFailure => report.protocol_failures += 1,
+        }
+        report.latencies.extend(latency);
+    }
+    report.latencies.sort();
+
+    report
+}
+
+/// Run a single key exchange, returning its outcome and duration if it succeeded
+fn timed_key_exchange(server: &NtsServer) -> (KeOutcome, Option<Duration>) {
+    let start = Instant::now();
+
+    let connection = NtsKeConnection::new(server.host(), server.port(), server.ke_options());
+    let mut ke = match connection {
+        Ok(ke) => ke,
+        Err(e) => {
+            tracing::debug!(error = ?e, "could not connect to NTS-KE server");
+            return (KeOutcome::TlsFailure, None);
+        }
+    };
+    if let Err(e) = ke.handshake() {
+        tracing::debug!(error = ?e, "NTS-KE handshake failed");
+        return (KeOutcome::TlsFailure, None);
+    }
+
+    match ke.do_request(AeadAlgorithm::AeadAesSivCmac256) {
+        Ok((cookies, _, _)) => (KeOutcome::Success(cookies), Some(start.elapsed())),
+        Err(e) => {
+            tracing::debug!(error = ?e, "NTS-KE exchange failed");
+            (KeOutcome::ProtocolFailure, None)
+        }
+    }
+}
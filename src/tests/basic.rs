@@ -1,16 +1,27 @@
 //! Tests that exercise the base protocol described in [RFC5905](https://datatracker.ietf.org/doc/html/rfc5905).
 
 use crate::macros::*;
-use crate::udp::UdpConnection;
-use crate::util::result::{TestResult, PASS};
-use ntp_proto::{NtpAssociationMode, NtpPacket};
+use crate::raw::RawSender;
+use crate::udp::{
+    association_mode_bits, clock_offset, leap, poll_interval, reference_timestamp,
+    round_trip_delay, seconds_between, short_format_to_seconds, stratum, udp_server_still_alive,
+    NtpTimestampExt, UdpConnection, UdpProbe, UdpRequest, MAX_POLL_EXPONENT, MIN_POLL_EXPONENT,
+};
+use crate::util::result::{fail, fail_no_response, TestError, TestResult, PASS};
+use crate::{TestCase, TestConfig};
+use anyhow::Context;
+use ntp_proto::{NtpAssociationMode, NtpLeapIndicator, NtpPacket, NtpTimestamp};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4};
+use std::panic::UnwindSafe;
+use std::time::Duration;
 
 /// Sending a normal poll request should return an answer
 ///
 /// Checks that the tested server actually responds to us.
 pub fn test_responds_to_version_4(conn: &mut UdpConnection) -> TestResult {
     let (request, id) = NtpPacket::poll_message(Default::default());
-    let response = conn.pester(request)?;
+    let response = conn.pester_retry(request, 3)?;
 
     let packet = pester_assert_response!(response);
     let header = pester_assert_version!(packet, packet, V4);
@@ -43,10 +54,833 @@ pub fn test_responds_to_version_4(conn: &mut UdpConnection) -> TestResult {
     PASS
 }
 
+/// Check that a zero transmit timestamp is copied into the origin timestamp
+///
+/// The server should copy the transmit timestamp of the request into the origin timestamp of the response, even if
+/// that timestamp is zero.
+pub fn test_zero_transmit_timestamp_is_echoed(conn: &mut UdpConnection) -> TestResult {
+    let (request, mut id) = NtpPacket::poll_message(Default::default());
+    id.expected_origin_timestamp = NtpTimestamp::default();
+    let request = UdpRequest::from(request).with_transmit_timestamp(0);
+
+    let response = pester_assert_response!(conn.pester_raw(request)?);
+    let packet = response.parse()?;
+    let header = pester_assert_version!(packet, packet, V4);
+
+    pester_assert_eq!(
+        packet,
+        header.origin_timestamp,
+        NtpTimestamp::default(),
+        "Origin timestamp does not match the zero transmit timestamp"
+    );
+    pester_assert!(
+        packet,
+        packet.valid_server_response(id, false),
+        "Server response not matching original packet"
+    );
+
+    PASS
+}
+
+/// Check that the timestamps of two consecutive polls are consistent
+///
+/// Servers supporting interleaved mode may answer the second poll with the transmit timestamp of the first exchange as
+/// origin. Either behavior is accepted, but the receive and transmit timestamps of both responses have to be ordered.
+pub fn test_consecutive_timestamps_are_consistent(conn: &mut UdpConnection) -> TestResult {
+    let (request, id) = NtpPacket::poll_message(Default::default());
+    let first = pester_assert_response!(conn.pester(request)?);
+    pester_assert!(
+        first,
+        first.valid_server_response(id, false),
+        "First response not matching original packet"
+    );
+    let first_header = pester_assert_version!(first, first, V4);
+
+    let (request, id) = NtpPacket::poll_message(Default::default());
+    let second = pester_assert_response!(conn.pester(request)?);
+    let second_header = pester_assert_version!(second, second, V4);
+
+    // In interleaved mode the origin refers to the previous exchange
+    let interleaved = second_header.origin_timestamp == first_header.transmit_timestamp;
+    if !interleaved {
+        pester_assert!(
+            second,
+            second.valid_server_response(id, false),
+            "Second response not matching original packet"
+        );
+    }
+
+    pester_assert!(
+        first,
+        first_header.transmit_timestamp >= first_header.receive_timestamp,
+        "First response was transmitted before it was received (interleaved: {interleaved})"
+    );
+    pester_assert!(
+        second,
+        second_header.transmit_timestamp >= second_header.receive_timestamp,
+        "Second response was transmitted before it was received (interleaved: {interleaved})"
+    );
+    pester_assert!(
+        second,
+        second_header.receive_timestamp >= first_header.receive_timestamp,
+        "Second request was received before the first (interleaved: {interleaved})"
+    );
+
+    PASS
+}
+
+/// Default upper bound for root delay and root dispersion before we consider a server misconfigured, see
+/// [`test_root_delay_and_dispersion_are_bounded`]
+pub const DEFAULT_MAX_ROOT_DISTANCE_SECONDS: f64 = 16.0;
+
+/// Check that the root delay and root dispersion of a server are plausible
+///
+/// Both values are non-negative by definition, and a server reporting more than `max_seconds` of root delay or
+/// dispersion is most likely misconfigured. The bound is set with `--max-root-distance`,
+/// [`DEFAULT_MAX_ROOT_DISTANCE_SECONDS`] by default.
+pub fn test_root_delay_and_dispersion_are_bounded(
+    max_seconds: f64,
+) -> Box<dyn TestCase + UnwindSafe + Send> {
+    struct RootDistance(f64);
+
+    impl TestCase for RootDistance {
+        fn name(&self) -> &'static str {
+            concat!(
+                module_path!(),
+                "::test_root_delay_and_dispersion_are_bounded"
+            )
+        }
+
+        fn run(&self, conf: &TestConfig) -> TestResult {
+            let mut conn = conf.udp()?;
+            root_distance_below(&mut conn, self.0)?;
+
+            udp_server_still_alive(&mut conn, None)
+        }
+    }
+
+    Box::new(RootDistance(max_seconds))
+}
+
+fn root_distance_below(conn: &mut UdpConnection, max_seconds: f64) -> TestResult {
+    let (request, id) = NtpPacket::poll_message(Default::default());
+    let packet = pester_assert_response!(conn.pester(request)?);
+    let header = pester_assert_version!(packet, packet, V4);
+
+    pester_assert!(
+        packet,
+        packet.valid_server_response(id, false),
+        "Server response not matching original packet"
+    );
+
+    let root_delay = short_format_to_seconds(header.root_delay);
+    let root_dispersion = short_format_to_seconds(header.root_dispersion);
+
+    pester_assert!(
+        packet,
+        root_delay >= 0.0 && root_dispersion >= 0.0,
+        "Negative root delay ({root_delay}s) or root dispersion ({root_dispersion}s)"
+    );
+    pester_assert_lt!(
+        packet,
+        root_delay,
+        max_seconds,
+        "Root delay of {root_delay}s is implausibly large"
+    );
+    pester_assert_lt!(
+        packet,
+        root_dispersion,
+        max_seconds,
+        "Root dispersion of {root_dispersion}s is implausibly large"
+    );
+
+    PASS
+}
+
+/// Number of polls sampled by [`test_root_dispersion_evolves_plausibly`]
+const DISPERSION_SAMPLES: usize = 5;
+
+/// Time between the polls of [`test_root_dispersion_evolves_plausibly`]
+const DISPERSION_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Check that the root dispersion behaves like an accumulated error estimate over repeated polls
+///
+/// The dispersion grows with the age of the last clock update, or stays flat in implementations that only update it
+/// when syncing. It can only shrink when the reference timestamp shows a new update. A dispersion of exactly zero in
+/// every response is a common sign of a stub server. Unsynchronized servers are skipped.
+pub fn test_root_dispersion_evolves_plausibly(conn: &mut UdpConnection) -> TestResult {
+    let mut samples = vec![];
+
+    for sample in 0..DISPERSION_SAMPLES {
+        if sample > 0 {
+            std::thread::sleep(DISPERSION_POLL_INTERVAL);
+        }
+
+        let (request, id) = NtpPacket::poll_message(Default::default());
+        let packet = pester_assert_response!(conn.pester(request)?);
+        let header = pester_assert_version!(packet, packet, V4);
+        pester_assert!(
+            packet,
+            packet.valid_server_response(id, false),
+            "Server response not matching original packet"
+        );
+        if header.stratum == 0 || header.leap == NtpLeapIndicator::Unknown {
+            return Err(TestError::Skipped);
+        }
+
+        samples.push((
+            header.reference_timestamp,
+            short_format_to_seconds(header.root_dispersion),
+        ));
+    }
+
+    let sequence = samples
+        .iter()
+        .map(|(_, dispersion)| format!("{:.6}s", dispersion))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    if samples.iter().all(|&(_, dispersion)| dispersion == 0.0) {
+        return fail_no_response(format!(
+            "Root dispersion is exactly zero in all {DISPERSION_SAMPLES} responses"
+        ));
+    }
+
+    for (index, pair) in samples.windows(2).enumerate() {
+        let [(reference_before, before), (reference_after, after)] = pair else {
+            unreachable!("windows of size 2");
+        };
+        if reference_before == reference_after && after < before {
+            return fail_no_response(format!(
+                "Root dispersion decreased between poll {} and {} without a clock update: {sequence}",
+                index + 1,
+                index + 2,
+            ));
+        }
+    }
+
+    PASS
+}
+
+/// Default upper bound for the ratio between response and request size, see [`test_amplification_factor`]
+pub const DEFAULT_MAX_AMPLIFICATION_FACTOR: f64 = 1.5;
+
+/// Check that the server does not reply with more than `max_factor` times the bytes it received
+///
+/// Responses that are much larger than the request make a server useful for amplification attacks. The bound is set
+/// with `--max-amplification`, [`DEFAULT_MAX_AMPLIFICATION_FACTOR`] by default.
+pub fn test_amplification_factor(max_factor: f64) -> Box<dyn TestCase + UnwindSafe + Send> {
+    struct AmplificationFactor(f64);
+
+    impl TestCase for AmplificationFactor {
+        fn name(&self) -> &'static str {
+            concat!(module_path!(), "::test_amplification_factor")
+        }
+
+        fn run(&self, conf: &TestConfig) -> TestResult {
+            let mut conn = conf.udp()?;
+            amplification_factor_below(&mut conn, self.0)?;
+
+            udp_server_still_alive(&mut conn, None)
+        }
+    }
+
+    Box::new(AmplificationFactor(max_factor))
+}
+
+fn amplification_factor_below(conn: &mut UdpConnection, max_factor: f64) -> TestResult {
+    let (request, _id) = NtpPacket::poll_message(Default::default());
+    let request = UdpRequest::from(request);
+    let request_len = request.0.len();
+
+    let response = pester_assert_response!(conn.pester_raw(request)?);
+    let factor = response.len() as f64 / request_len as f64;
+
+    pester_assert_lt!(
+        response,
+        factor,
+        max_factor,
+        "Response of {} bytes to a request of {request_len} bytes is an amplification of {factor:.2}",
+        response.len()
+    );
+
+    PASS
+}
+
+/// Check that the stratum and leap indicator agree on whether the server is synchronized
+///
+/// A server reporting stratum 16 (unsynchronized) must set the leap indicator to 3 (alarm), and a synchronized server
+/// (stratum 1-15) must never do so. See [RFC5905 section 7.3](https://datatracker.ietf.org/doc/html/rfc5905#section-7.3).
+pub fn test_stratum_matches_leap_indicator(conn: &mut UdpConnection) -> TestResult {
+    let (request, id) = NtpPacket::poll_message(Default::default());
+    let packet = pester_assert_response!(conn.pester(request)?);
+    pester_assert!(
+        packet,
+        packet.valid_server_response(id, false),
+        "Server response not matching original packet"
+    );
+
+    let (Some(stratum), Some(leap)) = (stratum(&packet), leap(&packet)) else {
+        return fail(
+            format!(
+                "Server replied with version {} instead of V4",
+                packet.version()
+            ),
+            packet,
+        );
+    };
+
+    match stratum {
+        // Kiss-o'-Death packets carry no synchronization information
+        0 => {}
+        1..=15 => {
+            pester_assert!(
+                packet,
+                leap != NtpLeapIndicator::Unknown,
+                "Server reports stratum {stratum} (synchronized) but sets leap indicator 3 (alarm)"
+            );
+        }
+        _ => {
+            pester_assert_eq!(
+                packet,
+                leap,
+                NtpLeapIndicator::Unknown,
+                "Server reports stratum {stratum} (unsynchronized) without leap indicator 3 (alarm)"
+            );
+        }
+    }
+
+    PASS
+}
+
+/// A reference id no server would pick for itself
+const CLIENT_REFERENCE_ID: [u8; 4] = [0xde, 0xad, 0xbe, 0xef];
+
+/// Check that the server fills in its own reference id instead of reflecting the one of the client
+///
+/// Echoing client supplied fields makes spoofed responses easier to craft. Kiss-o'-Death packets (stratum 0) carry a
+/// kiss code in this field, the test is skipped for them.
+pub fn test_reference_id_is_not_reflected(conn: &mut UdpConnection) -> TestResult {
+    let (packet, _id) = NtpPacket::poll_message(Default::default());
+    let request = UdpRequest::from(packet).with_reference_id(CLIENT_REFERENCE_ID);
+
+    let response = pester_assert_response!(conn.pester_raw(request)?);
+    let packet = response.parse()?;
+    if stratum(&packet) == Some(0) {
+        return Err(TestError::Skipped);
+    }
+
+    let Some(reference_id) = response.reference_id() else {
+        return fail("Response is too short to contain a reference id", response);
+    };
+    if reference_id == CLIENT_REFERENCE_ID {
+        return fail(
+            format!(
+                "Server reflected the reference id {} sent by the client, received {}",
+                hex::encode(CLIENT_REFERENCE_ID),
+                hex::encode(reference_id)
+            ),
+            packet,
+        );
+    }
+
+    PASS
+}
+
+/// The header fields a server has to fill in itself, as name and byte range in the packet
+const SERVER_FIELDS: [(&str, std::ops::Range<usize>); 7] = [
+    ("stratum", 1..2),
+    ("root delay", 4..8),
+    ("root dispersion", 8..12),
+    ("reference id", 12..16),
+    ("reference timestamp", 16..24),
+    ("receive timestamp", 32..40),
+    ("transmit timestamp", 40..48),
+];
+
+/// Check that the server handles a request with every header field at its maximum
+///
+/// The server may drop the request, but if it answers it must copy the transmit timestamp into the origin timestamp
+/// and fill in its own values for the other fields instead of echoing the extremes it received. Kiss-o'-Death packets
+/// (stratum 0) carry a kiss code in the reference id, which is not checked for them.
+pub fn test_all_fields_maximal(conn: &mut UdpConnection) -> TestResult {
+    let request = UdpRequest::all_fields_maximal();
+    let sent = request.0.clone();
+
+    let Some(response) = conn.pester_raw(request)? else {
+        return PASS;
+    };
+    if response.len() < 48 {
+        return fail(
+            "Response to a request with maximal fields is too short",
+            response,
+        );
+    }
+
+    if response.0[24..32] != sent[40..48] {
+        return fail(
+            "Response to a request with maximal fields has an incorrect origin timestamp",
+            response,
+        );
+    }
+
+    let is_kiss = response.0[1] == 0;
+    for (name, range) in SERVER_FIELDS {
+        if is_kiss && name == "reference id" {
+            continue;
+        }
+        if response.0[range.clone()] == sent[range.clone()] {
+            return fail(
+                format!(
+                    "Server echoed the maximal {name} {} of the request",
+                    hex::encode(&sent[range])
+                ),
+                response,
+            );
+        }
+    }
+
+    PASS
+}
+
+/// Key id of the MAC sent by [`test_bogus_mac_is_not_reflected`], unlikely to be configured on any server
+const BOGUS_MAC_KEY_ID: u32 = 0x5045_5354;
+
+/// Digest of the MAC sent by [`test_bogus_mac_is_not_reflected`], the length of a MD5 digest
+const BOGUS_MAC_DIGEST: [u8; 16] = [0x5a; 16];
+
+/// Check that a request with a MAC for an unknown key is handled without reflecting the MAC
+///
+/// The server may drop the request, answer it without MAC, or answer with a crypto-NAK (a MAC with key id 0 and no
+/// digest), see [RFC5905 section 7.4](https://datatracker.ietf.org/doc/html/rfc5905#section-7.4). Copying the MAC of
+/// the request into the response is never correct.
+pub fn test_bogus_mac_is_not_reflected(conn: &mut UdpConnection) -> TestResult {
+    let (request, _id) = NtpPacket::poll_message(Default::default());
+    let request = UdpRequest::from(request).with_mac(BOGUS_MAC_KEY_ID, &BOGUS_MAC_DIGEST);
+    let transmit = request.0[40..48].to_vec();
+    let mac = request.0[48..].to_vec();
+
+    let Some(response) = conn.pester_raw(request)? else {
+        return PASS;
+    };
+    if response.len() < 48 {
+        return fail("Response to a request with a MAC is too short", response);
+    }
+
+    if response.0[48..]
+        .windows(BOGUS_MAC_DIGEST.len())
+        .any(|window| window == BOGUS_MAC_DIGEST)
+    {
+        return fail(
+            format!(
+                "Server reflected the MAC {} of the request",
+                hex::encode(&mac)
+            ),
+            response,
+        );
+    }
+    if response.0[24..32] != transmit[..] {
+        return fail(
+            "Response to a request with a MAC has an incorrect origin timestamp",
+            response,
+        );
+    }
+
+    PASS
+}
+
+/// Number of polls sampled by [`test_offset_jitter_is_bounded`]
+const JITTER_SAMPLES: usize = 8;
+
+/// Time between the polls of [`test_offset_jitter_is_bounded`], short enough to keep the test fast but long enough
+/// to not trip common rate limits
+const JITTER_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Upper bound for the standard deviation of the clock offsets measured by [`test_offset_jitter_is_bounded`]
+pub const MAX_OFFSET_JITTER_SECONDS: f64 = 0.1;
+
+/// Check that the clock offset to the server is consistent over repeated polls
+///
+/// This is mostly informational, the offset and delay of every poll are computed as described in
+/// [RFC5905 section 8](https://datatracker.ietf.org/doc/html/rfc5905#section-8). Network jitter is expected, only
+/// offsets spread so widely that the server timestamps can not be trusted make the test fail.
+pub fn test_offset_jitter_is_bounded(conn: &mut UdpConnection) -> TestResult {
+    let mut offsets = vec![];
+    let mut delays = vec![];
+
+    for sample in 0..JITTER_SAMPLES {
+        if sample > 0 {
+            std::thread::sleep(JITTER_POLL_INTERVAL);
+        }
+
+        let (request, id) = NtpPacket::poll_message(Default::default());
+        let Some(packet) = conn.pester(request)? else {
+            continue;
+        };
+        if !packet.valid_server_response(id, false) || stratum(&packet) == Some(0) {
+            continue;
+        }
+
+        let Some(local) = conn.last_local_timestamps() else {
+            continue;
+        };
+        offsets.extend(clock_offset(&packet, local));
+        delays.extend(round_trip_delay(&packet, local));
+    }
+
+    if offsets.len() < 2 {
+        return fail_no_response(format!(
+            "Only {} of {JITTER_SAMPLES} polls were answered with a usable response",
+            offsets.len()
+        ));
+    }
+
+    let (mean, jitter) = mean_and_standard_deviation(&offsets);
+    let (mean_delay, _) = mean_and_standard_deviation(&delays);
+    if jitter > MAX_OFFSET_JITTER_SECONDS {
+        return fail_no_response(format!(
+            "Clock offset jitter of {:.3}ms over {} polls exceeds {:.3}ms (mean offset {:.3}ms, mean delay {:.3}ms)",
+            jitter * 1000.0,
+            offsets.len(),
+            MAX_OFFSET_JITTER_SECONDS * 1000.0,
+            mean * 1000.0,
+            mean_delay * 1000.0,
+        ));
+    }
+
+    PASS
+}
+
+/// The mean and population standard deviation of `values`
+fn mean_and_standard_deviation(values: &[f64]) -> (f64, f64) {
+    if values.is_empty() {
+        return (0.0, 0.0);
+    }
+
+    let count = values.len() as f64;
+    let mean = values.iter().sum::<f64>() / count;
+    let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / count;
+
+    (mean, variance.sqrt())
+}
+
+/// Number of polls sampled by [`test_stratum_is_stable`]
+const STRATUM_SAMPLES: usize = 6;
+
+/// Pause between the polls of [`test_stratum_is_stable`]
+const STRATUM_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Largest change of the stratum between consecutive polls accepted by [`test_stratum_is_stable`]
+pub const MAX_STRATUM_STEP: u8 = 1;
+
+/// Stratum of an unsynchronized server, see [RFC5905](https://datatracker.ietf.org/doc/html/rfc5905#section-7.3)
+///
+/// On the wire it is sent as stratum 0 with leap indicator 3.
+const UNSYNCHRONIZED_STRATUM: u8 = 16;
+
+/// Check that the stratum does not flap between back-to-back polls
+///
+/// The stratum may change when the server selects a different upstream, but only by [`MAX_STRATUM_STEP`] at a time.
+/// Becoming unsynchronized (stratum 16) or recovering from it within a few seconds points at unstable source selection,
+/// which confuses clients. Kiss-o'-Death responses with a known kiss code are ignored.
+pub fn test_stratum_is_stable(conn: &mut UdpConnection) -> TestResult {
+    let mut strata = vec![];
+
+    for sample in 0..STRATUM_SAMPLES {
+        if sample > 0 {
+            std::thread::sleep(STRATUM_POLL_INTERVAL);
+        }
+
+        let (request, id) = NtpPacket::poll_message(Default::default());
+        let Some(packet) = conn.pester(request)? else {
+            continue;
+        };
+        if !packet.valid_server_response(id, false)
+            || packet.is_kiss_rate()
+            || packet.is_kiss_deny()
+            || packet.is_kiss_rstr()
+            || packet.is_kiss_ntsn()
+        {
+            continue;
+        }
+        match (stratum(&packet), leap(&packet)) {
+            (Some(0), Some(NtpLeapIndicator::Unknown)) => strata.push(UNSYNCHRONIZED_STRATUM),
+            (Some(0), _) | (None, _) => continue,
+            (Some(stratum), _) => strata.push(stratum),
+        }
+    }
+    tracing::info!(?strata, "observed strata");
+
+    if strata.len() < 2 {
+        return fail_no_response(format!(
+            "Only {} of {STRATUM_SAMPLES} polls were answered with a usable response",
+            strata.len()
+        ));
+    }
+
+    let unsynchronized = strata
+        .iter()
+        .filter(|&&s| s == UNSYNCHRONIZED_STRATUM)
+        .count();
+    if unsynchronized > 0 && unsynchronized < strata.len() {
+        return fail_no_response(format!(
+            "Server flapped to or from stratum {UNSYNCHRONIZED_STRATUM} between polls: {strata:?}"
+        ));
+    }
+    if strata
+        .windows(2)
+        .any(|pair| pair[0].abs_diff(pair[1]) > MAX_STRATUM_STEP)
+    {
+        return fail_no_response(format!(
+            "Stratum changed by more than {MAX_STRATUM_STEP} between polls: {strata:?}"
+        ));
+    }
+
+    PASS
+}
+
+/// Source port used by [`test_responds_to_fixed_source_port`]
+const FIXED_SOURCE_PORT: u16 = 51123;
+
+/// Check that the server answers requests from a specific, non-ephemeral source port
+///
+/// Servers have to reply to whatever port a request came from. Requests from port 0 can not be sent, so this uses a
+/// fixed high port instead of the one picked by the operating system.
+pub fn test_responds_to_fixed_source_port(conn: &mut UdpConnection) -> TestResult {
+    let peer = conn.peer_addr()?;
+    let bind = SocketAddr::new(unspecified_ip(peer), FIXED_SOURCE_PORT);
+    let mut fixed = conn.reopen_from(bind)?;
+
+    let (request, id) = NtpPacket::poll_message(Default::default());
+    let response = fixed.pester_retry(request, 3)?;
+
+    let packet = pester_assert_response!(response);
+    pester_assert!(
+        packet,
+        packet.valid_server_response(id, false),
+        "Invalid response to a request from source port {FIXED_SOURCE_PORT}"
+    );
+
+    PASS
+}
+
+/// Check that a request to another port of the server is not answered from the NTP port
+///
+/// The request is sent from a fresh, unconnected socket so a reply from any port of the server is seen. Only a reply
+/// from the port the request was sent to is acceptable, e.g. when some other service happens to listen there.
+pub fn test_no_response_from_other_port(conn: &mut UdpConnection) -> TestResult {
+    let peer = conn.peer_addr()?;
+    let wrong = SocketAddr::new(peer.ip(), peer.port().wrapping_add(1));
+
+    let probe = UdpProbe::new(peer, conn.timeout())?;
+    let (request, _id) = NtpPacket::poll_message(Default::default());
+    probe.send_to(&UdpRequest::from(request), wrong)?;
+
+    while let Some((from, response)) = probe.recv_from()? {
+        if from.ip() == peer.ip() && from != wrong {
+            return fail(
+                format!("Request sent to {wrong} was answered from {from}"),
+                response,
+            );
+        }
+    }
+
+    PASS
+}
+
+/// Check that the server answers from the address and port the request was sent to
+///
+/// Clients using a connected socket never see a reply from another source, and one that does accept it can be fooled
+/// by spoofed responses. The request is sent from an unconnected socket so a reply from any source is seen. Only run
+/// with `--check-source`.
+pub fn test_responds_from_queried_address(conn: &mut UdpConnection) -> TestResult {
+    let peer = conn.peer_addr()?;
+    let probe = UdpProbe::new(peer, conn.timeout())?;
+    let (request, _id) = NtpPacket::poll_message(Default::default());
+
+    let Some((from, response)) = probe.pester_raw_unconnected(&UdpRequest::from(request), peer)?
+    else {
+        return fail_no_response(format!(
+            "No response from any address to a request sent to {peer}"
+        ));
+    };
+    if from != peer {
+        return fail(
+            format!("Request sent to {peer} was answered from {from}"),
+            response,
+        );
+    }
+
+    PASS
+}
+
+/// The forged source of [`test_survives_spoofed_request`], from TEST-NET-1 of
+/// [RFC5737](https://datatracker.ietf.org/doc/html/rfc5737) so responses do not reach anyone
+const SPOOFED_SOURCE: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 51123);
+
+/// Check that the server keeps running after a request with a forged source address
+///
+/// The response goes to the forged address and can not be observed, so this only sends the request. Whether the server
+/// still answers afterwards is checked by [`udp_test`](crate::udp::udp_test) like for every other test. Only run with
+/// `--allow-raw`, and skipped without the privileges to open a raw socket or for IPv6.
+pub fn test_survives_spoofed_request(conn: &mut UdpConnection) -> TestResult {
+    let SocketAddr::V4(peer) = conn.peer_addr()? else {
+        return Err(TestError::Skipped);
+    };
+
+    let sender = match RawSender::new() {
+        Ok(sender) => sender,
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+            tracing::warn!(error = ?e, "could not open raw socket, skipping");
+            return Err(TestError::Skipped);
+        }
+        Err(e) => {
+            return Err(anyhow::Error::new(e)
+                .context("Could not open raw socket")
+                .into())
+        }
+    };
+
+    let (request, _id) = NtpPacket::poll_message(Default::default());
+    sender
+        .send_spoofed(SPOOFED_SOURCE, peer, &UdpRequest::from(request).0)
+        .with_context(|| format!("Could not send request from {SPOOFED_SOURCE}"))?;
+
+    PASS
+}
+
+/// The port NTP servers listen on, which classic implementations also send from
+const NTP_PORT: u16 = 123;
+
+/// Check that the server answers requests sent from port 123, like classic symmetric NTP implementations do
+///
+/// Some hardened servers only answer requests from port 123, others refuse exactly those. The ephemeral port case is
+/// covered by every other test. Binding port 123 needs privileges, so this only runs with `--source-port-123` and is
+/// skipped if the port can not be bound.
+pub fn test_responds_to_source_port_123(conn: &mut UdpConnection) -> TestResult {
+    let peer = conn.peer_addr()?;
+    let bind = SocketAddr::new(unspecified_ip(peer), NTP_PORT);
+    let mut privileged = match conn.reopen_from(bind) {
+        Ok(privileged) => privileged,
+        Err(TestError::Error(e)) if is_unavailable_port(&e) => {
+            tracing::warn!(error = ?e, "could not bind source port {NTP_PORT}, skipping");
+            return Err(TestError::Skipped);
+        }
+        Err(e) => return Err(e),
+    };
+
+    let (request, id) = NtpPacket::poll_message(Default::default());
+    let Some(packet) = privileged.pester_retry(request, 3)? else {
+        return fail_no_response(format!(
+            "Server ignores requests from source port {NTP_PORT}, but answers those from ephemeral ports"
+        ));
+    };
+    pester_assert!(
+        packet,
+        packet.valid_server_response(id, false),
+        "Invalid response to a request from source port {NTP_PORT}"
+    );
+
+    PASS
+}
+
+/// Whether `error` means a port could not be bound because of missing privileges or another process using it
+fn is_unavailable_port(error: &anyhow::Error) -> bool {
+    error.downcast_ref::<io::Error>().is_some_and(|e| {
+        matches!(
+            e.kind(),
+            io::ErrorKind::PermissionDenied | io::ErrorKind::AddrInUse
+        )
+    })
+}
+
+/// The unspecified address of the same family as `addr`, to bind a socket that can reach it
+fn unspecified_ip(addr: SocketAddr) -> IpAddr {
+    match addr {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    }
+}
+
+/// Check that requests with the invalid version number 0 are ignored
+///
+/// No NTP version 0 was ever specified, so there is no format to answer in.
+pub fn test_ignores_version_0(conn: &mut UdpConnection) -> TestResult {
+    let response = conn.pester_raw(UdpRequest::poll_with_version(0))?;
+    pester_assert_no_response!(response, "Should not respond to version 0 requests");
+
+    PASS
+}
+
+/// Check that empty and single byte datagrams are ignored
+///
+/// These are too short to contain any NTP header, a server answering them reads beyond the received data. The single
+/// byte is the first byte of a NTPv4 client request, so only the length gives it away.
+pub fn test_ignores_truncated_datagrams(conn: &mut UdpConnection) -> TestResult {
+    let response = conn.pester_raw(UdpRequest(vec![]))?;
+    pester_assert_no_response!(response, "Should not respond to an empty datagram");
+
+    let first = UdpRequest::poll_with_version(4).0[0];
+    let response = conn.pester_raw(UdpRequest(vec![first]))?;
+    pester_assert_no_response!(response, "Should not respond to a single byte datagram");
+
+    PASS
+}
+
+/// The legacy versions a server may still answer, see
+/// [RFC5905 section 1](https://datatracker.ietf.org/doc/html/rfc5905#section-1)
+const LEGACY_VERSIONS: [u8; 3] = [1, 2, 3];
+
+/// Check that requests of the legacy versions 1, 2 and 3 are either ignored, or answered in the same version
+///
+/// Supporting these versions is optional. A server answering one of them has to reply in server mode, with the
+/// version of the request, and with the transmit timestamp of the request as origin timestamp.
+pub fn test_legacy_versions_are_answered_consistently(conn: &mut UdpConnection) -> TestResult {
+    for version in LEGACY_VERSIONS {
+        let request = UdpRequest::poll_with_version(version);
+        let transmit = request.0[40..48].to_vec();
+
+        let Some(response) = conn.pester_raw(request)? else {
+            continue;
+        };
+
+        if response.len() < 48 {
+            return fail(
+                format!("Response to version {version} request is too short"),
+                response,
+            );
+        }
+
+        let response_version = (response.0[0] >> 3) & 0b111;
+        let response_mode = response.0[0] & 0b111;
+        if response_version != version {
+            return fail(
+                format!("Version {version} request was answered with version {response_version}"),
+                response,
+            );
+        }
+        if response_mode != association_mode_bits(NtpAssociationMode::Server) {
+            return fail(
+                format!("Version {version} request was answered in mode {response_mode}"),
+                response,
+            );
+        }
+        if response.0[24..32] != transmit[..] {
+            return fail(
+                format!("Response to version {version} request has an incorrect origin timestamp"),
+                response,
+            );
+        }
+    }
+
+    PASS
+}
+
 /// Check that unknown versions are ignore
 ///
 /// Since NTPv5 is not released yet any compliant server should still ignore
-/// packets with this version number.
+/// packets with this version number. Servers implementing the draft are tested
+/// with `--ntpv5` instead, which replaces this test.
 pub fn test_ignores_version_5(conn: &mut UdpConnection) -> TestResult {
     let (packet, _id) = NtpPacket::poll_message_v5(Default::default());
     let response = conn.pester(packet)?;
@@ -55,3 +889,194 @@ pub fn test_ignores_version_5(conn: &mut UdpConnection) -> TestResult {
 
     PASS
 }
+
+/// Upper bound for the age of the reference timestamp relative to the transmit timestamp
+pub const MAX_REFERENCE_AGE_SECONDS: f64 = 24.0 * 60.0 * 60.0;
+
+/// Time between the two polls of [`test_reference_timestamp_is_monotonic`]
+const REFERENCE_POLL_INTERVAL: Duration = Duration::from_millis(300);
+
+/// Check that the reference timestamp never moves backwards and is reasonably recent
+///
+/// The reference timestamp is the time the server clock was last set or corrected, so it can only advance. An
+/// unsynchronized server has no meaningful reference timestamp, the test is skipped in that case.
+pub fn test_reference_timestamp_is_monotonic(conn: &mut UdpConnection) -> TestResult {
+    let (request, id) = NtpPacket::poll_message(Default::default());
+    let first = pester_assert_response!(conn.pester(request)?);
+    pester_assert!(
+        first,
+        first.valid_server_response(id, false),
+        "First response not matching original packet"
+    );
+    let first_header = pester_assert_version!(first, first, V4);
+
+    if !(1..=15).contains(&first_header.stratum) {
+        return Err(TestError::Skipped);
+    }
+
+    std::thread::sleep(REFERENCE_POLL_INTERVAL);
+
+    let (request, id) = NtpPacket::poll_message(Default::default());
+    let second = pester_assert_response!(conn.pester(request)?);
+    pester_assert!(
+        second,
+        second.valid_server_response(id, false),
+        "Second response not matching original packet"
+    );
+    let Some(second_reference) = reference_timestamp(&second) else {
+        return fail(
+            format!(
+                "Server replied with version {} instead of V4",
+                second.version()
+            ),
+            second,
+        );
+    };
+
+    let advance = seconds_between(first_header.reference_timestamp, second_reference);
+    pester_assert!(
+        second,
+        advance >= 0.0,
+        "Reference timestamp moved backwards by {:.6}s",
+        -advance
+    );
+
+    let age = seconds_between(
+        first_header.reference_timestamp,
+        first_header.transmit_timestamp,
+    );
+    pester_assert!(
+        first,
+        (0.0..=MAX_REFERENCE_AGE_SECONDS).contains(&age),
+        "Reference timestamp is {age:.3}s before the transmit timestamp"
+    );
+
+    PASS
+}
+
+/// Number of polls sent by [`test_transmit_timestamp_advances`]
+const TRANSMIT_TIMESTAMP_POLLS: usize = 10;
+
+/// Check that the transmit timestamp never moves backwards and actually advances under rapid polling
+///
+/// This catches servers with a frozen clock, which keep handing out the same time.
+pub fn test_transmit_timestamp_advances(conn: &mut UdpConnection) -> TestResult {
+    let mut timestamps: Vec<NtpTimestamp> = Vec::with_capacity(TRANSMIT_TIMESTAMP_POLLS);
+
+    for _ in 0..TRANSMIT_TIMESTAMP_POLLS {
+        let (request, id) = NtpPacket::poll_message(Default::default());
+        let packet = pester_assert_response!(conn.pester(request)?);
+        pester_assert!(
+            packet,
+            packet.valid_server_response(id, false),
+            "Server response not matching original packet"
+        );
+        let header = pester_assert_version!(packet, packet, V4);
+
+        let moved_backwards = timestamps
+            .last()
+            .is_some_and(|previous| header.transmit_timestamp.as_u64() < previous.as_u64());
+        timestamps.push(header.transmit_timestamp);
+        pester_assert!(
+            packet,
+            !moved_backwards,
+            "Transmit timestamp moved backwards, timestamps so far: {timestamps:?}"
+        );
+    }
+
+    let first = timestamps[0].as_u64();
+    if timestamps
+        .iter()
+        .all(|timestamp| timestamp.as_u64() == first)
+    {
+        return fail_no_response(format!(
+            "Transmit timestamp did not advance over {TRANSMIT_TIMESTAMP_POLLS} polls: {timestamps:?}"
+        ));
+    }
+
+    PASS
+}
+
+/// Number of polls sent at once by [`test_pipelined_polls_are_matched`]
+const PIPELINED_POLLS: usize = 4;
+
+/// Check that a burst of polls sent without waiting is answered with responses that each match their own request
+///
+/// Rate limiting servers may drop part of the burst or answer with a `RATE` kiss code, but at least one poll has to
+/// be answered.
+pub fn test_pipelined_polls_are_matched(conn: &mut UdpConnection) -> TestResult {
+    let (requests, ids): (Vec<_>, Vec<_>) = (0..PIPELINED_POLLS)
+        .map(|_| NtpPacket::poll_message(Default::default()))
+        .unzip();
+    let responses = conn.pester_many(requests)?;
+
+    if responses.iter().all(Option::is_none) {
+        return fail_no_response(format!(
+            "None of {PIPELINED_POLLS} pipelined polls was answered"
+        ));
+    }
+
+    for (index, (packet, id)) in responses.into_iter().zip(ids).enumerate() {
+        let Some(packet) = packet else { continue };
+        if packet.is_kiss_rate() {
+            continue;
+        }
+        pester_assert!(
+            packet,
+            packet.valid_server_response(id, false),
+            "Response to pipelined poll {index} not matching its request"
+        );
+    }
+
+    PASS
+}
+
+/// Check that the server answers a poll with the smallest allowed poll interval
+pub fn test_minimum_poll_interval(conn: &mut UdpConnection) -> TestResult {
+    poll_with_exponent(conn, MIN_POLL_EXPONENT)
+}
+
+/// Check that the server answers a poll with the largest allowed poll interval
+pub fn test_maximum_poll_interval(conn: &mut UdpConnection) -> TestResult {
+    poll_with_exponent(conn, MAX_POLL_EXPONENT)
+}
+
+fn poll_with_exponent(conn: &mut UdpConnection, exponent: i8) -> TestResult {
+    let (request, id) = NtpPacket::poll_message(poll_interval(exponent));
+    let packet = pester_assert_response!(conn.pester(request)?);
+    pester_assert_version!(packet, packet, V4);
+
+    pester_assert!(
+        packet,
+        packet.valid_server_response(id, false),
+        "Server response to poll exponent {exponent} not matching original packet"
+    );
+
+    PASS
+}
+
+/// Check that the server handles poll exponents outside the allowed range
+///
+/// The server may ignore such requests, but if it answers the poll exponent in the response has to be clamped to the
+/// range allowed by [RFC5905 section 7.3](https://datatracker.ietf.org/doc/html/rfc5905#section-7.3).
+pub fn test_out_of_range_poll_interval(conn: &mut UdpConnection) -> TestResult {
+    for exponent in [MIN_POLL_EXPONENT - 5, MAX_POLL_EXPONENT + 10] {
+        let (request, _id) = NtpPacket::poll_message(Default::default());
+        let request = UdpRequest::from(request).with_poll_exponent(exponent);
+
+        let Some(response) = conn.pester_raw(request)? else {
+            continue;
+        };
+        let packet = response.parse()?;
+        let header = pester_assert_version!(packet, packet, V4);
+
+        pester_assert!(
+            packet,
+            (MIN_POLL_EXPONENT..=MAX_POLL_EXPONENT).contains(&header.poll.as_log()),
+            "Server replied to poll exponent {exponent} with out of range poll exponent {}",
+            header.poll.as_log()
+        );
+    }
+
+    PASS
+}
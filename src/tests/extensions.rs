@@ -1,7 +1,7 @@
 //! Tests that exercise the extension field mechanism described in [RFC5905 section 7.5](https://datatracker.ietf.org/doc/html/rfc5905#section-7.5)
 
 use crate::macros::*;
-use crate::udp::UdpConnection;
+use crate::udp::{UdpConnection, UdpRequest};
 use crate::util::result::{fail, TestResult, PASS};
 use anyhow::anyhow;
 use ntp_proto::{ExtensionField, NtpPacket};
@@ -87,3 +87,144 @@ pub fn test_unique_id_is_returned(conn: &mut UdpConnection) -> TestResult {
 
     PASS
 }
+
+/// Test if a server handles a request with a duplicated unique id field sanely
+///
+/// The server may ignore such a request, or reply with a single unique id field, but should never reflect both
+/// copies back.
+pub fn test_duplicate_unique_id_is_not_reflected(conn: &mut UdpConnection) -> TestResult {
+    let (mut request, _id) = NtpPacket::poll_message(Default::default());
+    let uid = ExtensionField::UniqueIdentifier(Cow::Owned(
+        array::from_fn::<_, 32, _>(|i| i as u8).to_vec(),
+    ));
+    request.push_additional(uid.clone());
+    request.push_additional(uid.clone());
+
+    let Some(packet) = conn.pester(request)? else {
+        return PASS;
+    };
+
+    let uids: Vec<_> = packet
+        .untrusted_extension_fields()
+        .filter(|ef| matches!(ef, ExtensionField::UniqueIdentifier(_)))
+        .collect();
+    pester_assert_lt!(
+        packet,
+        uids.len(),
+        2,
+        "Server reflected {} unique id EFs for a request with a duplicated unique id",
+        uids.len()
+    );
+
+    if let Some(&echoed) = uids.first() {
+        pester_assert_eq!(packet, echoed, &uid, "Response UID does not match request");
+    }
+
+    PASS
+}
+
+/// Type of the unique identifier extension field, see [RFC8915 section 5.3](https://datatracker.ietf.org/doc/html/rfc8915#section-5.3)
+pub(crate) const UNIQUE_IDENTIFIER_TYPE: u16 = 0x0104;
+
+/// Build the raw bytes of a (possibly malformed) extension field
+///
+/// The length field covers the 4 byte header and a body of `body_len` bytes, without any padding. Unlike
+/// [`ExtensionField`] this allows fields that violate the length and padding rules of
+/// [RFC7822](https://datatracker.ietf.org/doc/html/rfc7822#section-3).
+fn raw_extension_field(type_id: u16, body_len: u16) -> Vec<u8> {
+    raw_extension_field_declaring(type_id, 4 + body_len, body_len)
+}
+
+/// Build the raw bytes of an extension field whose length field says `declared_len`, but has `body_len` body bytes
+fn raw_extension_field_declaring(type_id: u16, declared_len: u16, body_len: u16) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(4 + body_len as usize);
+    bytes.extend_from_slice(&type_id.to_be_bytes());
+    bytes.extend_from_slice(&declared_len.to_be_bytes());
+    bytes.extend((0..body_len).map(|i| 0xa0 | (i as u8 & 0x0f)));
+
+    bytes
+}
+
+/// Test if a server rejects extension fields violating the length rules of RFC7822
+///
+/// Extension fields have to be padded to a multiple of 4 bytes, and the last one in a packet without MAC has to be at
+/// least 28 bytes long, see
+/// [RFC7822 section 3](https://datatracker.ietf.org/doc/html/rfc7822#section-3). The server may ignore the request or
+/// the malformed field, but must not echo it.
+pub fn test_malformed_extension_fields_are_not_echoed(conn: &mut UdpConnection) -> TestResult {
+    for (description, field) in [
+        ("too short", raw_extension_field(UNIQUE_IDENTIFIER_TYPE, 8)),
+        ("unpadded", raw_extension_field(UNIQUE_IDENTIFIER_TYPE, 29)),
+    ] {
+        let (request, _id) = NtpPacket::poll_message(Default::default());
+        let mut request = UdpRequest::from(request);
+        request.0.extend_from_slice(&field);
+
+        let Some(response) = conn.pester_raw(request)? else {
+            continue;
+        };
+
+        let body = &field[4..];
+        if response.0.windows(body.len()).any(|window| window == body) {
+            return fail(
+                format!("Server echoed a {description} extension field"),
+                response,
+            );
+        }
+    }
+
+    PASS
+}
+
+/// An extension field type that is not assigned by IANA, so no server understands it
+const UNASSIGNED_TYPE: u16 = 0xfff0;
+
+/// Test if a server checks the declared length of an extension field against the data it received
+///
+/// A well-formed field of an unknown type has to be ignored, with the request answered as usual. A field declaring
+/// more bytes than the packet holds makes the packet invalid, the server may drop it or answer it, but must not echo
+/// the field.
+pub fn test_extension_field_length_is_checked(conn: &mut UdpConnection) -> TestResult {
+    let (request, id) = NtpPacket::poll_message(Default::default());
+    let mut request = UdpRequest::from(request);
+    let valid = raw_extension_field(UNASSIGNED_TYPE, 28);
+    request.0.extend_from_slice(&valid);
+
+    let response = pester_assert_response!(conn.pester_raw(request)?);
+    if response
+        .0
+        .windows(valid.len() - 4)
+        .any(|window| window == &valid[4..])
+    {
+        return fail(
+            "Server echoed a well-formed unknown extension field",
+            response,
+        );
+    }
+    let packet = response.parse()?;
+    pester_assert!(
+        packet,
+        packet.valid_server_response(id, false),
+        "Server response to a request with a well-formed unknown extension field not matching the request"
+    );
+
+    let (request, _id) = NtpPacket::poll_message(Default::default());
+    let mut request = UdpRequest::from(request);
+    let overlong = raw_extension_field_declaring(UNASSIGNED_TYPE, 4 + 64, 28);
+    request.0.extend_from_slice(&overlong);
+
+    if let Some(response) = conn.pester_raw(request)? {
+        if response
+            .0
+            .windows(overlong.len() - 4)
+            .any(|window| window == &overlong[4..])
+        {
+            return fail(
+                "Server echoed an extension field declaring more bytes than it holds",
+                response,
+            );
+        }
+    }
+
+    PASS
+}
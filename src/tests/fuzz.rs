@@ -0,0 +1,192 @@
+//! Robustness tests that send randomly mutated packets
+//!
+//! These tests are only run when enabled with `--fuzz`. They only check that the server stays alive and does not
+//! reflect the garbage it receives, not whether it answers correctly.
+
+use crate::udp::{udp_server_still_alive, UdpRequest, UdpResponse};
+use crate::util::result::{fail_no_response, TestContext, TestResult, PASS};
+use crate::{TestCase, TestConfig};
+use ntp_proto::NtpPacket;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use std::panic::UnwindSafe;
+
+/// Settings for [`random_packets`]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct FuzzOptions {
+    /// Number of packets to send
+    pub count: u32,
+    /// Seed for the random mutations, running with the same seed sends the same packets
+    pub seed: u64,
+}
+
+/// Number of findings listed in the failure message
+const MAX_REPORTED_FINDINGS: usize = 5;
+
+/// Length of the shortest run of request bytes that counts as reflected when it shows up in a response
+///
+/// Longer than a timestamp, so a few random bytes that happen to match are not reported.
+const MIN_REFLECTED_LEN: usize = 16;
+
+/// Length of the NTP header, without extension fields
+const NTP_HEADER_LEN: usize = 48;
+
+/// Send randomly mutated poll requests, checking the server stays alive after each of them
+///
+/// Responses that are not valid NTP packets, or that reflect the request or parts of it, are collected as findings
+/// and reported once all packets were sent.
+pub fn random_packets(options: FuzzOptions) -> Box<dyn TestCase + UnwindSafe + Send> {
+    struct RandomPackets(FuzzOptions);
+
+    impl TestCase for RandomPackets {
+        fn name(&self) -> &'static str {
+            concat!(module_path!(), "::random_packets")
+        }
+
+        fn run(&self, conf: &TestConfig) -> TestResult {
+            let FuzzOptions { count, seed } = self.0;
+            let mut conn = conf.udp()?;
+            let mut findings = vec![];
+
+            for index in 0..count {
+                let request = mutated_request(seed, index);
+                let sent = request.0.clone();
+
+                let response = conn
+                    .pester_raw(request)
+                    .test_context(|| format!("Sending random packet {index} (seed {seed})"))?;
+                if let Some(response) = response {
+                    if let Some(finding) = check_response(&sent, &response) {
+                        findings.push(format!("packet {index}: {finding}"));
+                    }
+                }
+
+                if let Err(e) = udp_server_still_alive(&mut conn, None) {
+                    return fail_no_response(format!(
+                        "Server stopped responding after random packet {index} (seed {seed}): {e}"
+                    ));
+                }
+            }
+
+            if findings.is_empty() {
+                return PASS;
+            }
+
+            let reported = findings.len().min(MAX_REPORTED_FINDINGS);
+            fail_no_response(format!(
+                "{} suspicious responses to {count} random packets (seed {seed}), the first {reported}:\n{}",
+                findings.len(),
+                findings[..reported].join("\n")
+            ))
+        }
+    }
+
+    Box::new(RandomPackets(options))
+}
+
+/// The `index`th random request for `seed`
+///
+/// Every request gets its own RNG, so a single packet can be reproduced without replaying all previous ones. Unlike
+/// `StdRng`, ChaCha8 produces the same packets in every version of rand.
+fn mutated_request(seed: u64, index: u32) -> UdpRequest {
+    let mut rng = ChaCha8Rng::seed_from_u64(seed ^ (index as u64).rotate_left(32));
+    let (request, _id) = NtpPacket::poll_message(Default::default());
+    let mut bytes = UdpRequest::from(request).0;
+    // The transmit timestamp of a poll message is random as well
+    rng.fill(&mut bytes[40..NTP_HEADER_LEN]);
+
+    for _ in 0..rng.gen_range(1..=4) {
+        match rng.gen_range(0..4) {
+            // Overwrite a single byte
+            0 | 1 => {
+                let position = rng.gen_range(0..bytes.len());
+                bytes[position] = rng.gen();
+            }
+            // Truncate the packet
+            2 => {
+                let length = rng.gen_range(0..bytes.len());
+                bytes.truncate(length);
+            }
+            // Append random trailing data
+            _ => {
+                let extra = rng.gen_range(1..=64);
+                bytes.extend((0..extra).map(|_| rng.gen::<u8>()));
+            }
+        }
+
+        if bytes.is_empty() {
+            bytes.push(rng.gen());
+        }
+    }
+
+    UdpRequest(bytes)
+}
+
+/// Describe what is suspicious about `response`, if anything
+fn check_response(sent: &[u8], response: &UdpResponse) -> Option<String> {
+    if response.is_empty() {
+        return None;
+    }
+
+    if response.0 == sent {
+        return Some(format!("request reflected as is {response:?}"));
+    }
+
+    if let Some(offset) = reflected_offset(sent, &response.0) {
+        return Some(format!(
+            "request bytes from offset {offset} reflected in {response:?}"
+        ));
+    }
+
+    match NtpPacket::try_from(response) {
+        Ok(_) => None,
+        Err(e) => Some(format!("unparsable response {response:?} ({e:?})")),
+    }
+}
+
+/// The offset of the first run of [`MIN_REFLECTED_LEN`] bytes after the header of `sent` that appears in `response`
+///
+/// The header is left out, as a response rightfully has one much like the request.
+fn reflected_offset(sent: &[u8], response: &[u8]) -> Option<usize> {
+    let position = sent
+        .get(NTP_HEADER_LEN..)?
+        .windows(MIN_REFLECTED_LEN)
+        .position(|run| {
+            response
+                .windows(MIN_REFLECTED_LEN)
+                .any(|candidate| candidate == run)
+        })?;
+
+    Some(NTP_HEADER_LEN + position)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mutated_requests_are_reproducible() {
+        assert_eq!(mutated_request(7, 3).0, mutated_request(7, 3).0);
+        assert_ne!(mutated_request(7, 3).0, mutated_request(7, 4).0);
+    }
+
+    #[test]
+    fn reflected_substring_is_found() {
+        let sent: Vec<u8> = (0..80).collect();
+        let response = [&[0u8; 48][..], &sent[50..70]].concat();
+
+        assert_eq!(reflected_offset(&sent, &response), Some(50));
+        let finding = check_response(&sent, &UdpResponse(response)).unwrap();
+        assert!(finding.starts_with("request bytes from offset 50 reflected"));
+    }
+
+    #[test]
+    fn header_is_not_a_reflection() {
+        let mut sent: Vec<u8> = (0..48).collect();
+        sent.extend([0xff; 8]);
+        let mut response = vec![0u8; 48];
+        response[..32].copy_from_slice(&sent[16..48]);
+
+        assert_eq!(reflected_offset(&sent, &response), None);
+    }
+}
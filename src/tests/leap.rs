@@ -0,0 +1,111 @@
+//! Diagnostics for the handling of a leap second
+//!
+//! These tests are only run when the time of an upcoming leap second is given with `--expect-leap`. Both announcing
+//! the leap second with the leap indicator and smearing it are valid strategies, so the test mostly reports which one
+//! the server appears to use.
+
+use crate::udp::{clock_offset, leap, stratum};
+use crate::util::result::{fail_no_response, TestError, TestResult, PASS};
+use crate::{TestCase, TestConfig};
+use ntp_proto::{NtpLeapIndicator, NtpPacket};
+use std::panic::UnwindSafe;
+use std::time::{Duration, SystemTime};
+
+/// How long before a leap second servers are expected to announce it
+///
+/// [RFC5905](https://datatracker.ietf.org/doc/html/rfc5905#section-7.3) does not say, but announcing during the last
+/// day before the event is common to all implementations.
+const LEAP_ANNOUNCE_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Number of polls sampled by [`leap_second_handling`]
+const LEAP_SAMPLES: usize = 4;
+
+/// Time between the polls of [`leap_second_handling`]
+const LEAP_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Mean clock offset above which a server that does not announce the leap second appears to be smearing it
+pub const SMEAR_OFFSET_SECONDS: f64 = 0.01;
+
+/// Poll the server shortly before the leap second at `event`, reporting how it handles it
+///
+/// A server setting leap indicator 1 (insert) or 2 (delete) announces the leap second, a server keeping leap indicator
+/// 0 while its clock drifts away from ours appears to smear it. Only a server changing its leap indicator between
+/// polls fails the test. Outside of the day before `event` the test is skipped.
+pub fn leap_second_handling(event: SystemTime) -> Box<dyn TestCase + UnwindSafe + Send> {
+    struct LeapSecondHandling(SystemTime);
+
+    impl TestCase for LeapSecondHandling {
+        fn name(&self) -> &'static str {
+            concat!(module_path!(), "::leap_second_handling")
+        }
+
+        fn run(&self, conf: &TestConfig) -> TestResult {
+            match self.0.duration_since(SystemTime::now()) {
+                Ok(remaining) if remaining <= LEAP_ANNOUNCE_WINDOW => {}
+                _ => {
+                    tracing::warn!("expected leap second is not within the next day, skipping");
+                    return Err(TestError::Skipped);
+                }
+            }
+
+            let mut conn = conf.udp()?;
+            let mut indicators = vec![];
+            let mut offsets = vec![];
+
+            for sample in 0..LEAP_SAMPLES {
+                if sample > 0 {
+                    std::thread::sleep(LEAP_POLL_INTERVAL);
+                }
+
+                let (request, id) = NtpPacket::poll_message(Default::default());
+                let Some(packet) = conn.pester(request)? else {
+                    continue;
+                };
+                if !packet.valid_server_response(id, false) || stratum(&packet) == Some(0) {
+                    continue;
+                }
+
+                indicators.extend(leap(&packet));
+                if let Some(local) = conn.last_local_timestamps() {
+                    offsets.extend(clock_offset(&packet, local));
+                }
+            }
+
+            let Some(&first) = indicators.first() else {
+                return fail_no_response(format!(
+                    "None of {LEAP_SAMPLES} polls were answered with a usable response"
+                ));
+            };
+            if indicators.iter().any(|&indicator| indicator != first) {
+                return fail_no_response(format!(
+                    "Server changed its leap indicator between polls shortly before a leap second: {indicators:?}"
+                ));
+            }
+
+            let mean_offset = offsets.iter().sum::<f64>() / offsets.len().max(1) as f64;
+            match first {
+                NtpLeapIndicator::Leap61 | NtpLeapIndicator::Leap59 => {
+                    tracing::info!(leap = ?first, "server announces the leap second");
+                }
+                NtpLeapIndicator::NoWarning if mean_offset.abs() > SMEAR_OFFSET_SECONDS => {
+                    tracing::info!(
+                        offset_ms = mean_offset * 1000.0,
+                        "server does not announce the leap second, and appears to smear it"
+                    );
+                }
+                NtpLeapIndicator::NoWarning => {
+                    tracing::info!(
+                        offset_ms = mean_offset * 1000.0,
+                        "server does not announce the leap second, and shows no sign of smearing yet"
+                    );
+                }
+                // An unsynchronized server says nothing about how it would handle the leap second
+                NtpLeapIndicator::Unknown => return Err(TestError::Skipped),
+            }
+
+            PASS
+        }
+    }
+
+    Box::new(LeapSecondHandling(event))
+}
@@ -1,33 +1,397 @@
 //! This module contains a collection of test cases
 //!
-//! Every test case is implemented as a function wrapped by one of [udp_test], [nts_test], or [ke_test]. This module is
-//! made public when the documentation is generated so that normal rust docstrings can be used the test cases.
+//! Every test case is implemented as a function wrapped by one of [udp_test], [nts_test], [ke_test], or
+//! [ke_server_test], and registered in [all_tests] with a one line description. This module is made public when the
+//! documentation is generated so that normal rust docstrings can be used the test cases.
 
 use crate::nts::nts_test;
-use crate::nts_ke::ke_test;
+use crate::nts_ke::{ke_server_test, ke_test, ke_udp_test};
 use crate::udp::udp_test;
-use crate::TestCase;
+use crate::{Category, TestCase, TestConfig, TestResult};
 use std::panic::UnwindSafe;
+use std::time::SystemTime;
 
 pub mod basic;
 pub mod extensions;
+pub mod fuzz;
+pub mod leap;
+pub mod modes;
 pub mod nts;
 pub mod nts_ke;
+pub mod v5;
 
-/// Generate a list of all currently implemented test cases
-pub fn all_tests() -> impl Iterator<Item = Box<dyn TestCase + UnwindSafe>> {
-    [
-        udp_test(basic::test_responds_to_version_4),
-        udp_test(basic::test_ignores_version_5),
-        udp_test(extensions::test_unknown_extensions_are_ignored),
-        udp_test(extensions::test_unique_id_is_returned),
-        nts_test(nts::happy),
-        ke_test(nts_ke::happy),
-        ke_test(nts_ke::error_on_unknown_next_protocol),
-        ke_test(nts_ke::ignore_unknown_extra_protocols),
-        ke_test(nts_ke::error_on_unknown_aead),
-        ke_test(nts_ke::ignore_unknown_extra_aead),
-        ke_test(nts_ke::empty_message_resolves_in_error),
-    ]
-    .into_iter()
+/// Optional parts of the test suite
+#[derive(Debug, Clone, Copy)]
+pub struct SuiteOptions {
+    /// Expect the server to implement the NTPv5 draft, instead of ignoring it
+    pub ntpv5: bool,
+    /// Send random packets, see [`fuzz::random_packets`]
+    pub fuzz: Option<fuzz::FuzzOptions>,
+    /// Send requests to the NTP multicast group, see [`modes::test_no_unicast_response_to_multicast`]
+    pub multicast: bool,
+    /// Send requests from port 123, see [`basic::test_responds_to_source_port_123`]
+    pub source_port_123: bool,
+    /// Send a key exchange request one byte at a time, see [`nts_ke::trickled_request`]
+    pub trickle: bool,
+    /// Require exactly this many cookies in [`nts_ke::happy`], instead of at least one
+    pub expected_cookies: Option<usize>,
+    /// The time of an upcoming leap second, see [`leap::leap_second_handling`]
+    pub expected_leap: Option<SystemTime>,
+    /// Receive responses on an unconnected socket, see [`basic::test_responds_from_queried_address`]
+    pub response_source: bool,
+    /// Send requests with a forged source over a raw socket, see [`basic::test_survives_spoofed_request`]
+    pub allow_raw: bool,
+    /// Sample the clock offset over a few seconds, see [`basic::test_offset_jitter_is_bounded`]
+    pub offset_jitter: bool,
+    /// Largest accepted ratio between response and request size, see [`basic::test_amplification_factor`]
+    pub max_amplification_factor: f64,
+    /// Largest accepted root delay and root dispersion in seconds, see
+    /// [`basic::test_root_delay_and_dispersion_are_bounded`]
+    pub max_root_distance: f64,
+}
+
+impl Default for SuiteOptions {
+    fn default() -> Self {
+        Self {
+            ntpv5: false,
+            fuzz: None,
+            multicast: false,
+            source_port_123: false,
+            trickle: false,
+            expected_cookies: None,
+            expected_leap: None,
+            response_source: false,
+            allow_raw: false,
+            offset_jitter: false,
+            max_amplification_factor: basic::DEFAULT_MAX_AMPLIFICATION_FACTOR,
+            max_root_distance: basic::DEFAULT_MAX_ROOT_DISTANCE_SECONDS,
+        }
+    }
+}
+
+type BoxedTest = Box<dyn TestCase + UnwindSafe + Send>;
+
+/// Registration helpers to attach metadata to a test, see [`TestCase::description`], [`TestCase::rfc`] and
+/// [`TestCase::tags`]
+trait Annotate {
+    /// Attach a one line description of what `self` checks
+    fn describe(self, description: &'static str) -> BoxedTest;
+
+    /// Attach a link to the section of the specification `self` checks
+    fn with_rfc(self, rfc: &'static str) -> BoxedTest;
+
+    /// Attach labels to select `self` by with `--tag`
+    fn with_tags(self, tags: &'static [&'static str]) -> BoxedTest;
+}
+
+impl Annotate for BoxedTest {
+    fn describe(self, description: &'static str) -> BoxedTest {
+        Box::new(Annotated {
+            test: self,
+            description: Some(description),
+            rfc: None,
+            tags: None,
+        })
+    }
+
+    fn with_rfc(self, rfc: &'static str) -> BoxedTest {
+        Box::new(Annotated {
+            test: self,
+            description: None,
+            rfc: Some(rfc),
+            tags: None,
+        })
+    }
+
+    fn with_tags(self, tags: &'static [&'static str]) -> BoxedTest {
+        Box::new(Annotated {
+            test: self,
+            description: None,
+            rfc: None,
+            tags: Some(tags),
+        })
+    }
+}
+
+/// A test with metadata overridden at registration, everything else is passed through
+struct Annotated {
+    test: BoxedTest,
+    description: Option<&'static str>,
+    rfc: Option<&'static str>,
+    tags: Option<&'static [&'static str]>,
+}
+
+impl TestCase for Annotated {
+    fn name(&self) -> &'static str {
+        self.test.name()
+    }
+
+    fn run(&self, conf: &TestConfig) -> TestResult {
+        self.test.run(conf)
+    }
+
+    fn category(&self) -> Category {
+        self.test.category()
+    }
+
+    fn description(&self) -> Option<&'static str> {
+        self.description.or_else(|| self.test.description())
+    }
+
+    fn rfc(&self) -> Option<&'static str> {
+        self.rfc.or_else(|| self.test.rfc())
+    }
+
+    fn tags(&self) -> &'static [&'static str] {
+        self.tags.unwrap_or_else(|| self.test.tags())
+    }
+}
+
+/// Generate a list of all currently implemented test cases, including the optional ones enabled in `options`
+pub fn all_tests(options: &SuiteOptions) -> impl Iterator<Item = BoxedTest> {
+    let mut tests =
+        vec![udp_test(basic::test_responds_to_version_4).describe("Answers a NTPv4 poll request")];
+
+    if options.ntpv5 {
+        tests.extend([
+            udp_test(v5::test_responds_to_version_5)
+                .describe("Answers a NTPv5 poll request with NTPv5"),
+            udp_test(v5::test_timescale_and_era)
+                .describe("Answers in the requested timescale and the current era"),
+            udp_test(v5::test_draft_identification_is_echoed)
+                .describe("Echoes the draft identification extension field"),
+            udp_test(v5::test_timestamps_are_era_aware)
+                .describe("Sends NTPv5 timestamps near the local time in the right era"),
+            udp_test(v5::test_timescale_is_utc_or_tai)
+                .describe("Answers a request for TAI in UTC, or in TAI with the current offset"),
+        ]);
+    } else {
+        tests.push(udp_test(basic::test_ignores_version_5).describe("Ignores NTPv5 requests"));
+    }
+
+    tests.extend([
+        udp_test(basic::test_ignores_version_0).describe("Ignores requests with version 0"),
+        udp_test(basic::test_ignores_truncated_datagrams)
+            .describe("Ignores empty and single byte datagrams")
+            .with_tags(&["robustness"]),
+        udp_test(basic::test_legacy_versions_are_answered_consistently)
+            .describe("Ignores NTPv1 to NTPv3 requests, or answers them in the same version")
+            .with_rfc("https://datatracker.ietf.org/doc/html/rfc5905#section-1"),
+        basic::test_root_delay_and_dispersion_are_bounded(options.max_root_distance)
+            .describe("The root delay and root dispersion are plausible"),
+        udp_test(basic::test_root_dispersion_evolves_plausibly)
+            .describe("The root dispersion only shrinks when the server clock is updated"),
+        udp_test(basic::test_zero_transmit_timestamp_is_echoed)
+            .describe("A zero transmit timestamp is copied into the origin timestamp"),
+        udp_test(basic::test_consecutive_timestamps_are_consistent)
+            .describe("The timestamps of two consecutive polls are consistent"),
+        basic::test_amplification_factor(options.max_amplification_factor)
+            .describe("Does not reply with more bytes than it received")
+            .with_tags(&["security", "amplification"]),
+        udp_test(basic::test_stratum_matches_leap_indicator)
+            .describe("The stratum and leap indicator agree on whether the server is synchronized")
+            .with_rfc("https://datatracker.ietf.org/doc/html/rfc5905#section-7.3"),
+        udp_test(basic::test_reference_id_is_not_reflected)
+            .describe("Fills in its own reference id instead of reflecting the one of the client")
+            .with_tags(&["security"]),
+        udp_test(basic::test_all_fields_maximal)
+            .describe("Drops or sanely answers a request with every header field at its maximum")
+            .with_tags(&["robustness"]),
+        udp_test(basic::test_bogus_mac_is_not_reflected)
+            .describe("Does not reflect the MAC of a request signed with an unknown key")
+            .with_rfc("https://datatracker.ietf.org/doc/html/rfc5905#section-7.3")
+            .with_tags(&["security"]),
+        udp_test(basic::test_stratum_is_stable)
+            .describe("The stratum does not flap between repeated polls"),
+        udp_test(basic::test_responds_to_fixed_source_port)
+            .describe("Answers requests from a fixed, non-ephemeral source port"),
+        udp_test(basic::test_no_response_from_other_port)
+            .describe("Does not answer a request sent to another port from its NTP port")
+            .with_tags(&["security"]),
+        udp_test(basic::test_reference_timestamp_is_monotonic)
+            .describe("The reference timestamp never moves backwards and is reasonably recent"),
+        udp_test(basic::test_transmit_timestamp_advances)
+            .describe("The transmit timestamp advances under rapid polling"),
+        udp_test(basic::test_pipelined_polls_are_matched)
+            .describe("Answers a burst of polls, matching every response to its request"),
+        udp_test(basic::test_minimum_poll_interval)
+            .describe("Answers a poll with the smallest allowed poll interval"),
+        udp_test(basic::test_maximum_poll_interval)
+            .describe("Answers a poll with the largest allowed poll interval"),
+        udp_test(basic::test_out_of_range_poll_interval)
+            .describe("Handles poll exponents outside the allowed range")
+            .with_tags(&["robustness"]),
+        udp_test(extensions::test_unknown_extensions_are_ignored)
+            .describe("Ignores invalid extensions")
+            .with_tags(&["robustness"]),
+        udp_test(extensions::test_unique_id_is_returned)
+            .describe("Returns a unique id field as is, even without NTS"),
+        udp_test(extensions::test_duplicate_unique_id_is_not_reflected)
+            .describe("Handles a request with a duplicated unique id field sanely")
+            .with_tags(&["security"]),
+        udp_test(extensions::test_extension_field_length_is_checked)
+            .describe("Checks the declared length of extension fields against the received data")
+            .with_tags(&["robustness"]),
+        udp_test(extensions::test_malformed_extension_fields_are_not_echoed)
+            .describe("Rejects extension fields violating the length rules of RFC7822")
+            .with_tags(&["security"]),
+        udp_test(modes::test_symmetric_active_is_not_answered_as_server)
+            .describe("A symmetric active request is not answered by a server mode response"),
+        udp_test(modes::test_ignores_server_mode_requests)
+            .describe("A request claiming to be sent by a server (mode 4) is not answered"),
+        udp_test(modes::test_ignores_control_and_private_modes)
+            .describe("Does not answer legacy mode 6 (control) and mode 7 (private) requests")
+            .with_rfc("https://datatracker.ietf.org/doc/html/rfc5905#section-3")
+            .with_tags(&["security", "amplification"]),
+        nts_test(nts::happy).describe("Responds to a normal NTS request"),
+        nts_test(nts::origin_timestamp_is_copied)
+            .describe("Copies the transmit timestamp of the request into the origin timestamp"),
+        nts_test(nts::cookie_rotation).describe(
+            "Supplies fresh cookies with every response, so a client never has to reuse one",
+        ),
+        nts_test(nts::rejects_forged_cookie)
+            .describe("Does not accept a forged cookie")
+            .with_rfc("https://datatracker.ietf.org/doc/html/rfc8915#section-5.7")
+            .with_tags(&["security"]),
+        nts_test(nts::response_is_authenticated)
+            .describe(
+                "Protects its responses to NTS requests with an NTS Authenticator extension field",
+            )
+            .with_rfc("https://datatracker.ietf.org/doc/html/rfc8915#section-5.7")
+            .with_tags(&["security"]),
+        nts_test(nts::unique_id_is_authenticated)
+            .describe("Returns the unique identifier of an NTS request in the authenticated part")
+            .with_rfc("https://datatracker.ietf.org/doc/html/rfc8915#section-5.3")
+            .with_tags(&["security"]),
+        nts_test(nts::replayed_request)
+            .describe("Ignores, refuses, or consistently answers a replayed NTS request")
+            .with_tags(&["security"]),
+        nts_test(nts::cookie_placeholders)
+            .describe("Sends one extra cookie for every NTS Cookie Placeholder extension field"),
+        nts_ke::happy(options.expected_cookies)
+            .describe("Responds to a valid request with a valid response"),
+        ke_test(nts_ke::new_cookie_is_not_critical)
+            .describe("Does not set the critical bit on New Cookie records")
+            .with_rfc("https://datatracker.ietf.org/doc/html/rfc8915#section-4.1.6"),
+        ke_test(nts_ke::critical_bits_are_correct)
+            .describe("Sets the critical bit of every record as specified for its type")
+            .with_rfc("https://datatracker.ietf.org/doc/html/rfc8915#section-4.1"),
+        ke_test(nts_ke::no_spurious_warnings)
+            .describe("Does not send warnings in response to a normal request"),
+        ke_test(nts_ke::closes_after_end_of_message)
+            .describe("Closes the connection after sending EndOfMessage"),
+        ke_test(nts_ke::cookies_are_sane).describe(
+            "The cookies returned by the server are non-empty, reasonably sized, and unique",
+        ),
+        ke_test(nts_ke::cookie_sizes_are_uniform)
+            .describe("All cookies of a response have the same size")
+            .with_tags(&["security"]),
+        ke_test(nts_ke::cookie_count_is_bounded)
+            .describe("Caps the number of cookies and the size of a single response"),
+        ke_test(nts_ke::shuffled_request)
+            .describe("The order of records in the request does not matter"),
+        ke_test(nts_ke::server_port_negotiation)
+            .describe("Handles NTPv4 Server and Port Negotiation records"),
+        nts_ke::negotiated_port_is_used()
+            .describe("Answers NTS polls on the negotiated NTP port, and only there")
+            .with_rfc("https://datatracker.ietf.org/doc/html/rfc8915#section-4.1.7"),
+        ke_test(nts_ke::error_on_unknown_next_protocol).describe(
+            "Replies with an empty protocol list if we send only protocols that do not exist",
+        ),
+        ke_test(nts_ke::ignore_unknown_extra_protocols).describe("Ignores unknown protocols"),
+        ke_test(nts_ke::error_on_critical_empty_aead)
+            .describe("Replies with an error to a critical AEAD record without algorithms"),
+        ke_test(nts_ke::error_on_unknown_aead).describe(
+            "Replies with an empty AEAD list if we send only algorithms that do not exist",
+        ),
+        ke_test(nts_ke::ignore_unknown_extra_aead).describe("Ignores unknown AEAD algorithms"),
+        ke_test(nts_ke::dedup_duplicate_aead)
+            .describe("Negotiates an AEAD algorithm offered twice only once")
+            .with_rfc("https://datatracker.ietf.org/doc/html/rfc8915#section-4.1.5"),
+        ke_test(nts_ke::empty_message_resolves_in_error)
+            .describe("Replies with an error message even to an invalid request"),
+        ke_test(nts_ke::error_on_missing_next_protocol)
+            .describe("Replies with a Bad Request error to a request without NextProtocol record")
+            .with_rfc("https://datatracker.ietf.org/doc/html/rfc8915#section-4.1.2"),
+        ke_test(nts_ke::error_on_duplicate_next_protocol)
+            .describe("Replies with an error to a request with two NextProtocol records")
+            .with_rfc("https://datatracker.ietf.org/doc/html/rfc8915#section-4.1.2"),
+        ke_test(nts_ke::error_on_unknown_critical_record).describe(
+            "Replies with an error to an unknown critical record, and closes the connection",
+        ),
+        ke_server_test(nts_ke::rejects_tls_1_2)
+            .describe("Refuses to establish a TLS 1.2 session")
+            .with_rfc("https://datatracker.ietf.org/doc/html/rfc8915#section-3")
+            .with_tags(&["security"]),
+        ke_server_test(nts_ke::rejects_wrong_alpn)
+            .describe("Refuses connections that do not negotiate the `ntske/1` ALPN protocol")
+            .with_tags(&["security"]),
+        nts_ke::aes_siv_cmac_512()
+            .describe("AES-SIV-CMAC-512 can be negotiated and its keys used for NTS protected NTP"),
+        ke_udp_test(nts_ke::derived_keys_protect_ntp)
+            .describe("The keys exported from the TLS session can be used for NTS protected NTP"),
+    ]);
+
+    if options.source_port_123 {
+        tests.push(
+            udp_test(basic::test_responds_to_source_port_123)
+                .describe("Answers requests sent from the privileged source port 123"),
+        );
+    }
+
+    if options.trickle {
+        tests.push(
+            ke_test(nts_ke::trickled_request)
+                .describe("Answers a request that arrives one byte at a time")
+                .with_tags(&["robustness"]),
+        );
+    }
+
+    if options.response_source {
+        tests.push(
+            udp_test(basic::test_responds_from_queried_address)
+                .describe("Answers from the address and port the request was sent to")
+                .with_tags(&["security"]),
+        );
+    }
+
+    if options.allow_raw {
+        tests.push(
+            udp_test(basic::test_survives_spoofed_request)
+                .describe("Keeps running after a request with a forged source address")
+                .with_tags(&["security"]),
+        );
+    }
+
+    if options.offset_jitter {
+        tests.push(
+            udp_test(basic::test_offset_jitter_is_bounded)
+                .describe("The clock offset measured over repeated polls is consistent"),
+        );
+    }
+
+    if options.multicast {
+        tests.push(
+            udp_test(modes::test_no_unicast_response_to_multicast)
+                .describe("Does not answer requests sent to the NTP multicast group with unicast")
+                .with_tags(&["security", "amplification"]),
+        );
+    }
+
+    if let Some(event) = options.expected_leap {
+        tests.push(
+            leap::leap_second_handling(event)
+                .describe("Announces or smears an upcoming leap second"),
+        );
+    }
+
+    if let Some(fuzz) = options.fuzz {
+        tests.push(
+            fuzz::random_packets(fuzz)
+                .describe("Stays alive and does not reflect randomly mutated poll requests")
+                .with_tags(&["robustness"]),
+        );
+    }
+
+    tests.into_iter()
 }
@@ -0,0 +1,92 @@
+//! Tests that exercise the association modes described in [RFC5905 section 3](https://datatracker.ietf.org/doc/html/rfc5905#section-3)
+
+use crate::macros::*;
+use crate::udp::{UdpConnection, UdpProbe, UdpRequest, NTP_MULTICAST_V4, NTP_MULTICAST_V6};
+use crate::util::result::{fail, TestResult, PASS};
+use ntp_proto::{NtpAssociationMode, NtpPacket};
+use std::net::SocketAddr;
+
+/// Check that a symmetric active request is not answered by a server mode response
+///
+/// A server configured for symmetric peering may reply in symmetric passive mode, otherwise the request should be
+/// ignored. Replying in server mode indicates the server confuses the association modes.
+pub fn test_symmetric_active_is_not_answered_as_server(conn: &mut UdpConnection) -> TestResult {
+    let request = UdpRequest::poll_with_mode(NtpAssociationMode::SymmetricActive);
+
+    let Some(response) = conn.pester_raw(request)? else {
+        return PASS;
+    };
+
+    let packet = match NtpPacket::try_from(&response) {
+        Ok(packet) => packet,
+        Err(e) => {
+            return fail(
+                format!("Server replied to symmetric active request with invalid packet: {e:?}"),
+                response,
+            )
+        }
+    };
+
+    match packet.mode() {
+        NtpAssociationMode::SymmetricPassive => PASS,
+        NtpAssociationMode::Server => fail(
+            "Server replied in server mode to a symmetric active request",
+            packet,
+        ),
+        other => fail(
+            format!("Server replied in unexpected mode {other:?} to a symmetric active request"),
+            packet,
+        ),
+    }
+}
+
+/// Check that a request claiming to be sent by a server (mode 4) is not answered
+///
+/// Answering server mode packets allows two servers to be tricked into an endless loop of replying to each other.
+pub fn test_ignores_server_mode_requests(conn: &mut UdpConnection) -> TestResult {
+    let response = conn.pester_raw(UdpRequest::poll_with_mode(NtpAssociationMode::Server))?;
+    pester_assert_no_response!(response, "Should not respond to mode 4 server packets");
+
+    PASS
+}
+
+/// Check that the server does not answer legacy mode 6 (control) and mode 7 (private) requests
+///
+/// These modes are a well known amplification vector, e.g. using the `monlist` command.
+pub fn test_ignores_control_and_private_modes(conn: &mut UdpConnection) -> TestResult {
+    let response = conn.pester_raw(UdpRequest::control_read_status())?;
+    pester_assert_no_response!(response, "Should not respond to mode 6 control requests");
+
+    let response = conn.pester_raw(UdpRequest::private_monlist())?;
+    pester_assert_no_response!(response, "Should not respond to mode 7 monlist requests");
+
+    PASS
+}
+
+/// Check that the server does not answer a request sent to the NTP multicast group with a unicast response
+///
+/// A request to a multicast group can be sent with a spoofed source address to reach every server on the network
+/// at once, so answering it amplifies the traffic towards the victim. Only run with `--multicast`, as it needs the
+/// tested server to be on the local network segment to receive the request at all.
+pub fn test_no_unicast_response_to_multicast(conn: &mut UdpConnection) -> TestResult {
+    let peer = conn.peer_addr()?;
+    let group = match peer {
+        SocketAddr::V4(_) => SocketAddr::new(NTP_MULTICAST_V4.into(), peer.port()),
+        SocketAddr::V6(_) => SocketAddr::new(NTP_MULTICAST_V6.into(), peer.port()),
+    };
+
+    let probe = UdpProbe::multicast(group, conn.timeout())?;
+    let (request, _id) = NtpPacket::poll_message(Default::default());
+    probe.send_to(&UdpRequest::from(request), group)?;
+
+    while let Some((from, response)) = probe.recv_from()? {
+        if from.ip() == peer.ip() {
+            return fail(
+                format!("Server answered a request sent to multicast group {group} from {from}"),
+                response,
+            );
+        }
+    }
+
+    PASS
+}
@@ -2,12 +2,22 @@
 //!
 //! These extensions are described in [RFC8915 section 5](https://datatracker.ietf.org/doc/html/rfc8915#name-nts-extension-fields-for-nt).
 
-use crate::macros::{pester_assert, pester_assert_eq, pester_assert_response};
-use crate::nts::NtsCookie;
-use crate::udp::UdpConnection;
-use crate::util::result::PASS;
+use crate::macros::{
+    pester_assert, pester_assert_eq, pester_assert_response, pester_assert_version,
+};
+use crate::nts::{new_cookies, NtsCookie};
+use crate::udp::{UdpConnection, UdpRequest, UdpResponse};
+use crate::util::result::{fail, fail_no_response, PASS};
 use crate::TestResult;
-use ntp_proto::{NtpPacket, NtsKeys, PollInterval};
+use anyhow::anyhow;
+use ntp_proto::{ExtensionField, NtpPacket, NtsKeys, PollInterval};
+use std::collections::HashSet;
+
+/// Number of exchanges done by [`cookie_rotation`]
+const COOKIE_ROTATION_ROUNDS: usize = 8;
+
+/// Number of cookie placeholders sent by [`cookie_placeholders`]
+const COOKIE_PLACEHOLDERS: usize = 3;
 
 /// Ensure the server correctly responds to a normal NTS request
 pub fn happy(conn: &mut UdpConnection, cookie: NtsCookie, keys: &NtsKeys) -> TestResult {
@@ -30,3 +40,254 @@ pub fn happy(conn: &mut UdpConnection, cookie: NtsCookie, keys: &NtsKeys) -> Tes
 
     PASS
 }
+
+/// Ensure the origin timestamp of an NTS response is the transmit timestamp of the request
+///
+/// The NTS counterpart of [`test_responds_to_version_4`](super::basic::test_responds_to_version_4), catching
+/// servers that zero or regenerate the origin timestamp on the authenticated path.
+pub fn origin_timestamp_is_copied(
+    conn: &mut UdpConnection,
+    cookie: NtsCookie,
+    keys: &NtsKeys,
+) -> TestResult {
+    let (request, id) = NtpPacket::nts_poll_message(&cookie, 1, PollInterval::default());
+
+    let response = pester_assert_response!(conn.pester_nts(request, keys)?);
+    let header = pester_assert_version!(response, response, V4);
+
+    pester_assert_eq!(
+        response,
+        header.origin_timestamp,
+        id.expected_origin_timestamp,
+        "Incorrect origin timestamp"
+    );
+    pester_assert!(
+        response,
+        response.valid_server_response(id, true),
+        "Response did not match request"
+    );
+
+    PASS
+}
+
+/// Ensure the server supplies fresh cookies with every response, so a client never has to reuse one
+///
+/// Each request uses a cookie from the previous response. See
+/// [RFC8915 section 5.7](https://datatracker.ietf.org/doc/html/rfc8915#section-5.7) for the requirements.
+pub fn cookie_rotation(conn: &mut UdpConnection, cookie: NtsCookie, keys: &NtsKeys) -> TestResult {
+    let mut seen = HashSet::from([cookie.clone()]);
+    let mut pool = vec![cookie];
+
+    for round in 0..COOKIE_ROTATION_ROUNDS {
+        let pool_size = pool.len();
+        let cookie = pool.pop().expect("Pool never shrinks");
+        let (request, id) = NtpPacket::nts_poll_message(&cookie, 1, PollInterval::default());
+
+        let response = pester_assert_response!(conn.pester_nts(request, keys)?);
+
+        pester_assert!(
+            response,
+            response.valid_server_response(id, true),
+            "Response in round {round} did not match request"
+        );
+
+        for new_cookie in new_cookies(&response) {
+            if !seen.insert(new_cookie.clone()) {
+                return fail(
+                    format!("Server handed out cookie {new_cookie:?} twice in round {round}"),
+                    response,
+                );
+            }
+            pool.push(new_cookie);
+        }
+
+        pester_assert!(
+            response,
+            pool.len() >= pool_size,
+            "Cookie pool shrunk from {pool_size} to {} in round {round}",
+            pool.len()
+        );
+    }
+
+    PASS
+}
+
+/// Ensure the server does not accept a forged cookie
+///
+/// The server must either drop the request or answer with an NTS NAK, see
+/// [RFC8915 section 5.7](https://datatracker.ietf.org/doc/html/rfc8915#section-5.7).
+pub fn rejects_forged_cookie(
+    conn: &mut UdpConnection,
+    cookie: NtsCookie,
+    keys: &NtsKeys,
+) -> TestResult {
+    let (request, id) =
+        NtpPacket::nts_poll_message(&cookie.corrupted(), 1, PollInterval::default());
+
+    let Some(response) = conn.pester_nts(request, keys)? else {
+        return PASS;
+    };
+
+    pester_assert!(
+        response,
+        !response.valid_server_response(id, true),
+        "Server sent an authenticated response to a request with a forged cookie"
+    );
+
+    pester_assert!(
+        response,
+        response.is_kiss_ntsn(),
+        "Server answered a request with a forged cookie with something other than an NTS NAK"
+    );
+
+    PASS
+}
+
+/// Ensure the server protects its responses to NTS requests with an NTS Authenticator extension field
+///
+/// See [RFC8915 section 5.7](https://datatracker.ietf.org/doc/html/rfc8915#section-5.7), a server that accepts the
+/// request must authenticate its response.
+pub fn response_is_authenticated(
+    conn: &mut UdpConnection,
+    cookie: NtsCookie,
+    keys: &NtsKeys,
+) -> TestResult {
+    let (request, _id) = NtpPacket::nts_poll_message(&cookie, 1, PollInterval::default());
+
+    let Some(response) = conn.pester_nts(request, keys)? else {
+        return fail_no_response("Server did not reply to a valid NTS request");
+    };
+
+    pester_assert!(
+        response,
+        response.authenticated_extension_fields().next().is_some(),
+        "Server replied to a valid NTS request without authenticating the response"
+    );
+
+    PASS
+}
+
+/// Ensure the unique identifier of an NTS request is returned byte for byte in the authenticated part of the response
+///
+/// Unlike plain NTP, where [`test_unique_id_is_returned`](super::extensions::test_unique_id_is_returned) checks the
+/// field is echoed at all, under NTS the unique identifier binds the response to the request and protects against
+/// replay. See [RFC8915 section 5.3](https://datatracker.ietf.org/doc/html/rfc8915#section-5.3).
+pub fn unique_id_is_authenticated(
+    conn: &mut UdpConnection,
+    cookie: NtsCookie,
+    keys: &NtsKeys,
+) -> TestResult {
+    let (request, id) = NtpPacket::nts_poll_message(&cookie, 1, PollInterval::default());
+    let Some(uid) = id.uid else {
+        return Err(
+            anyhow!("NTS request was created without a unique identifier, this is a bug!").into(),
+        );
+    };
+
+    let response = pester_assert_response!(conn.pester_nts(request, keys)?);
+
+    let returned: Vec<_> = response
+        .authenticated_extension_fields()
+        .filter_map(|field| match field {
+            ExtensionField::UniqueIdentifier(returned) => Some(returned.to_vec()),
+            _ => None,
+        })
+        .collect();
+    pester_assert_eq!(
+        response,
+        returned.len(),
+        1,
+        "Expected exactly one authenticated unique identifier, got {returned:?}"
+    );
+    pester_assert_eq!(
+        response,
+        &returned[0][..],
+        &uid[..],
+        "Response unique identifier does not match request"
+    );
+
+    PASS
+}
+
+/// Ensure a replayed NTS request is either ignored, refused, or answered consistently
+///
+/// The exact bytes of a valid request, including its cookie and unique identifier, are sent twice. Servers are not
+/// required to detect the duplicate, see [RFC8915 section 5.7](https://datatracker.ietf.org/doc/html/rfc8915#section-5.7),
+/// but an authenticated answer to the replay still has to match the request.
+pub fn replayed_request(conn: &mut UdpConnection, cookie: NtsCookie, keys: &NtsKeys) -> TestResult {
+    let (request, id) = NtpPacket::nts_poll_message(&cookie, 1, PollInterval::default());
+    let request = UdpRequest::from_ntp_packet(request, Some(keys));
+    let replay = UdpRequest(request.0.clone());
+
+    let first = pester_assert_response!(conn.pester_raw(request)?);
+    let first = decrypt(&first, keys)?;
+    pester_assert!(
+        first,
+        first.valid_server_response(id, true),
+        "Response to the original request did not match it"
+    );
+
+    let Some(second) = conn.pester_raw(replay)? else {
+        tracing::info!("server ignored the replayed NTS request");
+        return PASS;
+    };
+    let unauthenticated = second.parse()?;
+    if unauthenticated.is_kiss_ntsn() {
+        tracing::info!("server refused the replayed NTS request with a NTSN kiss code");
+        return PASS;
+    }
+
+    let second = decrypt(&second, keys)?;
+    pester_assert!(
+        second,
+        second.valid_server_response(id, true),
+        "Response to the replayed request did not match it"
+    );
+
+    PASS
+}
+
+/// Parse and authenticate `response` with the server to client key of `keys`
+fn decrypt(response: &UdpResponse, keys: &NtsKeys) -> TestResult<NtpPacket<'static>> {
+    match NtpPacket::deserialize(&response.0, &Some(keys.s2c.as_ref())) {
+        Ok((packet, _cookie)) => Ok(packet.into_owned()),
+        Err(e) => fail(
+            format!("Response could not be authenticated: {e:?}"),
+            response.clone(),
+        ),
+    }
+}
+
+/// Ensure the server sends one extra cookie for every NTS Cookie Placeholder extension field
+///
+/// See [RFC8915 section 5.5](https://datatracker.ietf.org/doc/html/rfc8915#section-5.5), placeholders have the same
+/// length as the cookie they accompany.
+pub fn cookie_placeholders(
+    conn: &mut UdpConnection,
+    cookie: NtsCookie,
+    keys: &NtsKeys,
+) -> TestResult {
+    let (mut request, id) = NtpPacket::nts_poll_message(&cookie, 1, PollInterval::default());
+    for _ in 0..COOKIE_PLACEHOLDERS {
+        request.push_additional(ExtensionField::NtsCookiePlaceholder {
+            cookie_length: cookie.len() as u16,
+        });
+    }
+
+    let response = pester_assert_response!(conn.pester_nts(request, keys)?);
+
+    pester_assert!(
+        response,
+        response.valid_server_response(id, true),
+        "Response did not match request"
+    );
+
+    pester_assert_eq!(
+        response,
+        response.new_cookies().count(),
+        1 + COOKIE_PLACEHOLDERS,
+        "Server did not send one cookie for the request and one per placeholder",
+    );
+
+    PASS
+}
@@ -2,15 +2,54 @@
 //!
 //! The protocol is specified in [RFC8915 section 4](https://datatracker.ietf.org/doc/html/rfc8915#name-the-nts-key-establishment-p).
 
-use crate::macros::{pester_assert, pester_assert_eq};
-use crate::nts_ke::{NtsKeConnection, Request};
-use crate::util::result::{TestResult, PASS};
-use ntp_proto::NtsRecord;
+use crate::macros::{
+    pester_assert, pester_assert_eq, pester_assert_gt, pester_assert_lt, pester_assert_ne,
+    pester_assert_response,
+};
+use crate::nts::NtsCookie;
+use crate::nts_ke::{
+    resolve_udp_host, NtsKeConnection, NtsKeError, RawRecord, Request, Response, DEFAULT_NTP_PORT,
+};
+use crate::udp::{ConnectionRefused, UdpConnection};
+use crate::util::result::{fail, fail_no_response, TestError, TestResult, PASS};
+use crate::{Category, NtsServer, TestCase, TestConfig};
+use ntp_proto::{AeadAlgorithm, NtpPacket, NtsKeys, NtsRecord, PollInterval, ProtocolId};
+use std::collections::HashSet;
+use std::io;
+use std::net::SocketAddr;
+use std::panic::UnwindSafe;
+use std::time::Duration;
 
 /// Check that the server responds with a valid response to a valid request
-pub fn happy(ke: &mut NtsKeConnection) -> TestResult {
-    let res = ke.exchange(Request::default())?;
+///
+/// RFC8915 does not say how many cookies a server sends, so by default any non-zero number is accepted. With
+/// `expected_cookies` the server has to send exactly that many, e.g. 8 for ntpd-rs.
+pub fn happy(expected_cookies: Option<usize>) -> Box<dyn TestCase + UnwindSafe + Send> {
+    struct Happy(Option<usize>);
+
+    impl TestCase for Happy {
+        fn name(&self) -> &'static str {
+            concat!(module_path!(), "::happy")
+        }
+
+        fn category(&self) -> Category {
+            Category::NtsKe
+        }
 
+        fn run(&self, conf: &TestConfig) -> TestResult {
+            happy_exchange(&mut conf.ke()?, self.0)
+        }
+    }
+
+    Box::new(Happy(expected_cookies))
+}
+
+fn happy_exchange(ke: &mut NtsKeConnection, expected_cookies: Option<usize>) -> TestResult {
+    check_happy_response(ke.exchange(Request::default())?, expected_cookies)
+}
+
+/// Check that `res` is a successful response to a [`Request::default`], with `expected_cookies` if given
+fn check_happy_response(res: Response, expected_cookies: Option<usize>) -> TestResult {
     pester_assert_eq!(
         res,
         res.next_protocol.clone(),
@@ -28,7 +67,8 @@ pub fn happy(ke: &mut NtsKeConnection) -> TestResult {
     pester_assert!(
         res,
         res.errors.is_empty(),
-        "Server did reply with error code to normal request",
+        "Server did reply with errors {:?} to normal request",
+        res.errors,
     );
 
     pester_assert!(
@@ -37,16 +77,422 @@ pub fn happy(ke: &mut NtsKeConnection) -> TestResult {
         "Server did reply with warning code to normal request",
     );
 
-    pester_assert_eq!(
+    match expected_cookies {
+        Some(expected) => {
+            pester_assert_eq!(
+                res,
+                res.cookies.len(),
+                expected,
+                "Server did not reply with {expected} cookies"
+            );
+        }
+        None => {
+            pester_assert_gt!(
+                res,
+                res.cookies.len(),
+                0,
+                "Server did not reply with any cookies"
+            );
+        }
+    }
+
+    PASS
+}
+
+/// Check that the server never sets the critical bit on a New Cookie record
+///
+/// [RFC8915 section 4.1.6](https://datatracker.ietf.org/doc/html/rfc8915#section-4.1.6) requires the bit to be clear.
+/// The parsed [`Response`] does not keep it, so the raw records are inspected.
+pub fn new_cookie_is_not_critical(ke: &mut NtsKeConnection) -> TestResult {
+    let records = ke.exchange_raw(Request::default())?;
+
+    let critical = records
+        .iter()
+        .filter(|record| record.record_type == RawRecord::NEW_COOKIE && record.critical)
+        .count();
+    if critical > 0 {
+        return fail(
+            format!("Server set the critical bit on {critical} New Cookie records"),
+            records,
+        );
+    }
+
+    PASS
+}
+
+/// Whether a record type has to, may, or must not have the critical bit set
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Criticality {
+    Required,
+    Allowed,
+    Forbidden,
+}
+
+/// The criticality of every record type, as specified in the subsections of
+/// [RFC8915 section 4.1](https://datatracker.ietf.org/doc/html/rfc8915#section-4.1)
+const CRITICALITY: [(u16, Criticality); 8] = [
+    (RawRecord::END_OF_MESSAGE, Criticality::Required),
+    (RawRecord::NEXT_PROTOCOL, Criticality::Required),
+    (RawRecord::ERROR, Criticality::Required),
+    (RawRecord::WARNING, Criticality::Required),
+    (RawRecord::AEAD_ALGORITHM, Criticality::Allowed),
+    (RawRecord::NEW_COOKIE, Criticality::Forbidden),
+    (RawRecord::SERVER, Criticality::Allowed),
+    (RawRecord::PORT, Criticality::Allowed),
+];
+
+/// Check the critical bit of every record in the response to a normal request
+///
+/// Records of unknown types are not checked, a server should not send them in the first place.
+pub fn critical_bits_are_correct(ke: &mut NtsKeConnection) -> TestResult {
+    let records = ke.exchange_raw(Request::default())?;
+
+    let violations: Vec<_> = records
+        .iter()
+        .filter_map(|record| {
+            let (_, rule) = CRITICALITY
+                .iter()
+                .find(|(record_type, _)| *record_type == record.record_type)?;
+            let expected = match (rule, record.critical) {
+                (Criticality::Required, false) => "set",
+                (Criticality::Forbidden, true) => "clear",
+                _ => return None,
+            };
+            Some(format!(
+                "{} record has the critical bit {}, it must be {expected}",
+                record.type_name(),
+                if record.critical { "set" } else { "clear" },
+            ))
+        })
+        .collect();
+
+    if !violations.is_empty() {
+        return fail(
+            format!(
+                "Incorrect critical bits in response:\n{}",
+                violations.join("\n")
+            ),
+            records,
+        );
+    }
+
+    PASS
+}
+
+/// Check that the server does not send warnings in response to a normal request
+///
+/// Clients ignore warnings, see [RFC8915 section 4.1.4](https://datatracker.ietf.org/doc/html/rfc8915#section-4.1.4),
+/// but a server warning about a valid request likely has a problem of its own.
+pub fn no_spurious_warnings(ke: &mut NtsKeConnection) -> TestResult {
+    let res = ke.exchange(Request::default())?;
+
+    pester_assert!(
         res,
-        res.cookies.len(),
-        8,
-        "Server did not reply with 8 cookies"
+        res.warnings.is_empty(),
+        "Server replied with warnings {:?} to a normal request",
+        res.warnings
     );
 
     PASS
 }
 
+/// Check that the server closes the connection after sending EndOfMessage
+///
+/// See [RFC8915 section 4](https://datatracker.ietf.org/doc/html/rfc8915#section-4), the response ends with
+/// EndOfMessage. Trailing records or a connection left open are reported as failures, the latter once the read times
+/// out.
+pub fn closes_after_end_of_message(ke: &mut NtsKeConnection) -> TestResult {
+    for record in Request::default() {
+        ke.send_record(record)?;
+    }
+
+    let mut records = vec![];
+    while records.last() != Some(&NtsRecord::EndOfMessage) {
+        match ke.recv_record()? {
+            Some(record) => records.push(record),
+            None => return fail("Server closed the connection before EndOfMessage", records),
+        }
+    }
+
+    match ke.recv_record() {
+        Ok(None) => PASS,
+        Ok(Some(record)) => fail(
+            format!("Server sent {record:?} after EndOfMessage"),
+            records,
+        ),
+        Err(TestError::Error(e)) if is_timeout(&e) => fail(
+            "Server did not close the connection after EndOfMessage",
+            records,
+        ),
+        Err(e) => Err(e),
+    }
+}
+
+/// Pause between the bytes sent by [`trickled_request`]
+const TRICKLE_DELAY: Duration = Duration::from_millis(20);
+
+/// Check that the server reassembles a request that arrives one byte at a time
+///
+/// Every byte of a valid request is sent in its own TLS record, with a short pause in between, like a slow-loris
+/// client would. The server has to wait for the complete request and answer it normally. Only run with `--trickle`.
+pub fn trickled_request(ke: &mut NtsKeConnection) -> TestResult {
+    ke.handshake()?;
+
+    let mut request = vec![];
+    for record in Request::default() {
+        record
+            .write(&mut request)
+            .expect("Vec never runs out of space");
+    }
+
+    for (index, byte) in request.iter().enumerate() {
+        if index > 0 {
+            std::thread::sleep(TRICKLE_DELAY);
+        }
+        if let Err(e) = ke.send_raw(&[*byte]) {
+            return fail_no_response(format!(
+                "Connection failed after sending {index} of {} bytes one at a time: {e}",
+                request.len()
+            ));
+        }
+    }
+
+    let res = match ke.recv_response() {
+        Ok(res) => res,
+        Err(TestError::Error(e)) if is_timeout(&e) => {
+            return fail_no_response("Server did not answer a request sent one byte at a time")
+        }
+        Err(e) => return Err(e),
+    };
+
+    pester_assert!(
+        res,
+        res.errors.is_empty(),
+        "Server replied with errors {:?} to a request sent one byte at a time",
+        res.errors
+    );
+    pester_assert!(
+        res,
+        !res.cookies.is_empty(),
+        "Server sent no cookies in response to a request sent one byte at a time"
+    );
+
+    PASS
+}
+
+/// Whether `error` was caused by a socket read or write timing out
+fn is_timeout(error: &anyhow::Error) -> bool {
+    error.chain().any(|cause| {
+        cause.downcast_ref::<io::Error>().is_some_and(|e| {
+            matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            )
+        })
+    })
+}
+
+/// Largest cookie we consider reasonable
+pub const MAX_COOKIE_LEN: usize = 256;
+
+/// Check that the cookies returned by the server are non-empty, reasonably sized, and unique
+///
+/// A server handing out identical cookies breaks the unlinkability NTS is supposed to provide, see
+/// [RFC8915 section 6](https://datatracker.ietf.org/doc/html/rfc8915#section-6).
+pub fn cookies_are_sane(ke: &mut NtsKeConnection) -> TestResult {
+    let res = ke.exchange(Request::default())?;
+
+    let mut seen = HashSet::new();
+    for (i, cookie) in res.cookies.iter().enumerate() {
+        pester_assert!(res, !cookie.is_empty(), "Cookie {i} is empty");
+        pester_assert_lt!(
+            res,
+            cookie.len(),
+            MAX_COOKIE_LEN,
+            "Cookie {i} is too long: {}",
+            hex::encode(&cookie[..])
+        );
+        pester_assert!(
+            res,
+            seen.insert(&cookie[..]),
+            "Cookie {i} was already handed out in the same response: {}",
+            hex::encode(&cookie[..])
+        );
+    }
+
+    PASS
+}
+
+/// Check that all cookies of a response have the same size
+///
+/// Cookies of varying sizes can leak server state to an observer of the NTP traffic, e.g. which key minted them or
+/// which AEAD they are for. Whether the size itself is plausible is checked by [`cookies_are_sane`].
+pub fn cookie_sizes_are_uniform(ke: &mut NtsKeConnection) -> TestResult {
+    let res = ke.exchange(Request::default())?;
+    let sizes: Vec<_> = res.cookies.iter().map(|cookie| cookie.len()).collect();
+    tracing::info!(?sizes, "cookie sizes");
+
+    let Some(&first) = sizes.first() else {
+        return fail("Server sent no cookies", res);
+    };
+    pester_assert!(
+        res,
+        sizes.iter().all(|&size| size == first),
+        "Server sent cookies of different sizes: {sizes:?}"
+    );
+
+    PASS
+}
+
+/// Upper bound for the number of cookies in a single key exchange response
+pub const MAX_COOKIES: usize = 32;
+
+/// Upper bound for the size of a key exchange response in bytes, enough for [`MAX_COOKIES`] cookies of
+/// [`MAX_COOKIE_LEN`] and the other records
+pub const MAX_RESPONSE_LEN: usize = MAX_COOKIES * (MAX_COOKIE_LEN + 4) + 1024;
+
+/// Check that the server caps the number of cookies it hands out in one response
+///
+/// NTS-KE has no way for the client to ask for a number of cookies, servers commonly send 8. A server returning
+/// thousands of cookies, or huge records, wastes resources on both ends.
+pub fn cookie_count_is_bounded(ke: &mut NtsKeConnection) -> TestResult {
+    let res = ke.exchange(Request::default())?;
+    let count = res.cookies.len();
+    let size = ke.bytes_received();
+
+    pester_assert!(
+        res,
+        count <= MAX_COOKIES && size <= MAX_RESPONSE_LEN,
+        "Server sent {count} cookies in a response of {size} bytes, expected at most {MAX_COOKIES} cookies and \
+        {MAX_RESPONSE_LEN} bytes"
+    );
+
+    PASS
+}
+
+/// Check that the order of records in the request does not matter
+///
+/// [RFC8915 section 4](https://datatracker.ietf.org/doc/html/rfc8915#section-4) only requires EndOfMessage to be the
+/// last record. The seed is fixed so failures can be reproduced.
+pub fn shuffled_request(ke: &mut NtsKeConnection) -> TestResult {
+    let res = ke.exchange(Request {
+        server: Some(ke.host().to_string()),
+        port: Some(DEFAULT_NTP_PORT),
+        shuffle_seed: Some(0x5EED),
+        ..Request::default()
+    })?;
+
+    check_happy_response(res, None)
+}
+
+/// Check that the server handles NTPv4 Server and Port Negotiation records
+///
+/// The server may echo or override the requested values, see
+/// [RFC8915 section 4.1.7](https://datatracker.ietf.org/doc/html/rfc8915#section-4.1.7). Whatever it returns has to be
+/// usable to contact the NTP server.
+pub fn server_port_negotiation(ke: &mut NtsKeConnection) -> TestResult {
+    let res = ke.exchange(Request {
+        server: Some(ke.host().to_string()),
+        port: Some(DEFAULT_NTP_PORT),
+        ..Request::default()
+    })?;
+
+    pester_assert!(
+        res,
+        res.errors.is_empty(),
+        "Server did reply with errors {:?} to server and port negotiation",
+        res.errors,
+    );
+    if let Some(server) = &res.server {
+        pester_assert!(
+            res,
+            !server.is_empty(),
+            "Server replied with an empty server name"
+        );
+    }
+    if let Some(port) = res.port {
+        pester_assert_ne!(res, port, 0, "Server replied with port 0");
+    }
+
+    if let Err(e) = resolve_udp_host(&res, ke.host(), ke.address_family()) {
+        return fail(format!("Negotiated NTP server does not resolve: {e}"), res);
+    }
+
+    PASS
+}
+
+/// Check that NTS polls work on the NTP port negotiated in a Port record, and only there
+///
+/// Skipped unless the key exchange points NTP at a port other than [`DEFAULT_NTP_PORT`], see
+/// [RFC8915 section 4.1.7](https://datatracker.ietf.org/doc/html/rfc8915#section-4.1.7). The server used by the other
+/// tests has to be on the negotiated port, and a poll on the default port of the same host must not be answered with
+/// a response authenticated by the keys of this exchange.
+pub fn negotiated_port_is_used() -> Box<dyn TestCase + UnwindSafe + Send> {
+    struct NegotiatedPortIsUsed;
+
+    impl TestCase for NegotiatedPortIsUsed {
+        fn name(&self) -> &'static str {
+            concat!(module_path!(), "::negotiated_port_is_used")
+        }
+
+        fn category(&self) -> Category {
+            Category::NtsKe
+        }
+
+        fn run(&self, conf: &TestConfig) -> TestResult {
+            let (mut cookies, udp_host, keys) =
+                conf.ke()?.do_request(AeadAlgorithm::AeadAesSivCmac256)?;
+            if udp_host.port() == DEFAULT_NTP_PORT {
+                tracing::info!("server did not negotiate a different NTP port, skipping");
+                return Err(TestError::Skipped);
+            }
+            let default_host = SocketAddr::new(udp_host.ip(), DEFAULT_NTP_PORT);
+            tracing::info!(negotiated = %udp_host, default = %default_host, "polling both ports");
+
+            let server_port = conf.nts_server()?.udp_host().port();
+            if server_port != udp_host.port() {
+                return fail_no_response(format!(
+                    "Key exchange negotiated port {}, but the NTP server under test uses port {server_port}",
+                    udp_host.port()
+                ));
+            }
+
+            let (Some(default_cookie), Some(negotiated_cookie)) = (cookies.pop(), cookies.pop())
+            else {
+                return fail_no_response("Server did not send enough cookies to poll both ports");
+            };
+
+            let (request, id) =
+                NtpPacket::nts_poll_message(&negotiated_cookie, 1, PollInterval::default());
+            let response =
+                pester_assert_response!(conf.udp_to(udp_host)?.pester_nts(request, &keys)?);
+            pester_assert!(
+                response,
+                response.valid_server_response(id, true),
+                "NTS poll to negotiated port {udp_host} was not answered with a matching response"
+            );
+
+            let (request, id) =
+                NtpPacket::nts_poll_message(&default_cookie, 1, PollInterval::default());
+            match conf.udp_to(default_host)?.pester_nts(request, &keys) {
+                Ok(Some(response)) if response.valid_server_response(id, true) => fail(
+                    format!(
+                        "NTS poll to default port {default_host} was authenticated, although port \
+                        {udp_host} was negotiated"
+                    ),
+                    response,
+                ),
+                // No response, an invalid one, or nothing listening at all
+                Ok(_) | Err(TestError::Fail(..)) => PASS,
+                Err(TestError::Error(e)) if e.is::<ConnectionRefused>() => PASS,
+                Err(e) => Err(e),
+            }
+        }
+    }
+
+    Box::new(NegotiatedPortIsUsed)
+}
+
 /// Check that the server replies with an empty protocol list if we send only protocols that do not exist
 ///
 /// See also [ignore_unknown_extra_protocols]
@@ -106,6 +552,31 @@ pub fn error_on_unknown_aead(ke: &mut NtsKeConnection) -> TestResult {
     PASS
 }
 
+/// Check that the server replies with an error to a critical AEAD Algorithm record without any algorithms
+///
+/// [RFC8915 section 4.1.5](https://datatracker.ietf.org/doc/html/rfc8915#section-4.1.5) requires the request to
+/// offer at least one algorithm, with the critical bit set the server can not just ignore the record.
+pub fn error_on_critical_empty_aead(ke: &mut NtsKeConnection) -> TestResult {
+    let response = ke.exchange(Request {
+        aead: vec![],
+        critical_aead: true,
+        ..Request::default()
+    })?;
+
+    pester_assert!(
+        response,
+        !response.errors.is_empty(),
+        "Server did not respond with an error to a critical AEAD record without algorithms"
+    );
+    pester_assert!(
+        response,
+        response.cookies.is_empty(),
+        "Server handed out cookies without negotiating an AEAD"
+    );
+
+    PASS
+}
+
 /// Check that the server ignores unknown AEAD algorithms
 ///
 /// See [RFC8915 section 4.1.5](https://datatracker.ietf.org/doc/html/rfc8915#name-aead-algorithm-negotiation)
@@ -125,6 +596,31 @@ pub fn ignore_unknown_extra_aead(ke: &mut NtsKeConnection) -> TestResult {
     PASS
 }
 
+/// Check that the server negotiates an AEAD algorithm offered twice only once
+///
+/// Nothing in [RFC8915 section 4.1.5](https://datatracker.ietf.org/doc/html/rfc8915#name-aead-algorithm-negotiation)
+/// forbids duplicates in the request, the response however must contain exactly one algorithm.
+pub fn dedup_duplicate_aead(ke: &mut NtsKeConnection) -> TestResult {
+    let response = ke.exchange(Request {
+        aead: vec![15, 15],
+        ..Request::default()
+    })?;
+
+    pester_assert!(
+        response,
+        response.errors.is_empty(),
+        "Server replied with an error to a duplicate AEAD algorithm"
+    );
+    pester_assert_eq!(
+        response,
+        response.aead.clone(),
+        Some(vec![15]),
+        "Server did not negotiate the duplicate AEAD algorithm exactly once"
+    );
+
+    PASS
+}
+
 /// Check that the server replies with an error message even to an invalid request
 ///
 /// See [RFC8915 section 4.1.3](https://datatracker.ietf.org/doc/html/rfc8915#name-error)
@@ -134,9 +630,234 @@ pub fn empty_message_resolves_in_error(ke: &mut NtsKeConnection) -> TestResult {
     pester_assert_eq!(
         response,
         response.errors.clone(),
-        vec![1],
+        vec![NtsKeError::BadRequest],
         "Server did not respond with error to empty message"
     );
 
     PASS
 }
+
+/// Check that the server replies with a Bad Request error to a request without NextProtocol record
+///
+/// See [RFC8915 section 4.1.2](https://datatracker.ietf.org/doc/html/rfc8915#section-4.1.2), the record is mandatory
+/// in requests.
+///
+/// See also [empty_message_resolves_in_error]
+pub fn error_on_missing_next_protocol(ke: &mut NtsKeConnection) -> TestResult {
+    let response = ke.exchange([
+        NtsRecord::AeadAlgorithm {
+            critical: false,
+            algorithm_ids: vec![AeadAlgorithm::AeadAesSivCmac256 as u16],
+        },
+        NtsRecord::EndOfMessage,
+    ])?;
+
+    pester_assert_eq!(
+        response,
+        response.errors.clone(),
+        vec![NtsKeError::BadRequest],
+        "Server did not respond with error to request without next protocol"
+    );
+
+    PASS
+}
+
+/// Check that the server replies with an error to a request with two NextProtocol records
+///
+/// [RFC8915 section 4.1.2](https://datatracker.ietf.org/doc/html/rfc8915#section-4.1.2) allows at most one such record
+/// per request, a request with more is malformed and should be answered with a Bad Request error. The failure shows
+/// the full response of servers that accept the duplicate.
+pub fn error_on_duplicate_next_protocol(ke: &mut NtsKeConnection) -> TestResult {
+    let next_protocol = || NtsRecord::NextProtocol {
+        protocol_ids: vec![ProtocolId::NtpV4 as u16],
+    };
+    let response = ke.exchange([
+        next_protocol(),
+        next_protocol(),
+        NtsRecord::AeadAlgorithm {
+            critical: false,
+            algorithm_ids: vec![AeadAlgorithm::AeadAesSivCmac256 as u16],
+        },
+        NtsRecord::EndOfMessage,
+    ])?;
+
+    pester_assert!(
+        response,
+        !response.errors.is_empty(),
+        "Server accepted a request with two next protocol records"
+    );
+
+    PASS
+}
+
+/// Check that the server replies with an error to an unknown critical record, and closes the connection
+///
+/// See [RFC8915 section 4](https://datatracker.ietf.org/doc/html/rfc8915#section-4): a server receiving a critical
+/// record it does not understand must reply with error code 0 (Unrecognized Critical Record).
+pub fn error_on_unknown_critical_record(ke: &mut NtsKeConnection) -> TestResult {
+    ke.send_record(NtsRecord::NextProtocol {
+        protocol_ids: vec![0],
+    })?;
+    ke.send_record(NtsRecord::AeadAlgorithm {
+        critical: false,
+        algorithm_ids: vec![15],
+    })?;
+    ke.send_record(NtsRecord::Unknown {
+        record_type: 0x4000, // Reserved for private or experimental use
+        critical: true,
+        data: vec![0xde, 0xad, 0xbe, 0xef],
+    })?;
+    ke.send_record(NtsRecord::EndOfMessage)?;
+
+    let mut records = vec![];
+    loop {
+        match ke.recv_record() {
+            Ok(Some(record)) => records.push(record),
+            Ok(None) => break,
+            Err(e) => {
+                return fail(
+                    format!("Server did not close the connection after the response: {e}"),
+                    records,
+                )
+            }
+        }
+    }
+
+    let response = Response::try_from(records)?;
+
+    pester_assert_eq!(
+        response,
+        response.errors.clone(),
+        vec![NtsKeError::UnrecognizedCriticalRecord],
+        "Server did not respond with unrecognized critical record error"
+    );
+
+    PASS
+}
+
+/// Check that the server refuses to establish a TLS 1.2 session
+///
+/// See [RFC8915 section 3](https://datatracker.ietf.org/doc/html/rfc8915#section-3), NTS-KE requires TLS 1.3 or
+/// later.
+pub fn rejects_tls_1_2(server: &NtsServer) -> TestResult {
+    let mut ke = NtsKeConnection::new_with_versions(
+        server.host(),
+        server.port(),
+        server.ke_options(),
+        &[&rustls::version::TLS12],
+    )?;
+
+    match ke.handshake() {
+        Ok(version) => fail_no_response(format!(
+            "Server established a session with TLS version {version:?}"
+        )),
+        Err(_) => PASS,
+    }
+}
+
+/// Check that the server refuses connections that do not negotiate the `ntske/1` ALPN protocol
+///
+/// See [RFC8915 section 4](https://datatracker.ietf.org/doc/html/rfc8915#section-4), this prevents the server from
+/// accepting NTS-KE over arbitrary TLS connections.
+pub fn rejects_wrong_alpn(server: &NtsServer) -> TestResult {
+    for (description, alpn) in [
+        ("no ALPN", vec![]),
+        ("ALPN http/1.1", vec![b"http/1.1".to_vec()]),
+    ] {
+        let mut ke = NtsKeConnection::new_with_alpn(
+            server.host(),
+            server.port(),
+            server.ke_options(),
+            &alpn,
+        )?;
+
+        if ke.handshake().is_ok() {
+            return fail_no_response(format!("Server completed the handshake with {description}"));
+        }
+    }
+
+    PASS
+}
+
+/// Check that the keys exported from the TLS session can be used for NTS protected NTP
+///
+/// The `happy` test only checks the records of the key exchange. This sends an actual request with the derived keys
+/// to the negotiated NTP server, catching mistakes in the key derivation contexts of either side. It also catches
+/// servers that mint their cookies for another AEAD than the negotiated one, whose responses clients silently drop.
+pub fn derived_keys_protect_ntp(
+    _ke: &mut NtsKeConnection,
+    conn: &mut UdpConnection,
+    cookies: Vec<NtsCookie>,
+    keys: &NtsKeys,
+) -> TestResult {
+    protected_poll(conn, cookies, keys)
+}
+
+/// Check that AES-SIV-CMAC-512 can be negotiated and its keys used for NTS protected NTP
+///
+/// Support for this AEAD is optional, so the test is skipped if the server does not offer it.
+pub fn aes_siv_cmac_512() -> Box<dyn TestCase + UnwindSafe + Send> {
+    struct AesSivCmac512;
+
+    impl TestCase for AesSivCmac512 {
+        fn name(&self) -> &'static str {
+            concat!(module_path!(), "::aes_siv_cmac_512")
+        }
+
+        fn category(&self) -> Category {
+            Category::NtsKe
+        }
+
+        fn run(&self, conf: &TestConfig) -> TestResult {
+            let res = conf.ke()?.exchange(Request {
+                aead: vec![AeadAlgorithm::AeadAesSivCmac512 as u16],
+                ..Request::default()
+            })?;
+            if res.aead != Some(vec![AeadAlgorithm::AeadAesSivCmac512 as u16]) {
+                return Err(TestError::Skipped);
+            }
+
+            let (cookies, udp_host, keys) =
+                conf.ke()?.do_request(AeadAlgorithm::AeadAesSivCmac512)?;
+
+            protected_poll(&mut conf.udp_to(udp_host)?, cookies, &keys)
+        }
+    }
+
+    Box::new(AesSivCmac512)
+}
+
+/// Check the NTP server of a key exchange accepts a request protected with the derived `keys` and authenticates its
+/// response
+fn protected_poll(conn: &mut UdpConnection, cookies: Vec<NtsCookie>, keys: &NtsKeys) -> TestResult {
+    let Some(cookie) = cookies.into_iter().next() else {
+        return fail_no_response("Server did not send any cookies");
+    };
+
+    let (request, id) = NtpPacket::nts_poll_message(&cookie, 1, PollInterval::default());
+    let response = match conn.pester_nts(request, keys) {
+        Err(TestError::Fail(msg, response)) => {
+            return Err(TestError::Fail(
+                format!(
+                "KE cookies incompatible with negotiated AEAD, the response did not decrypt: {msg}"
+            ),
+                response,
+            ))
+        }
+        result => pester_assert_response!(result?),
+    };
+
+    pester_assert!(
+        response,
+        !response.is_kiss_ntsn(),
+        "Server could not decrypt a request protected with the keys derived from the key exchange, or its cookies \
+        do not match the negotiated AEAD"
+    );
+    pester_assert!(
+        response,
+        response.valid_server_response(id, true),
+        "Response did not match request"
+    );
+
+    PASS
+}
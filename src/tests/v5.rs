@@ -0,0 +1,210 @@
+//! Tests that exercise the NTPv5 draft
+//!
+//! The protocol is still being specified in [draft-ietf-ntp-ntpv5](https://datatracker.ietf.org/doc/draft-ietf-ntp-ntpv5/),
+//! so these tests only run when enabled with `--ntpv5`.
+
+use crate::macros::*;
+use crate::udp::{UdpConnection, UdpRequest, UdpResponse, UNIX_TO_NTP_SECONDS};
+use crate::util::result::{fail, TestResult, PASS};
+use ntp_proto::v5::{NtpEra, NtpTimescale};
+use ntp_proto::{ExtensionField, NtpHeader, NtpPacket};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Offset of TAI ahead of UTC since the leap second at the end of 2016
+pub const TAI_UTC_OFFSET_SECONDS: f64 = 37.0;
+
+/// Difference to the local clock up to which the timestamps of a response are considered to be in the right era
+///
+/// An era lasts 136 years, so this only needs to be loose enough for a TAI server or a badly synchronized client.
+pub const MAX_ERA_ERROR_SECONDS: f64 = 3600.0;
+
+/// Difference to the expected offset up to which a TAI response is accepted, see [`test_timescale_is_utc_or_tai`]
+pub const MAX_TAI_OFFSET_ERROR_SECONDS: f64 = 1.0;
+
+/// Byte offset of the timescale in the NTPv5 header
+const TIMESCALE_OFFSET: usize = 4;
+
+/// Wire value of the TAI timescale
+const TIMESCALE_TAI: u8 = 1;
+
+/// Sending a NTPv5 poll request should return a NTPv5 answer
+pub fn test_responds_to_version_5(conn: &mut UdpConnection) -> TestResult {
+    let (request, id) = NtpPacket::poll_message_v5(Default::default());
+    let client_cookie = match request.header() {
+        NtpHeader::V5(header) => header.client_cookie,
+        _ => unreachable!("poll_message_v5 creates NTPv5 packets"),
+    };
+
+    let packet = pester_assert_response!(conn.pester(request)?);
+    let header = pester_assert_version!(packet, packet, V5);
+
+    pester_assert_eq!(
+        packet,
+        header.client_cookie,
+        client_cookie,
+        "Server did not echo the client cookie"
+    );
+    pester_assert!(
+        packet,
+        packet.valid_server_response(id, false),
+        "Server response not matching original packet"
+    );
+
+    PASS
+}
+
+/// The server should answer in the requested timescale and the current era
+///
+/// A poll request asks for UTC, which every server has to support. Era 0 lasts until 2036.
+pub fn test_timescale_and_era(conn: &mut UdpConnection) -> TestResult {
+    let (request, _id) = NtpPacket::poll_message_v5(Default::default());
+
+    let packet = pester_assert_response!(conn.pester(request)?);
+    let header = pester_assert_version!(packet, packet, V5);
+
+    pester_assert_eq!(
+        packet,
+        header.timescale,
+        NtpTimescale::Utc,
+        "Server did not reply in the requested timescale"
+    );
+    pester_assert_eq!(packet, header.era, NtpEra(0), "Server is in the wrong era");
+
+    PASS
+}
+
+/// The server has to echo the draft identification extension field
+///
+/// This allows clients to detect servers implementing a different version of the draft.
+pub fn test_draft_identification_is_echoed(conn: &mut UdpConnection) -> TestResult {
+    let (request, _id) = NtpPacket::poll_message_v5(Default::default());
+    let draft_id = request
+        .untrusted_extension_fields()
+        .find_map(|ef| match ef {
+            ExtensionField::DraftIdentification(id) => Some(id.clone().into_owned()),
+            _ => None,
+        })
+        .expect("poll_message_v5 includes a draft identification");
+
+    let packet = pester_assert_response!(conn.pester(request)?);
+    pester_assert_version!(packet, packet, V5);
+
+    let echoed = packet.untrusted_extension_fields().find_map(|ef| match ef {
+        ExtensionField::DraftIdentification(id) => Some(id.clone().into_owned()),
+        _ => None,
+    });
+
+    match echoed {
+        Some(echoed) if echoed == draft_id => PASS,
+        Some(echoed) => fail(
+            format!("Server replied with draft identification {echoed:?} instead of {draft_id:?}"),
+            packet,
+        ),
+        None => fail("Server did not echo the draft identification", packet),
+    }
+}
+
+/// The receive and transmit timestamps of a raw NTPv5 response, in seconds since the NTP prime epoch
+///
+/// Unlike [`ntp_proto::NtpTimestamp`] these include the era from the header. Returns `None` if the response is too
+/// short to contain a NTPv5 header.
+fn era_timestamps(response: &UdpResponse) -> Option<(f64, f64)> {
+    let header = response.0.get(..48)?;
+    let era = header[5] as u64;
+    let timestamp = |bytes: &[u8]| {
+        let seconds = u32::from_be_bytes(bytes[..4].try_into().unwrap()) as u64;
+        let fraction = u32::from_be_bytes(bytes[4..8].try_into().unwrap()) as f64;
+        ((era << 32) + seconds) as f64 + fraction / 4294967296.0
+    };
+
+    Some((timestamp(&header[32..40]), timestamp(&header[40..48])))
+}
+
+/// `time` in seconds since the NTP prime epoch, without wrapping into an era
+fn prime_epoch_seconds(time: SystemTime) -> f64 {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    since_epoch.as_secs_f64() + UNIX_TO_NTP_SECONDS as f64
+}
+
+/// The receive and transmit timestamps combined with the era should match the local clock
+///
+/// [draft-ietf-ntp-ntpv5](https://datatracker.ietf.org/doc/draft-ietf-ntp-ntpv5/) extends the 64 bit timestamps with
+/// the era field, so a server getting either wrong is off by at least one era of 136 years. The response also has to be
+/// sent after the request was received.
+pub fn test_timestamps_are_era_aware(conn: &mut UdpConnection) -> TestResult {
+    let (request, _id) = NtpPacket::poll_message_v5(Default::default());
+
+    let response = pester_assert_response!(conn.pester_raw(UdpRequest::from(request))?);
+    let packet = response.parse()?;
+    pester_assert_version!(response.clone(), packet, V5);
+
+    let Some((receive, transmit)) = era_timestamps(&response) else {
+        return fail("Response is too short to contain a NTPv5 header", response);
+    };
+    let local = conn
+        .last_local_timestamps()
+        .expect("a response was just received");
+    let sent = prime_epoch_seconds(local.sent);
+    let received = prime_epoch_seconds(local.received);
+
+    pester_assert!(
+        response,
+        receive <= transmit,
+        "Server transmitted its response at {transmit} before receiving the request at {receive}"
+    );
+    for (name, timestamp) in [("receive", receive), ("transmit", transmit)] {
+        pester_assert!(
+            response,
+            timestamp > sent - MAX_ERA_ERROR_SECONDS
+                && timestamp < received + MAX_ERA_ERROR_SECONDS,
+            "Server {name} timestamp {timestamp} is not near the local time {sent}, wrong era?"
+        );
+    }
+
+    PASS
+}
+
+/// The server should answer a request for TAI either in UTC, or in TAI offset by the current TAI-UTC difference
+///
+/// Servers only have to support UTC, and answer in it when they do not support the requested timescale. A server that
+/// does answer in TAI has to be ahead of UTC by [`TAI_UTC_OFFSET_SECONDS`], which assumes the local clock is UTC.
+pub fn test_timescale_is_utc_or_tai(conn: &mut UdpConnection) -> TestResult {
+    let (request, _id) = NtpPacket::poll_message_v5(Default::default());
+    let mut request = UdpRequest::from(request);
+    request.0[TIMESCALE_OFFSET] = TIMESCALE_TAI;
+
+    let response = pester_assert_response!(conn.pester_raw(request)?);
+    let packet = response.parse()?;
+    let header = pester_assert_version!(response.clone(), packet, V5);
+
+    let Some((_receive, transmit)) = era_timestamps(&response) else {
+        return fail("Response is too short to contain a NTPv5 header", response);
+    };
+    let local = conn
+        .last_local_timestamps()
+        .expect("a response was just received");
+    let midpoint = (prime_epoch_seconds(local.sent) + prime_epoch_seconds(local.received)) / 2.0;
+    let offset = transmit - midpoint;
+
+    match header.timescale {
+        NtpTimescale::Utc => {
+            tracing::info!(offset, "server does not support TAI, answered in UTC");
+            PASS
+        }
+        NtpTimescale::Tai
+            if (offset - TAI_UTC_OFFSET_SECONDS).abs() <= MAX_TAI_OFFSET_ERROR_SECONDS =>
+        {
+            PASS
+        }
+        NtpTimescale::Tai => fail(
+            format!(
+                "Server answered in TAI {offset:.3}s from our UTC clock instead of {TAI_UTC_OFFSET_SECONDS}s"
+            ),
+            response,
+        ),
+        timescale => fail(
+            format!("Server answered a request for TAI in {timescale:?}"),
+            response,
+        ),
+    }
+}
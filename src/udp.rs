@@ -1,20 +1,41 @@
 //! Functionality to contact and test a NTP server over UDP
 
 use crate::nts::NtsCookie;
-use crate::util::result::{fail, fail_no_response, TestResult, PASS};
-use crate::{TestCase, TestConfig};
+use crate::pcap::PcapWriter;
+use crate::util::result::{fail, fail_no_response, TestError, TestResult, PASS};
+use crate::{AddressFamily, Category, Direction, PacketTrace, TestCase, TestConfig};
 use anyhow::Context;
-use ntp_proto::{NoCipher, NtpPacket, NtsKeys, PacketParsingError, PollInterval};
-use std::fmt::{Debug, Formatter};
+use ntp_proto::{
+    NoCipher, NtpAssociationMode, NtpDuration, NtpHeader, NtpLeapIndicator, NtpPacket,
+    NtpTimestamp, NtsKeys, PacketParsingError, PollInterval,
+};
+use std::cell::RefCell;
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
 use std::io::{Cursor, ErrorKind};
-use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
 use std::panic::UnwindSafe;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 /// An active connection to a NTP server
 pub struct UdpConnection {
     socket: UdpSocket,
+    last_rtt: Option<Duration>,
+    last_sent: Option<SystemTime>,
+    trace: Option<PacketTrace>,
+    pcap: Option<Arc<PcapWriter>>,
+}
+
+thread_local! {
+    static ROUND_TRIP_TIMES: RefCell<Vec<Duration>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Take all round trip times measured by [`UdpConnection`]s on the current thread since the last call
+///
+/// The test runner uses this to attach latency information to the result of each test.
+pub fn take_round_trip_times() -> Vec<Duration> {
+    ROUND_TRIP_TIMES.with(|rtts| std::mem::take(&mut *rtts.borrow_mut()))
 }
 
 /// A collection of bytes that make up a NTP request
@@ -38,6 +59,129 @@ impl UdpRequest {
 
         Self(buffer)
     }
+
+    /// A NTPv4 poll request that claims to be sent in `mode`
+    pub fn poll_with_mode(mode: NtpAssociationMode) -> Self {
+        let (packet, _id) = NtpPacket::poll_message(Default::default());
+        Self::from(packet).with_mode(mode)
+    }
+
+    /// A poll request like [`NtpPacket::poll_message`], but claiming to be of `version`
+    ///
+    /// Only the version bits of the NTPv4 packet are changed, the layout of the NTPv4 header is compatible with all
+    /// earlier versions.
+    pub fn poll_with_version(version: u8) -> Self {
+        let (packet, _id) = NtpPacket::poll_message(Default::default());
+        Self::from(packet).with_version(version)
+    }
+
+    /// Overwrite the 3 bit version number in the serialized packet
+    pub fn with_version(mut self, version: u8) -> Self {
+        if let Some(first) = self.0.first_mut() {
+            *first = (*first & !0b0011_1000) | ((version & 0b111) << 3);
+        }
+
+        self
+    }
+
+    /// Overwrite the association mode in the serialized packet
+    ///
+    /// [`NtpPacket::poll_message`] only creates client requests, this allows testing how a server handles the other
+    /// modes.
+    pub fn with_mode(mut self, mode: NtpAssociationMode) -> Self {
+        if let Some(first) = self.0.first_mut() {
+            *first = (*first & !0b111) | association_mode_bits(mode);
+        }
+
+        self
+    }
+
+    /// Overwrite the transmit timestamp in the serialized packet
+    ///
+    /// The timestamp is given as its raw 64 bit NTP fixed point value.
+    pub fn with_transmit_timestamp(mut self, timestamp: u64) -> Self {
+        if let Some(field) = self.0.get_mut(40..48) {
+            field.copy_from_slice(&timestamp.to_be_bytes());
+        }
+
+        self
+    }
+
+    /// Overwrite the reference id in the serialized packet
+    pub fn with_reference_id(mut self, reference_id: [u8; 4]) -> Self {
+        if let Some(field) = self.0.get_mut(12..16) {
+            field.copy_from_slice(&reference_id);
+        }
+
+        self
+    }
+
+    /// Overwrite the poll exponent in the serialized packet, allowing values [`PollInterval`] would not create
+    pub fn with_poll_exponent(mut self, exponent: i8) -> Self {
+        if let Some(poll) = self.0.get_mut(2) {
+            *poll = exponent as u8;
+        }
+
+        self
+    }
+
+    /// Append a legacy symmetric key MAC, a 4 byte key id followed by the digest
+    ///
+    /// See [RFC5905 section 7.3](https://datatracker.ietf.org/doc/html/rfc5905#section-7.3). No key is involved, the
+    /// digest is taken as given.
+    pub fn with_mac(mut self, key_id: u32, digest: &[u8]) -> Self {
+        self.0.extend_from_slice(&key_id.to_be_bytes());
+        self.0.extend_from_slice(digest);
+
+        self
+    }
+
+    /// A client request with every header field set to its largest value
+    ///
+    /// The leap indicator claims an unsynchronized clock, the stratum is 255, and the poll and precision exponents are
+    /// the largest signed byte. The root delay, root dispersion, reference id and all timestamps are all ones.
+    pub fn all_fields_maximal() -> Self {
+        let mut bytes = vec![0xffu8; 48];
+        bytes[0] = (0b11 << 6) | (4 << 3) | association_mode_bits(NtpAssociationMode::Client);
+        bytes[2] = i8::MAX as u8;
+        bytes[3] = i8::MAX as u8;
+
+        Self(bytes)
+    }
+
+    /// A mode 6 (control) read status request, as sent by `ntpq`
+    pub fn control_read_status() -> Self {
+        let mut bytes = vec![0u8; 12];
+        bytes[0] = 0x16; // version 2, mode 6
+        bytes[1] = 0x01; // opcode: read status
+        bytes[3] = 0x01; // sequence number
+
+        Self(bytes)
+    }
+
+    /// A mode 7 (private) `monlist` request, as sent by `ntpdc`
+    pub fn private_monlist() -> Self {
+        let mut bytes = vec![0u8; 48];
+        bytes[0] = 0x17; // version 2, mode 7
+        bytes[2] = 0x03; // implementation: xntpd
+        bytes[3] = 0x2a; // request code: MON_GETLIST_1
+
+        Self(bytes)
+    }
+}
+
+/// The 3 bit value used to encode a [`NtpAssociationMode`] on the wire
+pub fn association_mode_bits(mode: NtpAssociationMode) -> u8 {
+    match mode {
+        NtpAssociationMode::Reserved => 0,
+        NtpAssociationMode::SymmetricActive => 1,
+        NtpAssociationMode::SymmetricPassive => 2,
+        NtpAssociationMode::Client => 3,
+        NtpAssociationMode::Server => 4,
+        NtpAssociationMode::Broadcast => 5,
+        NtpAssociationMode::Control => 6,
+        NtpAssociationMode::Private => 7,
+    }
 }
 
 impl From<NtpPacket<'_>> for UdpRequest {
@@ -62,6 +206,36 @@ impl<'a> TryFrom<&'a UdpResponse> for NtpPacket<'a> {
     }
 }
 
+impl UdpResponse {
+    /// The number of bytes received
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Returns `true` if the response was an empty datagram
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The raw reference id field of a NTPv4 header, the kiss code in Kiss-o'-Death packets
+    ///
+    /// Returns `None` if the response is too short to contain one.
+    pub fn reference_id(&self) -> Option<[u8; 4]> {
+        self.0.get(12..16)?.try_into().ok()
+    }
+
+    /// Parse the response as a NTP packet without NTS, failing the test if it is invalid
+    pub fn parse(&self) -> TestResult<NtpPacket<'_>> {
+        match NtpPacket::try_from(self) {
+            Ok(packet) => Ok(packet),
+            Err(e) => fail(
+                format!("Server replied with invalid packet: {e:?}"),
+                self.clone(),
+            ),
+        }
+    }
+}
+
 impl Debug for UdpResponse {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Response")
@@ -74,14 +248,18 @@ impl Debug for UdpResponse {
 impl UdpConnection {
     const MAX_LEN: usize = 9000;
 
-    /// Connect to the given host
-    pub fn new(to_addr: impl ToSocketAddrs, timeout: Duration) -> TestResult<Self> {
-        let mut to_addr = to_addr
+    /// Connect to the given host, using the first address of `address_family` it resolves to
+    pub fn new(
+        to_addr: impl ToSocketAddrs,
+        timeout: Duration,
+        address_family: AddressFamily,
+    ) -> TestResult<Self> {
+        let to_addr = to_addr
             .to_socket_addrs()
             .context("Could not parse peer address")?;
-        let to_addr = to_addr
-            .next()
-            .context("Domain did not resolve into any addresses")?;
+        let to_addr = address_family.select(to_addr).with_context(|| {
+            format!("Domain did not resolve into any {address_family} addresses")
+        })?;
 
         let from_addr: SocketAddr = match to_addr {
             SocketAddr::V4(_) => "0.0.0.0:0",
@@ -90,43 +268,161 @@ impl UdpConnection {
         .parse()
         .expect("no errors where made writing this address");
 
-        let socket = UdpSocket::bind(from_addr).context("Could not open socket")?;
+        Self::new_from(from_addr, to_addr, timeout)
+    }
+
+    /// Connect to `to_addr` from the local address `bind`
+    ///
+    /// Unlike [`UdpConnection::new`] this does not pick an ephemeral port, so tests can control the source port of
+    /// their requests. `bind` must be of the same address family as `to_addr`.
+    pub fn new_from(bind: SocketAddr, to_addr: SocketAddr, timeout: Duration) -> TestResult<Self> {
+        let socket =
+            UdpSocket::bind(bind).with_context(|| format!("Could not open socket on {bind}"))?;
         socket
             .connect(to_addr)
-            .with_context(|| format!("Can not connect to {to_addr} from {from_addr}"))?;
+            .with_context(|| format!("Can not connect to {to_addr} from {bind}"))?;
         socket
             .set_read_timeout(Some(timeout))
             .context("Could not set timeout")?;
 
-        Ok(Self { socket })
+        Ok(Self {
+            socket,
+            last_rtt: None,
+            last_sent: None,
+            trace: None,
+            pcap: None,
+        })
+    }
+
+    /// Open a new connection to the same server from `bind`, keeping the timeout, trace and pcap of this one
+    pub fn reopen_from(&self, bind: SocketAddr) -> TestResult<Self> {
+        let conn = Self::new_from(bind, self.peer_addr()?, self.timeout())?;
+        Ok(conn
+            .with_trace(self.trace.clone())
+            .with_pcap(self.pcap.clone()))
+    }
+
+    /// The address of the server this connection sends to
+    pub fn peer_addr(&self) -> TestResult<SocketAddr> {
+        Ok(self.socket.peer_addr().context("No peer address")?)
+    }
+
+    /// How long to wait for a response before giving up
+    pub fn timeout(&self) -> Duration {
+        self.socket
+            .read_timeout()
+            .ok()
+            .flatten()
+            .unwrap_or(Duration::from_secs(1))
+    }
+
+    /// Pass every request and response of this connection to `trace`
+    pub fn with_trace(mut self, trace: Option<PacketTrace>) -> Self {
+        self.trace = trace;
+        self
+    }
+
+    /// Record every request and response of this connection in `pcap`
+    pub fn with_pcap(mut self, pcap: Option<Arc<PcapWriter>>) -> Self {
+        self.pcap = pcap;
+        self
+    }
+
+    fn capture(&self, direction: Direction, bytes: &[u8], time: SystemTime) -> TestResult {
+        let Some(pcap) = &self.pcap else {
+            return PASS;
+        };
+
+        let local = self.socket.local_addr().context("No local address")?;
+        let peer = self.socket.peer_addr().context("No peer address")?;
+        let (src, dst) = match direction {
+            Direction::Sent => (local, peer),
+            Direction::Received => (peer, local),
+        };
+        pcap.write_udp(src, dst, bytes, time)
+            .context("Could not write to pcap file")?;
+
+        PASS
     }
 
     /// Send and receive raw bytes to the server
     ///
     /// Returns `Ok(None)` if the server did not reply.
     pub fn pester_raw(&mut self, req: UdpRequest) -> TestResult<Option<UdpResponse>> {
-        self.socket
-            .send(req.0.as_slice())
-            .context("Could not send request")?;
+        self.last_rtt = None;
+        if let Some(trace) = &self.trace {
+            trace.trace("NTP", Direction::Sent, &req.0);
+        }
+
+        self.capture(Direction::Sent, &req.0, SystemTime::now())?;
+        // Taken right before sending, so the capture does not count towards the round trip
+        let sent = SystemTime::now();
+        self.last_sent = Some(sent);
+        let start = Instant::now();
+        if let Err(err) = self.socket.send(req.0.as_slice()) {
+            return Err(self.refused_or(err, "Could not send request"));
+        }
+        tracing::trace!(len = req.0.len(), "sent UDP request");
 
         let mut response = vec![0; Self::MAX_LEN];
         let len = match self.socket.recv(response.as_mut_slice()) {
             Ok(len) => len,
             Err(err) => match err.kind() {
-                ErrorKind::TimedOut | ErrorKind::WouldBlock => return Ok(None),
-                _ => Err(err).context("Could not receive response")?,
+                ErrorKind::TimedOut | ErrorKind::WouldBlock => {
+                    tracing::debug!("no UDP response before timeout");
+                    return Ok(None);
+                }
+                _ => return Err(self.refused_or(err, "Could not receive response")),
             },
         };
+        let rtt = start.elapsed();
+        let received = SystemTime::now();
         response.truncate(len);
+        tracing::trace!(len, ?rtt, "received UDP response");
+
+        self.last_rtt = Some(rtt);
+        ROUND_TRIP_TIMES.with(|rtts| rtts.borrow_mut().push(rtt));
+
+        if let Some(trace) = &self.trace {
+            trace.trace("NTP", Direction::Received, &response);
+        }
+        self.capture(Direction::Received, &response, received)?;
 
         Ok(Some(UdpResponse(response)))
     }
 
+    /// Turn `err` into a [`ConnectionRefused`] error if applicable, otherwise add `context` to it
+    fn refused_or(&self, err: std::io::Error, context: &'static str) -> TestError {
+        match (err.kind(), self.socket.peer_addr()) {
+            (ErrorKind::ConnectionRefused, Ok(peer)) => {
+                TestError::Error(anyhow::Error::new(ConnectionRefused(peer)))
+            }
+            _ => TestError::Error(anyhow::Error::new(err).context(context)),
+        }
+    }
+
+    /// The round trip time of the last request, or `None` if it was not answered
+    pub fn last_rtt(&self) -> Option<Duration> {
+        self.last_rtt
+    }
+
+    /// The local send and receive time of the last request, or `None` if it was not answered
+    ///
+    /// The receive time is derived from the monotonic round trip time, so a step of the local clock during the
+    /// exchange does not distort it.
+    pub fn last_local_timestamps(&self) -> Option<LocalTimestamps> {
+        let sent = self.last_sent?;
+        Some(LocalTimestamps {
+            sent,
+            received: sent + self.last_rtt?,
+        })
+    }
+
     fn pester_pkt(
         &mut self,
         packet: NtpPacket,
         keys: Option<&NtsKeys>,
-    ) -> TestResult<Option<NtpPacket>> {
+    ) -> TestResult<Option<NtpPacket<'static>>> {
         let req = UdpRequest::from_ntp_packet(packet, keys);
         let response = match self.pester_raw(req)? {
             None => return Ok(None),
@@ -150,7 +446,25 @@ impl UdpConnection {
     /// Pester a server with a given packet
     ///
     /// Returning the response packet, or `None` if none was received.
-    pub fn pester(&mut self, packet: NtpPacket) -> TestResult<Option<NtpPacket>> {
+    pub fn pester(&mut self, packet: NtpPacket) -> TestResult<Option<NtpPacket<'static>>> {
+        self.pester_pkt(packet, None)
+    }
+
+    /// Pester a server, resending the packet up to `attempts` times in total if no response was received
+    ///
+    /// Only use this for tests that do not care about lost packets, e.g. when checking that the server responds at
+    /// all. At least one attempt is always made.
+    pub fn pester_retry(
+        &mut self,
+        packet: NtpPacket,
+        attempts: usize,
+    ) -> TestResult<Option<NtpPacket<'static>>> {
+        for _ in 1..attempts {
+            if let Some(response) = self.pester_pkt(packet.clone(), None)? {
+                return Ok(Some(response));
+            }
+        }
+
         self.pester_pkt(packet, None)
     }
 
@@ -161,17 +475,344 @@ impl UdpConnection {
         &mut self,
         packet: NtpPacket,
         keys: &NtsKeys,
-    ) -> TestResult<Option<NtpPacket>> {
+    ) -> TestResult<Option<NtpPacket<'static>>> {
         self.pester_pkt(packet, Some(keys))
     }
+
+    /// Send all `packets` at once, then collect the responses until the timeout of this connection expires
+    ///
+    /// Responses are matched to their request by the origin timestamp, so replies arriving out of order end up in the
+    /// right place. The result has one entry per packet in the order they were sent, `None` for the unanswered ones.
+    /// Datagrams that do not decode as an NTP packet are skipped.
+    pub fn pester_many(
+        &mut self,
+        packets: Vec<NtpPacket>,
+    ) -> TestResult<Vec<Option<NtpPacket<'static>>>> {
+        self.last_rtt = None;
+        self.last_sent = None;
+
+        let mut transmit_timestamps = vec![];
+        for packet in packets {
+            let req = UdpRequest::from_ntp_packet(packet, None);
+            transmit_timestamps.push(req.0.get(40..48).map(<[u8]>::to_vec));
+
+            if let Some(trace) = &self.trace {
+                trace.trace("NTP", Direction::Sent, &req.0);
+            }
+            self.capture(Direction::Sent, &req.0, SystemTime::now())?;
+            if let Err(err) = self.socket.send(req.0.as_slice()) {
+                return Err(self.refused_or(err, "Could not send request"));
+            }
+        }
+        tracing::trace!(
+            count = transmit_timestamps.len(),
+            "sent batch of UDP requests"
+        );
+
+        let timeout = self.timeout();
+        let deadline = Instant::now() + timeout;
+        let mut responses: Vec<Option<NtpPacket<'static>>> = vec![None; transmit_timestamps.len()];
+        let result = loop {
+            if responses.iter().all(Option::is_some) {
+                break Ok(());
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break Ok(());
+            }
+            // Errors break out of the loop instead of returning, so the original timeout is always restored
+            let set_timeout = self
+                .socket
+                .set_read_timeout(Some(remaining))
+                .context("Could not set read timeout");
+            if let Err(e) = set_timeout {
+                break Err(e.into());
+            }
+
+            let mut response = vec![0; Self::MAX_LEN];
+            let len = match self.socket.recv(response.as_mut_slice()) {
+                Ok(len) => len,
+                Err(err) if matches!(err.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) => {
+                    break Ok(());
+                }
+                Err(err) => break Err(self.refused_or(err, "Could not receive response")),
+            };
+            response.truncate(len);
+
+            if let Some(trace) = &self.trace {
+                trace.trace("NTP", Direction::Received, &response);
+            }
+            if let Err(e) = self.capture(Direction::Received, &response, SystemTime::now()) {
+                break Err(e);
+            }
+
+            let packet = match NtpPacket::deserialize(response.as_slice(), &None) {
+                Ok((packet, _cookie)) => packet.into_owned(),
+                Err(e) => {
+                    // Do not let a single bad datagram discard the answers matched so far
+                    tracing::debug!(error = ?e, "skipping undecodable response");
+                    continue;
+                }
+            };
+
+            let origin = response.get(24..32);
+            let index = transmit_timestamps
+                .iter()
+                .zip(&responses)
+                .position(|(sent, answer)| answer.is_none() && sent.as_deref() == origin);
+            match index {
+                Some(index) => responses[index] = Some(packet),
+                None => tracing::debug!("response does not match any outstanding request"),
+            }
+        };
+
+        self.socket
+            .set_read_timeout(Some(timeout))
+            .context("Could not restore read timeout")?;
+        result?;
+
+        Ok(responses)
+    }
+}
+
+/// The IPv4 multicast group of NTP, see the [IANA registry](https://www.iana.org/assignments/multicast-addresses)
+pub const NTP_MULTICAST_V4: Ipv4Addr = Ipv4Addr::new(224, 0, 1, 1);
+
+/// The site-local IPv6 multicast group of NTP servers
+pub const NTP_MULTICAST_V6: Ipv6Addr = Ipv6Addr::new(0xff05, 0, 0, 0, 0, 0, 0, 0x101);
+
+/// A socket that is not connected to a single peer, so replies from any address are received
+///
+/// [`UdpConnection`] only sees replies from the address it sent to. Tests that check whether the server answers
+/// requests addressed elsewhere, e.g. to another port or to a multicast group, use this instead.
+pub struct UdpProbe {
+    socket: UdpSocket,
+}
+
+impl UdpProbe {
+    /// Open a socket on an ephemeral port, able to send to addresses of the same family as `peer`
+    pub fn new(peer: SocketAddr, timeout: Duration) -> TestResult<Self> {
+        let bind: SocketAddr = match peer {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+
+        let socket = UdpSocket::bind(bind).context("Could not open socket")?;
+        socket
+            .set_read_timeout(Some(timeout))
+            .context("Could not set timeout")?;
+
+        Ok(Self { socket })
+    }
+
+    /// Open a socket to send to the multicast group `group`
+    ///
+    /// IPv4 requests are limited to the local network segment, IPv6 ones by the scope of the group. No group is joined
+    /// since only unicast replies are of interest.
+    pub fn multicast(group: SocketAddr, timeout: Duration) -> TestResult<Self> {
+        if !group.ip().is_multicast() {
+            return Err(anyhow::anyhow!("{group} is not a multicast address").into());
+        }
+
+        let probe = Self::new(group, timeout)?;
+        if group.is_ipv4() {
+            probe
+                .socket
+                .set_multicast_ttl_v4(1)
+                .context("Could not set multicast TTL")?;
+        }
+
+        Ok(probe)
+    }
+
+    /// Send `req` to `to`
+    pub fn send_to(&self, req: &UdpRequest, to: SocketAddr) -> TestResult {
+        self.socket
+            .send_to(&req.0, to)
+            .with_context(|| format!("Could not send request to {to}"))?;
+
+        PASS
+    }
+
+    /// Send `req` to `to` and wait for the first reply, reporting the address it actually came from
+    ///
+    /// Unlike [`UdpConnection::pester_raw`] a reply from another address or port than `to` is not dropped by the
+    /// kernel, so tests can check where the server answers from.
+    pub fn pester_raw_unconnected(
+        &self,
+        req: &UdpRequest,
+        to: SocketAddr,
+    ) -> TestResult<Option<(SocketAddr, UdpResponse)>> {
+        self.send_to(req, to)?;
+        self.recv_from()
+    }
+
+    /// Receive the next datagram from any address
+    ///
+    /// Returns `Ok(None)` once nothing arrives within the timeout.
+    pub fn recv_from(&self) -> TestResult<Option<(SocketAddr, UdpResponse)>> {
+        let mut buf = vec![0; UdpConnection::MAX_LEN];
+        match self.socket.recv_from(&mut buf) {
+            Ok((len, from)) => {
+                buf.truncate(len);
+                Ok(Some((from, UdpResponse(buf))))
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(None),
+            Err(e) => Err(anyhow::Error::new(e).context("Could not receive").into()),
+        }
+    }
+}
+
+/// The server refused our request, usually because nothing is listening on the port
+///
+/// The operating system reports this when it receives an ICMP port unreachable message. It is kept as a distinct
+/// [`TestError::Error`], so a server that is down can be told apart from one that silently drops packets.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ConnectionRefused(pub SocketAddr);
+
+impl Display for ConnectionRefused {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Connection refused by {}, the server does not seem to be listening",
+            self.0
+        )
+    }
+}
+
+impl Error for ConnectionRefused {}
+
+/// Convert a NTP short format value (e.g. root delay or root dispersion) into seconds
+///
+/// Useful to print human-readable values in assertion messages.
+pub fn short_format_to_seconds(duration: NtpDuration) -> f64 {
+    duration.to_seconds()
+}
+
+/// The stratum of a NTPv4 packet, or `None` for other versions
+pub fn stratum(packet: &NtpPacket) -> Option<u8> {
+    match packet.header() {
+        NtpHeader::V4(header) => Some(header.stratum),
+        _ => None,
+    }
+}
+
+/// The leap indicator of a NTPv4 packet, or `None` for other versions
+pub fn leap(packet: &NtpPacket) -> Option<NtpLeapIndicator> {
+    match packet.header() {
+        NtpHeader::V4(header) => Some(header.leap),
+        _ => None,
+    }
+}
+
+/// The smallest poll exponent allowed by [RFC5905 section 7.3](https://datatracker.ietf.org/doc/html/rfc5905#section-7.3)
+pub const MIN_POLL_EXPONENT: i8 = 4;
+
+/// The largest poll exponent allowed by [RFC5905 section 7.3](https://datatracker.ietf.org/doc/html/rfc5905#section-7.3)
+pub const MAX_POLL_EXPONENT: i8 = 17;
+
+/// The poll interval with the given exponent, e.g. [`MIN_POLL_EXPONENT`] or [`MAX_POLL_EXPONENT`]
+pub fn poll_interval(exponent: i8) -> PollInterval {
+    PollInterval::from_byte(exponent as u8)
+}
+
+/// The reference timestamp of a NTPv4 packet, or `None` for other versions
+pub fn reference_timestamp(packet: &NtpPacket) -> Option<NtpTimestamp> {
+    match packet.header() {
+        NtpHeader::V4(header) => Some(header.reference_timestamp),
+        _ => None,
+    }
+}
+
+/// Helpers to compare NTP timestamps ordinally
+pub trait NtpTimestampExt {
+    /// The raw 64 bit value, seconds in the upper and the fraction in the lower 32 bits
+    fn as_u64(&self) -> u64;
+}
+
+impl NtpTimestampExt for NtpTimestamp {
+    fn as_u64(&self) -> u64 {
+        // ntp-proto does not expose the raw value, so read it back from where a packet puts it on the wire
+        let (mut packet, _) = NtpPacket::poll_message(PollInterval::default());
+        packet.set_transmit_timestamp(*self);
+        let mut buf = [0u8; 48];
+        packet
+            .serialize(&mut Cursor::new(&mut buf[..]), &NoCipher, None)
+            .expect("A packet without extension fields fits in 48 bytes");
+        u64::from_be_bytes(buf[40..48].try_into().expect("Slice of 8 bytes"))
+    }
+}
+
+/// The time from `earlier` to `later` in seconds, negative if `later` is actually before `earlier`
+///
+/// Unlike comparing the timestamps directly this handles era rollover correctly.
+pub fn seconds_between(earlier: NtpTimestamp, later: NtpTimestamp) -> f64 {
+    (later - earlier).to_seconds()
+}
+
+/// Offset between the UNIX epoch and the NTP era 0 epoch in 1900
+pub(crate) const UNIX_TO_NTP_SECONDS: u64 = 2_208_988_800;
+
+/// Convert a local time into a NTP timestamp
+pub fn ntp_timestamp(time: SystemTime) -> NtpTimestamp {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    // Truncating to 32 bits wraps into the current era, just like the timestamps on the wire
+    let seconds = (since_epoch.as_secs() + UNIX_TO_NTP_SECONDS) as u32;
+    NtpTimestamp::from_seconds_nanos_since_ntp_era(seconds, since_epoch.subsec_nanos())
+}
+
+/// When a request was sent and its response received according to the local clock
+///
+/// These are the origin (T1) and destination (T4) timestamps of
+/// [RFC5905 section 8](https://datatracker.ietf.org/doc/html/rfc5905#section-8).
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct LocalTimestamps {
+    pub sent: SystemTime,
+    pub received: SystemTime,
+}
+
+/// The offset of the server clock relative to the local clock in seconds, or `None` for non NTPv4 packets
+///
+/// Computed as `((T2 - T1) + (T3 - T4)) / 2` following
+/// [RFC5905 section 8](https://datatracker.ietf.org/doc/html/rfc5905#section-8).
+pub fn clock_offset(packet: &NtpPacket, local: LocalTimestamps) -> Option<f64> {
+    let NtpHeader::V4(header) = packet.header() else {
+        return None;
+    };
+
+    let t1 = ntp_timestamp(local.sent);
+    let t4 = ntp_timestamp(local.received);
+    Some(
+        (seconds_between(t1, header.receive_timestamp)
+            + seconds_between(t4, header.transmit_timestamp))
+            / 2.0,
+    )
+}
+
+/// The round trip delay of an exchange in seconds, without the time spent in the server, or `None` for non NTPv4
+/// packets
+///
+/// Computed as `(T4 - T1) - (T3 - T2)` following
+/// [RFC5905 section 8](https://datatracker.ietf.org/doc/html/rfc5905#section-8).
+pub fn round_trip_delay(packet: &NtpPacket, local: LocalTimestamps) -> Option<f64> {
+    let NtpHeader::V4(header) = packet.header() else {
+        return None;
+    };
+
+    let t1 = ntp_timestamp(local.sent);
+    let t4 = ntp_timestamp(local.received);
+    Some(
+        seconds_between(t1, t4)
+            - seconds_between(header.receive_timestamp, header.transmit_timestamp),
+    )
 }
 
 /// Wrap a given function into a test case
 ///
 /// Passes the function an active connection, and checks after the test if the server is still reachable.
-pub fn udp_test<F>(f: F) -> Box<dyn TestCase + UnwindSafe>
+pub fn udp_test<F>(f: F) -> Box<dyn TestCase + UnwindSafe + Send>
 where
-    F: Fn(&mut UdpConnection) -> TestResult + UnwindSafe + 'static,
+    F: Fn(&mut UdpConnection) -> TestResult + UnwindSafe + Send + 'static,
 {
     struct UdpTest<F> {
         f: F,
@@ -185,6 +826,10 @@ where
             std::any::type_name::<F>()
         }
 
+        fn category(&self) -> Category {
+            Category::Ntp
+        }
+
         fn run(&self, conf: &TestConfig) -> TestResult {
             let mut conn = conf.udp()?;
             (self.f)(&mut conn)?;
@@ -219,8 +864,237 @@ pub fn udp_server_still_alive(
             response,
         ),
         Ok(None) => fail_no_response("After test: Server did no longer reply to normal poll"),
+        // Keep this an error, the server went down instead of misbehaving
+        Err(TestError::Error(e)) if e.is::<ConnectionRefused>() => {
+            Err(TestError::Error(e.context("After test")))
+        }
         Err(e) => fail_no_response(format!(
             "After test: Server did no longer reply to normal poll. Error: {e:?}"
         )),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ntp_proto::RequestIdentifier;
+
+    /// A minimal server response to the NTPv4 `request`, which passes `valid_server_response`
+    fn reply_to(request: &[u8]) -> Vec<u8> {
+        let mut response = request[..48].to_vec();
+        response[0] = (4 << 3) | 4; // no leap second, version 4, server mode
+        response[1] = 1; // stratum
+        response.copy_within(40..48, 24); // origin timestamp
+        response
+    }
+
+    /// Answer the datagrams sent to a loopback socket with whatever `respond` returns for them
+    ///
+    /// The responder stops once it did not receive anything for a second.
+    fn responder(mut respond: impl FnMut(&[u8]) -> Vec<Vec<u8>> + Send + 'static) -> SocketAddr {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 1024];
+            while let Ok((len, from)) = socket.recv_from(&mut buf) {
+                for datagram in respond(&buf[..len]) {
+                    socket.send_to(&datagram, from).unwrap();
+                }
+            }
+        });
+
+        addr
+    }
+
+    fn connect(addr: SocketAddr) -> UdpConnection {
+        UdpConnection::new(addr, Duration::from_millis(100), AddressFamily::Any).unwrap()
+    }
+
+    /// A responder that ignores the first `dropped` requests
+    fn lossy_responder(dropped: usize) -> SocketAddr {
+        let mut received = 0;
+        responder(move |request| {
+            received += 1;
+            if received > dropped {
+                vec![reply_to(request)]
+            } else {
+                vec![]
+            }
+        })
+    }
+
+    #[test]
+    fn pester_retry_resends_lost_requests() {
+        let mut conn = connect(lossy_responder(2));
+        let (request, id) = NtpPacket::poll_message(PollInterval::default());
+
+        let response = conn.pester_retry(request, 3).unwrap();
+        assert!(response.unwrap().valid_server_response(id, false));
+    }
+
+    #[test]
+    fn pester_retry_gives_up_after_attempts() {
+        let mut conn = connect(lossy_responder(2));
+        let (request, _id) = NtpPacket::poll_message(PollInterval::default());
+
+        assert!(conn.pester_retry(request.clone(), 2).unwrap().is_none());
+        // The third attempt in total is answered again
+        assert!(conn.pester(request).unwrap().is_some());
+    }
+
+    #[test]
+    fn round_trip_time_is_measured() {
+        let mut conn = connect(lossy_responder(0));
+        let (request, _id) = NtpPacket::poll_message(PollInterval::default());
+        take_round_trip_times();
+
+        assert!(conn.pester(request).unwrap().is_some());
+        let rtt = conn.last_rtt().unwrap();
+        assert!(rtt > Duration::ZERO);
+        assert_eq!(take_round_trip_times(), [rtt]);
+    }
+
+    #[test]
+    fn round_trip_time_is_cleared_without_response() {
+        let mut conn = connect(lossy_responder(1));
+        let (request, _id) = NtpPacket::poll_message(PollInterval::default());
+        take_round_trip_times();
+
+        assert!(conn.pester(request).unwrap().is_none());
+        assert_eq!(conn.last_rtt(), None);
+        assert!(take_round_trip_times().is_empty());
+    }
+
+    #[test]
+    fn closed_port_is_refused() {
+        // Nothing listens on the port once the socket that was given it is dropped
+        let addr = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut conn = connect(addr);
+        let (request, _id) = NtpPacket::poll_message(PollInterval::default());
+
+        match conn.pester(request) {
+            Err(TestError::Error(e)) => {
+                assert_eq!(e.downcast_ref(), Some(&ConnectionRefused(addr)));
+            }
+            other => panic!("Expected the connection to be refused, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn offset_and_delay_follow_rfc5905() {
+        let sent = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let local = LocalTimestamps {
+            sent,
+            received: sent + Duration::from_millis(300),
+        };
+        let (mut packet, _id) = NtpPacket::poll_message(PollInterval::default());
+        packet.set_receive_timestamp(ntp_timestamp(sent + Duration::from_millis(10_500)));
+        packet.set_transmit_timestamp(ntp_timestamp(sent + Duration::from_millis(10_600)));
+
+        // ((T2 - T1) + (T3 - T4)) / 2 = (10.5s + 10.3s) / 2 and (T4 - T1) - (T3 - T2) = 0.3s - 0.1s
+        let offset = clock_offset(&packet, local).unwrap();
+        let delay = round_trip_delay(&packet, local).unwrap();
+        assert!((offset - 10.4).abs() < 1e-6, "{offset}");
+        assert!((delay - 0.2).abs() < 1e-6, "{delay}");
+    }
+
+    #[test]
+    fn offset_of_loopback_server_is_measured() {
+        let addr = responder(|request| {
+            let mut response = reply_to(request);
+            // A server clock 10s ahead, answering instantly
+            let now = ntp_timestamp(SystemTime::now() + Duration::from_secs(10));
+            response[32..40].copy_from_slice(&now.as_u64().to_be_bytes());
+            response[40..48].copy_from_slice(&now.as_u64().to_be_bytes());
+            vec![response]
+        });
+        let mut conn = connect(addr);
+        let (request, _id) = NtpPacket::poll_message(PollInterval::default());
+
+        let packet = conn.pester(request).unwrap().unwrap();
+        let local = conn.last_local_timestamps().unwrap();
+        let offset = clock_offset(&packet, local).unwrap();
+        assert!((offset - 10.0).abs() < 0.05, "{offset}");
+    }
+
+    #[test]
+    fn timestamp_as_u64_is_exact() {
+        // One and two nanoseconds only differ in bits that do not fit in the mantissa of a f64
+        let one = NtpTimestamp::from_seconds_nanos_since_ntp_era(0x4000_0000, 1);
+        let two = NtpTimestamp::from_seconds_nanos_since_ntp_era(0x4000_0000, 2);
+        assert_eq!(one.as_u64(), 0x4000_0000_0000_0004);
+        assert!(one.as_u64() < two.as_u64());
+    }
+
+    /// A responder that waits for `count` requests, then answers them in reverse order with `respond`
+    fn reversing_responder(
+        count: usize,
+        mut respond: impl FnMut(&[u8]) -> Vec<Vec<u8>> + Send + 'static,
+    ) -> SocketAddr {
+        let mut requests = vec![];
+        responder(move |request| {
+            requests.push(request.to_vec());
+            if requests.len() < count {
+                return vec![];
+            }
+            requests.drain(..).rev().flat_map(|r| respond(&r)).collect()
+        })
+    }
+
+    fn polls(count: usize) -> (Vec<NtpPacket<'static>>, Vec<RequestIdentifier>) {
+        (0..count)
+            .map(|_| NtpPacket::poll_message(PollInterval::default()))
+            .unzip()
+    }
+
+    #[test]
+    fn pester_many_matches_out_of_order_responses() {
+        let mut conn = connect(reversing_responder(3, |request| vec![reply_to(request)]));
+        let (requests, ids) = polls(3);
+
+        let responses = conn.pester_many(requests).unwrap();
+        assert_eq!(responses.len(), 3);
+        for (response, id) in responses.into_iter().zip(ids) {
+            assert!(response.unwrap().valid_server_response(id, false));
+        }
+    }
+
+    #[test]
+    fn pester_many_skips_undecodable_responses() {
+        // Every answer is preceded by a datagram too short to be a NTP packet
+        let mut conn = connect(reversing_responder(3, |request| {
+            vec![vec![0x24; 3], reply_to(request)]
+        }));
+        let (requests, ids) = polls(3);
+
+        let responses = conn.pester_many(requests).unwrap();
+        for (response, id) in responses.into_iter().zip(ids) {
+            assert!(response.unwrap().valid_server_response(id, false));
+        }
+    }
+
+    #[test]
+    fn pester_many_leaves_dropped_requests_unanswered() {
+        let mut received = 0;
+        let mut conn = connect(responder(move |request| {
+            received += 1;
+            if received == 2 {
+                vec![]
+            } else {
+                vec![reply_to(request)]
+            }
+        }));
+        let (requests, _ids) = polls(3);
+
+        let responses = conn.pester_many(requests).unwrap();
+        let answered: Vec<_> = responses.iter().map(Option::is_some).collect();
+        assert_eq!(answered, [true, false, true]);
+    }
+}
@@ -64,3 +64,22 @@ pub fn fail<T>(msg: impl ToString, response: impl Into<Response>) -> TestResult<
 pub fn fail_no_response<T>(msg: impl ToString) -> TestResult<T> {
     Err(TestError::Fail(msg.to_string(), None))
 }
+
+/// Attach context to the error of a [`TestResult`], keeping apart failures and other errors
+///
+/// Using [`anyhow::Context`] directly would turn every failure into a [`TestError::Error`].
+pub trait TestContext<T> {
+    fn test_context<C: Display>(self, context: impl FnOnce() -> C) -> TestResult<T>;
+}
+
+impl<T> TestContext<T> for TestResult<T> {
+    fn test_context<C: Display>(self, context: impl FnOnce() -> C) -> TestResult<T> {
+        self.map_err(|e| match e {
+            TestError::Fail(msg, response) => {
+                TestError::Fail(format!("{}: {msg}", context()), response)
+            }
+            TestError::Skipped => TestError::Skipped,
+            TestError::Error(e) => TestError::Error(e.context(context().to_string())),
+        })
+    }
+}